@@ -1,8 +1,8 @@
 use solana_program::{
-    account_info::AccountInfo, entrypoint, entrypoint::ProgramResult, pubkey::Pubkey, msg,
+    account_info::AccountInfo, entrypoint, entrypoint::ProgramResult, program_error::PrintProgramError, pubkey::Pubkey, msg,
 };
 
-use crate::processor::Processor;
+use crate::{error::StepError, processor::Processor};
 
 entrypoint!(process_instruction);
 fn process_instruction(
@@ -11,7 +11,12 @@ fn process_instruction(
     instruction_data: &[u8],
 ) -> ProgramResult {
     msg!("Rust program entrypoint");
-    Processor::process(program_id, accounts, instruction_data)
+    if let Err(error) = Processor::process(program_id, accounts, instruction_data) {
+        // Log a readable name for errors of the program
+        error.print::<StepError>();
+        return Err(error);
+    }
+    Ok(())
 }
 /*
 #[cfg(test)]
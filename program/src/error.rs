@@ -1,8 +1,9 @@
+use num_derive::FromPrimitive;
 use thiserror::Error;
 
-use solana_program::program_error::ProgramError;
+use solana_program::{decode_error::DecodeError, program_error::ProgramError};
 
-#[derive(Error, Debug, Copy, Clone)]
+#[derive(Error, Debug, Copy, Clone, PartialEq, Eq, FromPrimitive)]
 pub enum StepError {
     /// Invalid instruction
     #[error("Invalid Instruction")]
@@ -16,10 +17,112 @@ pub enum StepError {
     /// Amount Overflow
     #[error("Amount Overflow")]
     AmountOverflow,
+    /// An account is not at the address derived from its seeds
+    #[error("Address Derivation Mismatch")]
+    AddressDerivationMismatch,
+    /// An account is not owned by the signer
+    #[error("Invalid Owner")]
+    InvalidOwner,
+    /// A user account belongs to a different pool
+    #[error("Pool Mismatch")]
+    PoolMismatch,
+    /// A stream belongs to a different user account
+    #[error("Stream Mismatch")]
+    StreamMismatch,
+    /// An account holds a different mint than expected
+    #[error("Mint Mismatch")]
+    MintMismatch,
+    /// The pool does not hold wrapped SOL
+    #[error("Not Native Mint")]
+    NotNativeMint,
+    /// Input and output are the same mint
+    #[error("Same Input And Output")]
+    SameInputAndOutput,
+    /// The pool or user account has no free slot
+    #[error("No Free Slot")]
+    NoFreeSlot,
+    /// Nothing is due for execution yet
+    #[error("Not Due")]
+    NotDue,
+    /// The stream is paused or completed
+    #[error("Stream Not Active")]
+    StreamNotActive,
+    /// The stream is not paused
+    #[error("Stream Not Paused")]
+    StreamNotPaused,
+    /// The stream is completed
+    #[error("Stream Completed")]
+    StreamCompleted,
+    /// The stream has input in a round which is not executed yet
+    #[error("Round Pending")]
+    RoundPending,
+    /// The pools have not gathered a round with each other
+    #[error("Round Not Gathered")]
+    RoundNotGathered,
+    /// The balance cannot cover the amount
+    #[error("Insufficient Balance")]
+    InsufficientBalance,
+    /// The swap returned less than the minimum amount out
+    #[error("Slippage Exceeded")]
+    SlippageExceeded,
+    /// The swap is not the registered route of the pools
+    #[error("Invalid Route")]
+    InvalidRoute,
+    /// The swap has no liquidity
+    #[error("No Liquidity")]
+    NoLiquidity,
+    /// Amount is zero
+    #[error("Invalid Amount")]
+    InvalidAmount,
+    /// Schedule, catch-up or order window is invalid
+    #[error("Invalid Schedule")]
+    InvalidSchedule,
+    /// Slippage is out of bounds
+    #[error("Invalid Slippage")]
+    InvalidSlippage,
+    /// Limit price is not allowed or not positive
+    #[error("Invalid Limit Price")]
+    InvalidLimitPrice,
+    /// Strategy parameters are invalid
+    #[error("Invalid Strategy")]
+    InvalidStrategy,
+    /// End conditions leave no execution
+    #[error("Invalid End Conditions")]
+    InvalidEndConditions,
+    /// Mint weights are invalid
+    #[error("Invalid Weights")]
+    InvalidWeights,
+    /// Pool name is empty, too long or contains a zero byte
+    #[error("Invalid Pool Name")]
+    InvalidPoolName,
 }
 
 impl From<StepError> for ProgramError {
     fn from(e: StepError) -> Self {
         ProgramError::Custom(e as u32)
     }
-}
\ No newline at end of file
+}
+
+impl<T> DecodeError<T> for StepError {
+    fn type_of() -> &'static str {
+        "StepError"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_custom_errors() {
+        assert_eq!(StepError::decode_custom_error_to_enum(0), Some(StepError::InvalidInstruction));
+        assert_eq!(StepError::decode_custom_error_to_enum(3), Some(StepError::AmountOverflow));
+        assert_eq!(
+            StepError::decode_custom_error_to_enum(StepError::InvalidPoolName as u32),
+            Some(StepError::InvalidPoolName)
+        );
+        assert_eq!(StepError::decode_custom_error_to_enum(StepError::InvalidPoolName as u32 + 1), None::<StepError>);
+        assert_eq!(<StepError as DecodeError<StepError>>::type_of(), "StepError");
+        assert_eq!(ProgramError::from(StepError::SlippageExceeded), ProgramError::Custom(StepError::SlippageExceeded as u32));
+    }
+}
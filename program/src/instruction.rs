@@ -1,16 +1,23 @@
 use solana_program::{
     clock::UnixTimestamp,
+    instruction::{AccountMeta, Instruction},
     program_error::ProgramError,
-    program_pack::Pack
+    pubkey::Pubkey,
+    system_program,
+    sysvar
 };
-use std::convert::TryInto;
+use borsh::{BorshDeserialize, BorshSchema, BorshSerialize};
 
 use crate::error::StepError::InvalidInstruction;
+use crate::schedule::Schedule;
 use crate::state::{
-    Stream,
-    PubkeyData
+    Price,
+    PubkeyData,
+    StreamDirection,
+    StreamParams
 };
 
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
 pub enum StepInstruction {
     /// One-time initialization called by the deployer to set some global program states
     ///
@@ -30,111 +37,1455 @@ pub enum StepInstruction {
     ///
     /// 0. `[signer]` The account of deployer.
     /// 1. `[]` The program state account. This is a PDA.
-    /// 2. `[writable]` The pool state account. This is a PDA.
-    /// 3. `[]` System Program.
-    /// 4. `[]` Token Program.
-    /// 5. `[]` Rent sysvar.
-    InitPool {
-        pda_seed: [u8; 32]
-    },
+    /// 2. `[writable]` The pool state account. This is a PDA derived from the mint and the pool name.
+    /// 3. `[writable]` The pools token account. This is a PDA derived from the pool state account.
+    /// 4. `[]` The mint of the token the pool holds.
+    /// 5. `[]` System Program.
+    /// 6. `[]` Token Program.
+    /// 7. `[]` Rent sysvar.
+    InitPool(InitPool),
     /// Deposits a token by transferring it from the user to a token account owned by the PDA for a specified pool.
+    /// On first deposit the depositors pool user account is created in the pools lowest free user slot.
     ///
     ///
     /// Accounts expected:
     ///
-    /// 0. `[signer]` The account of the user depositing funds.
-    /// 1. `[writable]` Depositors pool user account.
-    /// 2. `[]` Depositors token account where source of funds come from.
-    /// 4. `[writable]` The PDA of pool state account.
-    /// 5. `[writable]` The PDA of the pools token account.
-    /// 6. `[]` Token Program.
-    Deposit {
-        /// The amount user wants to deposit
-        amount: u64,
-    },
+    /// 0. `[writable, signer]` The account of the user depositing funds.
+    /// 1. `[writable]` Depositors token account where source of funds come from.
+    /// 2. `[writable]` Depositors pool user account. This is a PDA derived from the pool state account and the user slot.
+    /// 3. `[writable]` The PDA of pool state account.
+    /// 4. `[writable]` The PDA of the pools token account.
+    /// 5. `[]` Token Program.
+    /// 6. `[]` System Program.
+    /// 7. `[]` Rent sysvar.
+    Deposit(Deposit),
     /// Creates a new stream.
-    /// A stream defines the asset pair to swap and at what interval.
+    /// A stream defines the asset pair to swap and its schedule, either a fixed interval of a minute up to a year
+    /// or a daily, weekly or monthly time in UTC. The stream is created in the users lowest free stream slot
+    /// and is first due at the schedules first execution time, straight away for interval schedules.
+    /// The catch-up policy decides how windows missed while no round was executed are handled.
+    /// The direction marks the stream as buying the output mint or selling the input mint, the
+    /// deposit of the user account always being the input. The input mint must be the mint of the
+    /// pool and the free balance of the user account must fund the first window. An optional limit price is a ceiling
+    /// for buys and a floor for sells on the swap price, checked when the stream is gathered and when
+    /// its round is executed. Windows where it is not met are skipped.
+    /// The strategy spends either a fixed amount per window or, for value averaging, what keeps the
+    /// value of the accumulated output on a target growing every window.
     ///
     ///
     /// Accounts expected:
     ///
-    /// 0. `[signer]` The account of the user.
+    /// 0. `[writable, signer]` The account of the user.
     /// 1. `[writable]` The users pool account.
-    /// 2. `[writable]` The PDA of pool state account.
-    /// 4. `[writable]` The new stream account.
-    /// 5. `[]` The last stream in the linked list.
-    CreateStream {
-        input_token_pubkey: PubkeyData,
-        output_token_pubkey: PubkeyData,
-        interval_days: UnixTimestamp,
-        amount: u64
+    /// 2. `[]` The PDA of pool state account.
+    /// 3. `[writable]` The new stream account. This is a PDA derived from the users pool account and the stream slot.
+    /// 4. `[]` System Program.
+    /// 5. `[]` Clock sysvar.
+    /// 6. `[]` Rent sysvar.
+    CreateStream(CreateStream),
+    /// Executes a gathered round of a pair of pools. Callable by anyone.
+    ///
+    /// Both pools must have finished gathering their due streams for each other with [Gather](enum.StepInstruction.html#variant.Gather).
+    /// The input of gathered streams whose limit price the swap's current reserves are not within is
+    /// left out of the round, and returned to the streams the next time they are visited. The rest of
+    /// the input gathered by each pool is matched against the other at the swap's current mid-price.
+    /// Only the imbalance is swapped through the pair's registered route. The output of each side is
+    /// recorded on its pool and credited to the gathered streams the next time they are visited.
+    /// Output of users with auto-delivery can be sent straight away with [Deliver](enum.StepInstruction.html#variant.Deliver).
+    /// The caller is paid the keeper reward if either pool gathered at least its minimum reward input,
+    /// set with [SetMinRewardInput](enum.StepInstruction.html#variant.SetMinRewardInput).
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable]` The account of the keeper receiving the reward.
+    /// 1. `[]` The program state account. This is a PDA.
+    /// 2. `[]` The route account of the pair. This is a PDA.
+    /// 3. `[writable]` The PDA of the input pool state account.
+    /// 4. `[writable]` The PDA of the input pools token account.
+    /// 5. `[writable]` The PDA of the output pool state account.
+    /// 6. `[writable]` The PDA of the output pools token account.
+    /// 7. `[]` Token swap program.
+    /// 8. `[]` Token program.
+    /// 9. `[]` The Swap info account.
+    /// 10. `[]` Authority of the Swap info account.
+    /// 11. `[writable]` Swap token account holding the input pools mint.
+    /// 12. `[writable]` Swap token account holding the output pools mint.
+    /// 13. `[writable]` The Pool LP token mint.
+    /// 14. `[writable]` The swap program owner fee address.
+    /// 15. `[writable]` The keeper reward vault. This is a PDA.
+    /// 16. `[]` System Program.
+    /// 17. `[]` Rent sysvar.
+    Execute {
     },
-    /// Execute a trade
+    /// Registers the swap used to trade between two pools
     ///
     ///
     /// Accounts expected:
     ///
     /// 0. `[signer]` The account of deployer.
-    /// 1. `[writable]` The PDA of programs token account.
-    /// 2. `[]` Token swap program.
+    /// 1. `[]` The program state account. This is a PDA.
+    /// 2. `[writable]` The route account. This is a PDA and the account is created inside the instruction.
+    /// 3. `[]` The PDA of the first pool state account.
+    /// 4. `[]` The PDA of the second pool state account.
+    /// 5. `[]` Token swap program.
+    /// 6. `[]` The Swap info account trading the mints of both pools.
+    /// 7. `[]` System Program.
+    /// 8. `[]` Rent sysvar.
+    InitRoute {
+    },
+    /// Sets the reward paid to keepers for each execution that trades
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of deployer.
+    /// 1. `[writable]` The program state account. This is a PDA.
+    SetKeeperReward(SetKeeperReward),
+    /// Gathers a page of due streams of a pool for the next execution round with another pool.
+    /// Callable by anyone.
+    ///
+    /// If both pools are idle a new round is started for the pair. User and stream slots are visited
+    /// in order starting from the pools cursor, skipping free slots, and the page ends when the
+    /// accounts run out. Each due stream is debited from its user and its input added to the round.
+    /// Due streams whose limit price is not met by the reserves of the pairs swap skip their window.
+    /// A round takes streams with at most four distinct limit prices, streams with another limit
+    /// price stay due for a later round. The pool is gathered once its cursor has visited every user slot.
+    ///
+    /// Output of the other pools mint settled to the streams of users with auto-delivery is sent to
+    /// the owners associated token account, or stays credited to the stream when that account does
+    /// not exist.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable]` The PDA of the pool state account being gathered.
+    /// 1. `[writable]` The PDA of the other pool state account of the pair.
+    /// 2. `[]` The route account of the pair. This is a PDA.
+    /// 3. `[]` Clock sysvar.
+    /// 4. `[]` The Swap info account of the route.
+    /// 5. `[]` Swap token account holding the gathered pools mint.
+    /// 6. `[]` Swap token account holding the other pools mint.
+    /// 7. `[writable]` The PDA of the other pools token account.
+    /// 8. `[]` Token program.
+    /// 9.. `[writable]` The pools users, each followed by its streams, in slot order from the cursor.
+    ///     The streams of users with auto-delivery are each followed by the owners associated token
+    ///     account of the other pools mint. It can be left out, the output then stays credited.
+    Gather {
+    },
+    /// Cancels a stream. Any output of the stream not claimed yet is paid out to the owner, the
+    /// streams slot is freed and its account closed with the rent refunded to the owner. Input of a
+    /// basket stream still waiting for the rounds of its pairs is returned to the user account.
+    /// Fails while the stream has input in a round that is not executed yet.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable, signer]` The account of the stream owner.
+    /// 1. `[writable]` The owners pool user account.
+    /// 2. `[]` The PDA of the pool state account of the user account.
+    /// 3. `[writable]` The stream account.
+    /// 4. `[]` The PDA of the pool state account holding the streams output mint.
+    /// 5. `[writable]` The PDA of the output pools token account.
+    /// 6. `[writable]` The owners token account receiving the unclaimed output.
+    ///    Accounts 4 to 6 are repeated for every output of a basket stream, in the baskets order.
+    /// 7. `[]` Token Program.
+    CancelStream {
+    },
+    /// Pauses a stream. The stream is kept but skipped by executions until resumed.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the stream owner.
+    /// 1. `[]` The owners pool user account.
+    /// 2. `[writable]` The stream account.
+    PauseStream {
+    },
+    /// Resumes a paused stream. It is due again at its next scheduled time, or straight away if
+    /// that time passed while it was paused.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the stream owner.
+    /// 1. `[]` The owners pool user account.
+    /// 2. `[writable]` The stream account.
+    /// 3. `[]` Clock sysvar.
+    ResumeStream {
+    },
+    /// Changes the parameters and end conditions of a stream, keeping its execution history.
+    /// Input of an executed round is settled first, and a new schedule applies from the streams
+    /// next execution. Fails while the stream has input in a round that is not executed yet.
+    /// Completed streams cannot be updated, and the free balance must fund the new window amount.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the stream owner.
+    /// 1. `[writable]` The owners pool user account.
+    /// 2. `[]` The PDA of the pool state account of the user account.
+    /// 3. `[writable]` The stream account.
+    /// 4. `[]` Clock sysvar.
+    UpdateStream(UpdateStream),
+    /// Pays out the output credited to a stream so far to its owner.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the stream owner.
+    /// 1. `[writable]` The owners pool user account.
+    /// 2. `[]` The PDA of the pool state account of the user account.
+    /// 3. `[writable]` The stream account.
+    /// 4. `[]` The PDA of the pool state account holding the streams output mint.
+    /// 5. `[writable]` The PDA of the output pools token account.
+    /// 6. `[writable]` The owners token account receiving the output.
+    ///    Accounts 4 to 6 are repeated for every output of a basket stream, in the baskets order.
+    /// 7. `[]` Token Program.
+    Claim {
+    },
+    /// Creates a basket stream, splitting the input of every window across up to eight output mints
+    /// by weights in bps adding up to 10000. Each output is gathered in the rounds of the pair of the
+    /// input mint and its mint and executed through that pairs registered route, its output credited
+    /// separately. Basket streams spend a fixed amount and cannot have a limit price.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// Same as [CreateStream](enum.StepInstruction.html#variant.CreateStream).
+    CreateBasketStream(CreateBasketStream),
+    /// Creates a rebalancing stream keeping the users deposits across the pools of up to eight mints
+    /// at target weights in bps adding up to 10000. The first mint is the one of the given user
+    /// accounts pool and values the others, each of which needs a route with it. The stream is first
+    /// due at the schedules first execution time.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable, signer]` The account of the user.
+    /// 1. `[]` The users pool account in the pool of the first mint.
+    /// 2. `[]` The PDA of the pool state account of the first mint.
+    /// 3. `[writable]` The new rebalance stream account. This is a PDA derived from the users pool account.
+    /// 4. `[]` System Program.
+    /// 5. `[]` Clock sysvar.
+    /// 6. `[]` Rent sysvar.
+    CreateRebalanceStream(CreateRebalanceStream),
+    /// Runs a due rebalancing stream. Callable by anyone.
+    ///
+    /// Values the users deposits in the first mint and, when a weight drifted from its target past
+    /// the streams threshold, swaps the most overweight deposit into the most underweight one through
+    /// the route of their pools. The trade is capped at the streams maximum trade value and moves
+    /// the deposits between the two pools token accounts. The trade accounts can be left out when
+    /// the holdings are within the threshold.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[]` Clock sysvar.
+    /// 1. `[writable]` The rebalance stream account.
+    /// 2. For every target in order:
+    ///    `[]` The PDA of the pool state account of the mint.
+    ///    `[writable]` The owners pool user account in that pool.
+    /// 3. For every target but the first, in order:
+    ///    `[]` The route account of the pools of the first mint and the mint.
+    ///    `[]` The Swap info account of the route.
+    ///    `[]` Swap token account holding the first mint.
+    ///    `[]` Swap token account holding the mint.
+    /// 4. `[]` The route account of the pools of the overweight and underweight mints.
+    /// 5. `[writable]` The PDA of the overweight pools token account.
+    /// 6. `[writable]` The PDA of the underweight pools token account.
+    /// 7. `[]` Token swap program.
+    /// 8. `[]` Token program.
+    /// 9. `[]` The Swap info account.
+    /// 10. `[]` Authority of the Swap info account.
+    /// 11. `[writable]` Swap token account holding the overweight mint.
+    /// 12. `[writable]` Swap token account holding the underweight mint.
+    /// 13. `[writable]` Swap pool mint.
+    /// 14. `[writable]` Swap pool fee account.
+    Rebalance {
+    },
+    /// Cancels a rebalancing stream, closing its account with the rent refunded to the owner.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable, signer]` The account of the stream owner.
+    /// 1. `[writable]` The rebalance stream account.
+    CancelRebalanceStream {
+    },
+    /// Creates a TWAP order selling a fixed amount of the users deposit into an output mint in equal
+    /// slices over a time range. The whole amount is set aside from the deposit on creation.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable, signer]` The account of the order owner.
+    /// 1. `[writable]` The owners pool user account holding the input.
+    /// 2. `[]` The PDA of the pool state account of the input.
+    /// 3. `[writable]` The order account. This is a PDA and the account is created inside the instruction.
+    /// 4. `[]` System Program.
+    /// 5. `[]` Clock sysvar.
+    /// 6. `[]` Rent sysvar.
+    CreateTwapOrder(CreateTwapOrder),
+    /// Swaps the due slices of a TWAP order through the route of its pools. Callable by anyone.
+    ///
+    /// Slices skipped by the limit price roll forward into the next slice, the last slice stays due
+    /// until it is filled. The caller is paid the keeper reward for slices of at least the minimum
+    /// reward input of the input pool.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. - 17. Same as the accounts of Execute for the orders input and output pools.
+    /// 18. `[]` Clock sysvar.
+    /// 19. `[writable]` The order account.
+    ExecuteTwapSlice {
+    },
+    /// Closes a TWAP order. The unfilled input is returned to the users deposit and the output paid
+    /// out to the owner before the account is closed with the rent refunded.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable, signer]` The account of the order owner.
+    /// 1. `[writable]` The owners pool user account holding the input.
+    /// 2. `[writable]` The order account.
+    /// 3. `[]` The PDA of the pool state account of the output.
+    /// 4. `[writable]` The PDA of the output pools token account.
+    /// 5. `[writable]` The owners token account receiving the output.
+    /// 6. `[]` Token program.
+    CloseTwapOrder {
+    },
+    /// Turns auto-delivery of a user accounts output on or off. With auto-delivery the output
+    /// settled to its streams is sent to the owners associated token accounts while gathering, or
+    /// by anyone with Deliver.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the user account owner.
+    /// 1. `[writable]` The pool user account.
+    SetAutoDelivery(SetAutoDelivery),
+    /// Deposits native SOL into a pool of wrapped SOL. The lamports are transferred from the user to
+    /// the pools token account and synced into its token balance. On first deposit the depositors
+    /// pool user account is created in the pools lowest free user slot.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable, signer]` The account of the user depositing lamports.
+    /// 1. `[writable]` Depositors pool user account. This is a PDA derived from the pool state account and the user slot.
+    /// 2. `[writable]` The PDA of the wrapped SOL pool state account.
+    /// 3. `[writable]` The PDA of the pools token account.
+    /// 4. `[]` Token Program.
+    /// 5. `[]` System Program.
+    /// 6. `[]` Rent sysvar.
+    DepositSol(DepositSol),
+    /// Claims the wrapped SOL output of a stream as lamports. Other outputs of a basket stream are
+    /// left credited.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable, signer]` The account of the stream owner, receiving the lamports.
+    /// 1. `[writable]` The owners pool user account.
+    /// 2. `[]` The PDA of the pool state account of the user.
+    /// 3. `[writable]` The stream account.
+    /// 4. `[]` The PDA of the wrapped SOL pool state account.
+    /// 5. `[writable]` The PDA of the wrapped SOL pools token account.
+    /// 6. `[writable]` The token account unwrapping the output. This is a PDA derived from the wrapped
+    ///    SOL pool state account, created and closed inside the instruction.
+    /// 7. `[]` The native mint.
+    /// 8. `[]` Token program.
+    /// 9. `[]` System Program.
+    /// 10. `[]` Rent sysvar.
+    ClaimSol {
+    },
+    /// Withdraws from the free balance of a user account, the part not reserved for the next window
+    /// of its streams. When the free balance falls short the listed streams are shrunk or cancelled
+    /// in order if requested, otherwise the withdrawal fails. Cancelled streams are completed and
+    /// keep their account until closed with CancelStream. A withdrawal leaving the user account
+    /// without balance, streams or TWAP orders closes it and frees its user slot.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable, signer]` The account of the user account owner.
+    /// 1. `[writable]` The owners pool user account.
+    /// 2. `[writable]` The PDA of the pool state account.
+    /// 3. `[writable]` The PDA of the pools token account.
+    /// 4. `[writable]` The owners token account receiving the withdrawal, or the account receiving
+    ///    the lamports when unwrapping.
+    /// 5. `[]` Token program.
+    /// 6. `[]` Clock sysvar.
+    /// 7. When unwrapping:
+    ///    `[writable]` The token account unwrapping the withdrawal.
+    ///    `[]` The native mint.
+    ///    `[]` System Program.
+    ///    `[]` Rent sysvar.
+    /// 8.. `[writable]` Streams of the user account released in order when the free balance falls short.
+    Withdraw(Withdraw),
+    /// Sends the settled output of one mint of streams of users with auto-delivery to the owners
+    /// associated token accounts. Callable by anyone, typically in the transaction of Execute, so
+    /// output is delivered without waiting for the next round of the pair to gather the streams.
+    /// Streams whose owner has no associated token account keep their output credited.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[]` The PDA of the pool state account of the user accounts.
+    /// 1. `[]` The PDA of the pool state account holding the output mint.
+    /// 2. `[writable]` The PDA of the output pools token account.
     /// 3. `[]` Token program.
-    /// 4. `[]` Step Program.
-    /// 5. `[]` The Swap info account.
-    /// 6. `[]` Mint authority for the Pool LP token.
-    /// 7. `[writable]` Token A account for SOURCE.
-    /// 8. `[writable]` Token B account for DESTINATION.
-    /// 9. `[writable]` DESTINATION Token Account.
-    /// 10. `[writable]` The Pool LP token account.
-    /// 11.`[writable]` The swap program owner fee address.
-    Execute {
-        pda_seed: [u8; 32]
+    /// 4. `[writable]` A user account of the pool with auto-delivery.
+    /// 5. `[writable]` A stream of the user account.
+    /// 6. `[writable]` The owners associated token account of the output mint.
+    ///    Accounts 4 to 6 are repeated for every stream delivered.
+    Deliver {
+    },
+    /// Sets the least input a round of the pool must gather for its execution to pay the keeper reward,
+    /// in the pools mint. Rounds gathering less are still executed, without a reward.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of deployer.
+    /// 1. `[]` The program state account. This is a PDA.
+    /// 2. `[writable]` The PDA of the pool state account.
+    SetMinRewardInput(SetMinRewardInput),
+    /// Ends the round a pair of pools is gathering or waiting to execute without executing it, for
+    /// rounds that cannot be executed anymore. The input gathered by their streams goes back to the
+    /// user accounts as the streams are settled.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of deployer.
+    /// 1. `[]` The program state account. This is a PDA.
+    /// 2. `[writable]` The PDA of a pool state account of the pair.
+    /// 3. `[writable]` The PDA of the other pool state account of the pair.
+    AbortRound {
     }
 }
 
+/// Payload of [InitPool](enum.StepInstruction.html#variant.InitPool)
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct InitPool {
+    /// Name of the pool, unique per mint and at most `MAX_POOL_NAME_LEN` bytes
+    pub name: String,
+}
+
+/// Payload of [Deposit](enum.StepInstruction.html#variant.Deposit)
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct Deposit {
+    /// The amount user wants to deposit
+    pub amount: u64,
+}
+
+/// Payload of [CreateStream](enum.StepInstruction.html#variant.CreateStream)
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct CreateStream {
+    pub input_token_pubkey: PubkeyData,
+    pub output_token_pubkey: PubkeyData,
+    pub direction: StreamDirection,
+    pub params: StreamParams,
+}
+
+/// Payload of [SetKeeperReward](enum.StepInstruction.html#variant.SetKeeperReward)
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct SetKeeperReward {
+    pub keeper_reward_lamports: u64,
+}
+
+/// Payload of [UpdateStream](enum.StepInstruction.html#variant.UpdateStream)
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct UpdateStream {
+    pub params: StreamParams,
+}
+
+/// Payload of [CreateBasketStream](enum.StepInstruction.html#variant.CreateBasketStream)
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct CreateBasketStream {
+    pub input_token_pubkey: PubkeyData,
+    pub direction: StreamDirection,
+    /// Output mints and their weights in bps
+    pub outputs: Vec<(PubkeyData, u16)>,
+    pub params: StreamParams,
+}
+
+/// Payload of [CreateRebalanceStream](enum.StepInstruction.html#variant.CreateRebalanceStream)
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct CreateRebalanceStream {
+    pub schedule: Schedule,
+    /// Distance of a weight from its target in bps past which the stream trades
+    pub drift_bps: u16,
+    /// Largest value, in the first mint, traded by one rebalance step
+    pub max_trade_value: u64,
+    pub slippage_bps: u16,
+    /// Mints and their target weights in bps
+    pub targets: Vec<(PubkeyData, u16)>,
+}
+
+/// Payload of [CreateTwapOrder](enum.StepInstruction.html#variant.CreateTwapOrder)
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct CreateTwapOrder {
+    /// Id of the order among the orders of the user account
+    pub order_id: u64,
+    pub output_token_pubkey: PubkeyData,
+    pub direction: StreamDirection,
+    pub total_amount: u64,
+    pub start_ts: UnixTimestamp,
+    pub end_ts: UnixTimestamp,
+    pub slice_count: u32,
+    pub limit_price: Option<Price>,
+    pub slippage_bps: u16,
+}
+
+/// Payload of [SetAutoDelivery](enum.StepInstruction.html#variant.SetAutoDelivery)
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct SetAutoDelivery {
+    pub enabled: bool,
+}
+
+/// Payload of [SetMinRewardInput](enum.StepInstruction.html#variant.SetMinRewardInput)
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct SetMinRewardInput {
+    pub min_reward_input: u64,
+}
+
+/// Payload of [DepositSol](enum.StepInstruction.html#variant.DepositSol)
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct DepositSol {
+    pub amount: u64,
+}
+
+/// Payload of [Withdraw](enum.StepInstruction.html#variant.Withdraw)
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct Withdraw {
+    pub amount: u64,
+    pub release: Option<StreamRelease>,
+    /// Whether wrapped SOL is paid out as lamports
+    pub unwrap: bool,
+}
+
+/// How a withdrawal frees balance reserved for streams
+#[derive(Clone, Copy, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub enum StreamRelease {
+    /// Fixed amount streams spend less per window
+    Shrink,
+    /// Streams are completed
+    Cancel
+}
+
 impl StepInstruction {
-    /// Unpacks a byte buffer into a [StepInstruction](enum.StepInstruction.html).
+    /// Unpacks a byte buffer into a [StepInstruction](enum.StepInstruction.html). The buffer is the
+    /// Borsh encoding of the instruction, a tag byte of the variant followed by its payload, and
+    /// must hold nothing else.
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
-        let (tag, rest) = input.split_first().ok_or(InvalidInstruction)?;
-
-        Ok(match tag {
-            0 => Self::InitProgram {
-            },
-            1 => Self::InitPool {
-                pda_seed: Self::unpack_pda_seed(rest)?
-            },
-            2 => Self::Deposit {
-                amount: Self::unpack_amount(rest)?,
-            },
-            3 => {
-                let s = Stream::unpack_from_slice(rest)?;
-                Self::CreateStream {
-                    input_token_pubkey: s.input_token_pubkey,
-                    output_token_pubkey: s.output_token_pubkey,
-                    interval_days: s.interval_days,
-                    amount: s.amount
-                }
-            },
-            4 => Self::Execute {
-                pda_seed: Self::unpack_pda_seed(rest)?
-            },
-            _ => return Err(InvalidInstruction.into()),
-        })
-    }
-
-    fn unpack_amount(input: &[u8]) -> Result<u64, ProgramError> {
-        let amount = input
-            .get(..8)
-            .and_then(|slice| slice.try_into().ok())
-            .map(u64::from_le_bytes)
-            .ok_or(InvalidInstruction)?;
-        Ok(amount)
-    }
-
-    fn unpack_pda_seed(input: &[u8]) -> Result<[u8; 32], ProgramError> {
-        let amount = input
-            .get(..32)
-            .and_then(|slice| slice.try_into().ok())
-            .ok_or(InvalidInstruction)?;
-        Ok(amount)
-    }
-}
\ No newline at end of file
+        let mut data = input;
+        let instruction = Self::deserialize(&mut data).map_err(|_| InvalidInstruction)?;
+        if !data.is_empty() {
+            return Err(InvalidInstruction.into());
+        }
+        Ok(instruction)
+    }
+
+    /// Packs a [StepInstruction](enum.StepInstruction.html) into a byte buffer.
+    pub fn pack(&self) -> Vec<u8> {
+        self.try_to_vec().unwrap()
+    }
+}
+
+/// Accounts of the token swap a pair of pools trades through, with the swaps token accounts
+/// named from the side of the trades input
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SwapAccountKeys {
+    pub program_id: Pubkey,
+    pub swap: Pubkey,
+    pub authority: Pubkey,
+    pub input_token: Pubkey,
+    pub output_token: Pubkey,
+    pub pool_mint: Pubkey,
+    pub fee_account: Pubkey,
+}
+
+impl SwapAccountKeys {
+    fn account_metas(&self) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(self.program_id, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(self.swap, false),
+            AccountMeta::new_readonly(self.authority, false),
+            AccountMeta::new(self.input_token, false),
+            AccountMeta::new(self.output_token, false),
+            AccountMeta::new(self.pool_mint, false),
+            AccountMeta::new(self.fee_account, false),
+        ]
+    }
+}
+
+/// Creates an `InitProgram` instruction.
+pub fn init_program(program_id: &Pubkey, deployer: &Pubkey, program_state: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*deployer, true),
+            AccountMeta::new(*program_state, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data: StepInstruction::InitProgram { }.pack(),
+    }
+}
+
+/// Creates an `InitPool` instruction.
+pub fn init_pool(
+    program_id: &Pubkey,
+    deployer: &Pubkey,
+    program_state: &Pubkey,
+    pool: &Pubkey,
+    pool_token_account: &Pubkey,
+    mint: &Pubkey,
+    name: &str,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*deployer, true),
+            AccountMeta::new_readonly(*program_state, false),
+            AccountMeta::new(*pool, false),
+            AccountMeta::new(*pool_token_account, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data: StepInstruction::InitPool(InitPool { name: name.to_string() }).pack(),
+    }
+}
+
+/// Creates a `Deposit` instruction.
+pub fn deposit(
+    program_id: &Pubkey,
+    depositor: &Pubkey,
+    depositor_token_account: &Pubkey,
+    user_account: &Pubkey,
+    pool: &Pubkey,
+    pool_token_account: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*depositor, true),
+            AccountMeta::new(*depositor_token_account, false),
+            AccountMeta::new(*user_account, false),
+            AccountMeta::new(*pool, false),
+            AccountMeta::new(*pool_token_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data: StepInstruction::Deposit(Deposit { amount }).pack(),
+    }
+}
+
+/// Creates a `CreateStream` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn create_stream(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    user_account: &Pubkey,
+    pool: &Pubkey,
+    stream: &Pubkey,
+    input_mint: &Pubkey,
+    output_mint: &Pubkey,
+    direction: StreamDirection,
+    params: StreamParams,
+) -> Instruction {
+    let data = StepInstruction::CreateStream(CreateStream {
+        input_token_pubkey: input_mint.to_bytes(),
+        output_token_pubkey: output_mint.to_bytes(),
+        direction,
+        params
+    }).pack();
+    create_stream_instruction(program_id, user, user_account, pool, stream, data)
+}
+
+/// Creates a `CreateBasketStream` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn create_basket_stream(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    user_account: &Pubkey,
+    pool: &Pubkey,
+    stream: &Pubkey,
+    input_mint: &Pubkey,
+    direction: StreamDirection,
+    outputs: &[(Pubkey, u16)],
+    params: StreamParams,
+) -> Instruction {
+    let data = StepInstruction::CreateBasketStream(CreateBasketStream {
+        input_token_pubkey: input_mint.to_bytes(),
+        direction,
+        outputs: outputs.iter().map(|(mint, weight_bps)| (mint.to_bytes(), *weight_bps)).collect(),
+        params
+    }).pack();
+    create_stream_instruction(program_id, user, user_account, pool, stream, data)
+}
+
+/// Accounts shared by the instructions creating a stream or an order at a PDA of the user account
+fn create_stream_instruction(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    user_account: &Pubkey,
+    pool: &Pubkey,
+    stream: &Pubkey,
+    data: Vec<u8>,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*user, true),
+            AccountMeta::new(*user_account, false),
+            AccountMeta::new_readonly(*pool, false),
+            AccountMeta::new(*stream, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data,
+    }
+}
+
+/// Creates an `Execute` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn execute(
+    program_id: &Pubkey,
+    keeper: &Pubkey,
+    program_state: &Pubkey,
+    route: &Pubkey,
+    input_pool: &Pubkey,
+    input_pool_token_account: &Pubkey,
+    output_pool: &Pubkey,
+    output_pool_token_account: &Pubkey,
+    swap_accounts: &SwapAccountKeys,
+    reward_vault: &Pubkey,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*keeper, false),
+        AccountMeta::new_readonly(*program_state, false),
+        AccountMeta::new_readonly(*route, false),
+        AccountMeta::new(*input_pool, false),
+        AccountMeta::new(*input_pool_token_account, false),
+        AccountMeta::new(*output_pool, false),
+        AccountMeta::new(*output_pool_token_account, false),
+    ];
+    accounts.extend(swap_accounts.account_metas());
+    accounts.extend_from_slice(&[
+        AccountMeta::new(*reward_vault, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+    ]);
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: StepInstruction::Execute { }.pack(),
+    }
+}
+
+/// Creates an `InitRoute` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn init_route(
+    program_id: &Pubkey,
+    deployer: &Pubkey,
+    program_state: &Pubkey,
+    route: &Pubkey,
+    first_pool: &Pubkey,
+    second_pool: &Pubkey,
+    token_swap_program: &Pubkey,
+    swap: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*deployer, true),
+            AccountMeta::new_readonly(*program_state, false),
+            AccountMeta::new(*route, false),
+            AccountMeta::new_readonly(*first_pool, false),
+            AccountMeta::new_readonly(*second_pool, false),
+            AccountMeta::new_readonly(*token_swap_program, false),
+            AccountMeta::new_readonly(*swap, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data: StepInstruction::InitRoute { }.pack(),
+    }
+}
+
+/// Creates a `SetKeeperReward` instruction.
+pub fn set_keeper_reward(
+    program_id: &Pubkey,
+    deployer: &Pubkey,
+    program_state: &Pubkey,
+    keeper_reward_lamports: u64,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*deployer, true),
+            AccountMeta::new(*program_state, false),
+        ],
+        data: StepInstruction::SetKeeperReward(SetKeeperReward { keeper_reward_lamports }).pack(),
+    }
+}
+
+/// Creates a `Gather` instruction for a page of users and streams, given in slot order with the
+/// associated token accounts of auto-delivery users after their streams.
+#[allow(clippy::too_many_arguments)]
+pub fn gather(
+    program_id: &Pubkey,
+    pool: &Pubkey,
+    other_pool: &Pubkey,
+    route: &Pubkey,
+    swap: &Pubkey,
+    swap_pool_token: &Pubkey,
+    swap_other_token: &Pubkey,
+    other_pool_token_account: &Pubkey,
+    page: &[Pubkey],
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*pool, false),
+        AccountMeta::new(*other_pool, false),
+        AccountMeta::new_readonly(*route, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(*swap, false),
+        AccountMeta::new_readonly(*swap_pool_token, false),
+        AccountMeta::new_readonly(*swap_other_token, false),
+        AccountMeta::new(*other_pool_token_account, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+    ];
+    accounts.extend(page.iter().map(|account| AccountMeta::new(*account, false)));
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: StepInstruction::Gather { }.pack(),
+    }
+}
+
+/// Creates a `CancelStream` instruction. `outputs` holds the output pool, its token account and
+/// the owners token account for the stream, or for every output of a basket stream.
+pub fn cancel_stream(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    user_account: &Pubkey,
+    pool: &Pubkey,
+    stream: &Pubkey,
+    outputs: &[(Pubkey, Pubkey, Pubkey)],
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*owner, true),
+        AccountMeta::new(*user_account, false),
+        AccountMeta::new_readonly(*pool, false),
+        AccountMeta::new(*stream, false),
+    ];
+    accounts.extend(output_account_metas(outputs));
+    accounts.push(AccountMeta::new_readonly(spl_token::id(), false));
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: StepInstruction::CancelStream { }.pack(),
+    }
+}
+
+/// Creates a `PauseStream` instruction.
+pub fn pause_stream(program_id: &Pubkey, owner: &Pubkey, user_account: &Pubkey, stream: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*owner, true),
+            AccountMeta::new_readonly(*user_account, false),
+            AccountMeta::new(*stream, false),
+        ],
+        data: StepInstruction::PauseStream { }.pack(),
+    }
+}
+
+/// Creates a `ResumeStream` instruction.
+pub fn resume_stream(program_id: &Pubkey, owner: &Pubkey, user_account: &Pubkey, stream: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*owner, true),
+            AccountMeta::new_readonly(*user_account, false),
+            AccountMeta::new(*stream, false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+        ],
+        data: StepInstruction::ResumeStream { }.pack(),
+    }
+}
+
+/// Creates an `UpdateStream` instruction.
+pub fn update_stream(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    user_account: &Pubkey,
+    pool: &Pubkey,
+    stream: &Pubkey,
+    params: StreamParams,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*owner, true),
+            AccountMeta::new(*user_account, false),
+            AccountMeta::new_readonly(*pool, false),
+            AccountMeta::new(*stream, false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+        ],
+        data: StepInstruction::UpdateStream(UpdateStream { params }).pack(),
+    }
+}
+
+/// Creates a `Claim` instruction. `outputs` is as for [cancel_stream](fn.cancel_stream.html).
+pub fn claim(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    user_account: &Pubkey,
+    pool: &Pubkey,
+    stream: &Pubkey,
+    outputs: &[(Pubkey, Pubkey, Pubkey)],
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*owner, true),
+        AccountMeta::new(*user_account, false),
+        AccountMeta::new_readonly(*pool, false),
+        AccountMeta::new(*stream, false),
+    ];
+    accounts.extend(output_account_metas(outputs));
+    accounts.push(AccountMeta::new_readonly(spl_token::id(), false));
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: StepInstruction::Claim { }.pack(),
+    }
+}
+
+fn output_account_metas(outputs: &[(Pubkey, Pubkey, Pubkey)]) -> Vec<AccountMeta> {
+    outputs
+        .iter()
+        .flat_map(|(output_pool, output_pool_token_account, owner_token_account)| vec![
+            AccountMeta::new_readonly(*output_pool, false),
+            AccountMeta::new(*output_pool_token_account, false),
+            AccountMeta::new(*owner_token_account, false),
+        ])
+        .collect()
+}
+
+/// Creates a `CreateRebalanceStream` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn create_rebalance_stream(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    user_account: &Pubkey,
+    pool: &Pubkey,
+    stream: &Pubkey,
+    schedule: Schedule,
+    drift_bps: u16,
+    max_trade_value: u64,
+    slippage_bps: u16,
+    targets: &[(Pubkey, u16)],
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*owner, true),
+            AccountMeta::new_readonly(*user_account, false),
+            AccountMeta::new_readonly(*pool, false),
+            AccountMeta::new(*stream, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data: StepInstruction::CreateRebalanceStream(CreateRebalanceStream {
+            schedule,
+            drift_bps,
+            max_trade_value,
+            slippage_bps,
+            targets: targets.iter().map(|(mint, weight_bps)| (mint.to_bytes(), *weight_bps)).collect()
+        }).pack(),
+    }
+}
+
+/// Creates a `Rebalance` instruction.
+///
+/// `holdings` holds the pool and the owners user account of every target, `valuations` the
+/// route, swap and swap token accounts of the first and the targets mint for every target but
+/// the first. `trade` holds the route and the token accounts of the overweight and underweight
+/// pools with the swap between them, and can be left out when no trade is due.
+pub fn rebalance(
+    program_id: &Pubkey,
+    stream: &Pubkey,
+    holdings: &[(Pubkey, Pubkey)],
+    valuations: &[(Pubkey, Pubkey, Pubkey, Pubkey)],
+    trade: Option<(Pubkey, Pubkey, Pubkey, SwapAccountKeys)>,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new(*stream, false),
+    ];
+    for (pool, user_account) in holdings {
+        accounts.push(AccountMeta::new_readonly(*pool, false));
+        accounts.push(AccountMeta::new(*user_account, false));
+    }
+    for (route, swap, swap_first_token, swap_target_token) in valuations {
+        accounts.extend_from_slice(&[
+            AccountMeta::new_readonly(*route, false),
+            AccountMeta::new_readonly(*swap, false),
+            AccountMeta::new_readonly(*swap_first_token, false),
+            AccountMeta::new_readonly(*swap_target_token, false),
+        ]);
+    }
+    if let Some((route, overweight_token_account, underweight_token_account, swap_accounts)) = trade {
+        accounts.extend_from_slice(&[
+            AccountMeta::new_readonly(route, false),
+            AccountMeta::new(overweight_token_account, false),
+            AccountMeta::new(underweight_token_account, false),
+        ]);
+        accounts.extend(swap_accounts.account_metas());
+    }
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: StepInstruction::Rebalance { }.pack(),
+    }
+}
+
+/// Creates a `CancelRebalanceStream` instruction.
+pub fn cancel_rebalance_stream(program_id: &Pubkey, owner: &Pubkey, stream: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*owner, true),
+            AccountMeta::new(*stream, false),
+        ],
+        data: StepInstruction::CancelRebalanceStream { }.pack(),
+    }
+}
+
+/// Creates a `CreateTwapOrder` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn create_twap_order(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    user_account: &Pubkey,
+    pool: &Pubkey,
+    order: &Pubkey,
+    order_id: u64,
+    output_mint: &Pubkey,
+    direction: StreamDirection,
+    total_amount: u64,
+    start_ts: UnixTimestamp,
+    end_ts: UnixTimestamp,
+    slice_count: u32,
+    limit_price: Option<Price>,
+    slippage_bps: u16,
+) -> Instruction {
+    let data = StepInstruction::CreateTwapOrder(CreateTwapOrder {
+        order_id,
+        output_token_pubkey: output_mint.to_bytes(),
+        direction,
+        total_amount,
+        start_ts,
+        end_ts,
+        slice_count,
+        limit_price,
+        slippage_bps
+    }).pack();
+    create_stream_instruction(program_id, owner, user_account, pool, order, data)
+}
+
+/// Creates an `ExecuteTwapSlice` instruction, with the accounts of `Execute` for the orders pools.
+#[allow(clippy::too_many_arguments)]
+pub fn execute_twap_slice(
+    program_id: &Pubkey,
+    keeper: &Pubkey,
+    program_state: &Pubkey,
+    route: &Pubkey,
+    input_pool: &Pubkey,
+    input_pool_token_account: &Pubkey,
+    output_pool: &Pubkey,
+    output_pool_token_account: &Pubkey,
+    swap_accounts: &SwapAccountKeys,
+    reward_vault: &Pubkey,
+    order: &Pubkey,
+) -> Instruction {
+    let mut instruction = execute(
+        program_id,
+        keeper,
+        program_state,
+        route,
+        input_pool,
+        input_pool_token_account,
+        output_pool,
+        output_pool_token_account,
+        swap_accounts,
+        reward_vault,
+    );
+    instruction.accounts.push(AccountMeta::new_readonly(sysvar::clock::id(), false));
+    instruction.accounts.push(AccountMeta::new(*order, false));
+    instruction.data = StepInstruction::ExecuteTwapSlice { }.pack();
+    instruction
+}
+
+/// Creates a `CloseTwapOrder` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn close_twap_order(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    user_account: &Pubkey,
+    order: &Pubkey,
+    output_pool: &Pubkey,
+    output_pool_token_account: &Pubkey,
+    owner_token_account: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*owner, true),
+            AccountMeta::new(*user_account, false),
+            AccountMeta::new(*order, false),
+            AccountMeta::new_readonly(*output_pool, false),
+            AccountMeta::new(*output_pool_token_account, false),
+            AccountMeta::new(*owner_token_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+        data: StepInstruction::CloseTwapOrder { }.pack(),
+    }
+}
+
+/// Creates a `SetAutoDelivery` instruction.
+pub fn set_auto_delivery(program_id: &Pubkey, owner: &Pubkey, user_account: &Pubkey, enabled: bool) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*owner, true),
+            AccountMeta::new(*user_account, false),
+        ],
+        data: StepInstruction::SetAutoDelivery(SetAutoDelivery { enabled }).pack(),
+    }
+}
+
+/// Creates a `DepositSol` instruction.
+pub fn deposit_sol(
+    program_id: &Pubkey,
+    depositor: &Pubkey,
+    user_account: &Pubkey,
+    pool: &Pubkey,
+    pool_token_account: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*depositor, true),
+            AccountMeta::new(*user_account, false),
+            AccountMeta::new(*pool, false),
+            AccountMeta::new(*pool_token_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data: StepInstruction::DepositSol(DepositSol { amount }).pack(),
+    }
+}
+
+/// Creates a `ClaimSol` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn claim_sol(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    user_account: &Pubkey,
+    pool: &Pubkey,
+    stream: &Pubkey,
+    output_pool: &Pubkey,
+    output_pool_token_account: &Pubkey,
+    unwrap_account: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*owner, true),
+            AccountMeta::new(*user_account, false),
+            AccountMeta::new_readonly(*pool, false),
+            AccountMeta::new(*stream, false),
+            AccountMeta::new_readonly(*output_pool, false),
+            AccountMeta::new(*output_pool_token_account, false),
+            AccountMeta::new(*unwrap_account, false),
+            AccountMeta::new_readonly(spl_token::native_mint::id(), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data: StepInstruction::ClaimSol { }.pack(),
+    }
+}
+
+/// Creates a `Withdraw` instruction. Wrapped SOL is withdrawn as lamports to the destination
+/// through `unwrap_account` when it is given.
+#[allow(clippy::too_many_arguments)]
+pub fn withdraw(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    user_account: &Pubkey,
+    pool: &Pubkey,
+    pool_token_account: &Pubkey,
+    destination: &Pubkey,
+    unwrap_account: Option<&Pubkey>,
+    streams: &[Pubkey],
+    amount: u64,
+    release: Option<StreamRelease>,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*owner, true),
+        AccountMeta::new(*user_account, false),
+        AccountMeta::new(*pool, false),
+        AccountMeta::new(*pool_token_account, false),
+        AccountMeta::new(*destination, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+    if let Some(unwrap_account) = unwrap_account {
+        accounts.extend_from_slice(&[
+            AccountMeta::new(*unwrap_account, false),
+            AccountMeta::new_readonly(spl_token::native_mint::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ]);
+    }
+    accounts.extend(streams.iter().map(|stream| AccountMeta::new(*stream, false)));
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: StepInstruction::Withdraw(Withdraw { amount, release, unwrap: unwrap_account.is_some() }).pack(),
+    }
+}
+
+/// Creates a `Deliver` instruction. `deliveries` holds for every stream its user account, the stream
+/// and the owners associated token account of the output mint.
+pub fn deliver(
+    program_id: &Pubkey,
+    pool: &Pubkey,
+    output_pool: &Pubkey,
+    output_pool_token_account: &Pubkey,
+    deliveries: &[(Pubkey, Pubkey, Pubkey)],
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*pool, false),
+        AccountMeta::new_readonly(*output_pool, false),
+        AccountMeta::new(*output_pool_token_account, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+    ];
+    accounts.extend(deliveries.iter().flat_map(|(user_account, stream, owner_token_account)| vec![
+        AccountMeta::new(*user_account, false),
+        AccountMeta::new(*stream, false),
+        AccountMeta::new(*owner_token_account, false),
+    ]));
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: StepInstruction::Deliver { }.pack(),
+    }
+}
+
+/// Creates a `SetMinRewardInput` instruction.
+pub fn set_min_reward_input(
+    program_id: &Pubkey,
+    deployer: &Pubkey,
+    program_state: &Pubkey,
+    pool: &Pubkey,
+    min_reward_input: u64,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*deployer, true),
+            AccountMeta::new_readonly(*program_state, false),
+            AccountMeta::new(*pool, false),
+        ],
+        data: StepInstruction::SetMinRewardInput(SetMinRewardInput { min_reward_input }).pack(),
+    }
+}
+
+/// Creates an `AbortRound` instruction.
+pub fn abort_round(
+    program_id: &Pubkey,
+    deployer: &Pubkey,
+    program_state: &Pubkey,
+    pool: &Pubkey,
+    other_pool: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*deployer, true),
+            AccountMeta::new_readonly(*program_state, false),
+            AccountMeta::new(*pool, false),
+            AccountMeta::new(*other_pool, false),
+        ],
+        data: StepInstruction::AbortRound { }.pack(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::schedule::CatchUpPolicy;
+    use crate::state::{EndConditions, Strategy};
+
+    fn stream_params() -> StreamParams {
+        StreamParams {
+            schedule: Schedule::Weekly { weekday: 2, hour: 14 },
+            catch_up: CatchUpPolicy::Combined { max_windows: 4 },
+            strategy: Strategy::ValueAveraging { target_growth: 100, min_amount: 10, max_amount: 1_000 },
+            slippage_bps: 50,
+            end_conditions: EndConditions { end_ts: Some(1_704_067_200), max_executions: None, max_total_input: Some(u64::MAX) },
+            limit_price: Some(Price { quote_amount: 3, base_amount: 2 }),
+            carry_over: true
+        }
+    }
+
+    fn check_round_trip(instruction: StepInstruction) {
+        assert_eq!(StepInstruction::unpack(&instruction.pack()).unwrap(), instruction);
+    }
+
+    #[test]
+    fn test_pack_unpack_round_trip() {
+        check_round_trip(StepInstruction::InitProgram { });
+        check_round_trip(StepInstruction::InitPool(InitPool { name: "sol".to_string() }));
+        check_round_trip(StepInstruction::Deposit(Deposit { amount: u64::MAX }));
+        check_round_trip(StepInstruction::CreateStream(CreateStream {
+            input_token_pubkey: [1; 32],
+            output_token_pubkey: [2; 32],
+            direction: StreamDirection::Sell,
+            params: stream_params()
+        }));
+        check_round_trip(StepInstruction::Execute { });
+        check_round_trip(StepInstruction::InitRoute { });
+        check_round_trip(StepInstruction::SetKeeperReward(SetKeeperReward { keeper_reward_lamports: 5_000 }));
+        check_round_trip(StepInstruction::Gather { });
+        check_round_trip(StepInstruction::CancelStream { });
+        check_round_trip(StepInstruction::PauseStream { });
+        check_round_trip(StepInstruction::ResumeStream { });
+        check_round_trip(StepInstruction::UpdateStream(UpdateStream {
+            params: StreamParams {
+                schedule: Schedule::Interval { interval_seconds: 3_600 },
+                catch_up: CatchUpPolicy::OneWindowPerCrank,
+                strategy: Strategy::FixedAmount { amount: 100 },
+                end_conditions: EndConditions::default(),
+                limit_price: None,
+                carry_over: false,
+                ..stream_params()
+            }
+        }));
+        check_round_trip(StepInstruction::Claim { });
+        check_round_trip(StepInstruction::CreateBasketStream(CreateBasketStream {
+            input_token_pubkey: [1; 32],
+            direction: StreamDirection::Buy,
+            outputs: vec![([2; 32], 2_500), ([3; 32], 7_500)],
+            params: StreamParams { schedule: Schedule::Monthly { day: 31, hour: 23 }, ..stream_params() }
+        }));
+        check_round_trip(StepInstruction::CreateRebalanceStream(CreateRebalanceStream {
+            schedule: Schedule::Daily { hour: 0 },
+            drift_bps: 500,
+            max_trade_value: 1_000,
+            slippage_bps: 100,
+            targets: vec![([1; 32], 6_000), ([2; 32], 4_000)]
+        }));
+        check_round_trip(StepInstruction::Rebalance { });
+        check_round_trip(StepInstruction::CancelRebalanceStream { });
+        check_round_trip(StepInstruction::CreateTwapOrder(CreateTwapOrder {
+            order_id: 9,
+            output_token_pubkey: [2; 32],
+            direction: StreamDirection::Buy,
+            total_amount: 1_000,
+            start_ts: -1,
+            end_ts: i64::MAX,
+            slice_count: 10,
+            limit_price: Some(Price { quote_amount: 1, base_amount: u64::MAX }),
+            slippage_bps: 100
+        }));
+        check_round_trip(StepInstruction::ExecuteTwapSlice { });
+        check_round_trip(StepInstruction::CloseTwapOrder { });
+        check_round_trip(StepInstruction::SetAutoDelivery(SetAutoDelivery { enabled: true }));
+        check_round_trip(StepInstruction::DepositSol(DepositSol { amount: 1 }));
+        check_round_trip(StepInstruction::ClaimSol { });
+        check_round_trip(StepInstruction::Withdraw(Withdraw { amount: 10, release: None, unwrap: false }));
+        check_round_trip(StepInstruction::Withdraw(Withdraw { amount: 10, release: Some(StreamRelease::Shrink), unwrap: false }));
+        check_round_trip(StepInstruction::Withdraw(Withdraw { amount: 10, release: Some(StreamRelease::Cancel), unwrap: true }));
+        check_round_trip(StepInstruction::Deliver { });
+        check_round_trip(StepInstruction::SetMinRewardInput(SetMinRewardInput { min_reward_input: 1_000 }));
+        check_round_trip(StepInstruction::AbortRound { });
+    }
+
+    #[test]
+    fn test_unpack_rejects_bad_length() {
+        let invalid_instruction = Err(InvalidInstruction.into());
+        assert_eq!(StepInstruction::unpack(&[]), invalid_instruction);
+        assert_eq!(StepInstruction::unpack(&[27]), invalid_instruction);
+
+        let data = StepInstruction::Deposit(Deposit { amount: 42 }).pack();
+        assert_eq!(data, [&[2][..], &42u64.to_le_bytes()].concat());
+        assert_eq!(StepInstruction::unpack(&data[..data.len() - 1]), invalid_instruction);
+        assert_eq!(StepInstruction::unpack(&[&data[..], &[0]].concat()), invalid_instruction);
+
+        assert_eq!(StepInstruction::unpack(&[4]), Ok(StepInstruction::Execute { }));
+        assert_eq!(StepInstruction::unpack(&[4, 0]), invalid_instruction);
+
+        let data = StepInstruction::CreateStream(CreateStream {
+            input_token_pubkey: [1; 32],
+            output_token_pubkey: [2; 32],
+            direction: StreamDirection::Buy,
+            params: stream_params()
+        }).pack();
+        assert_eq!(StepInstruction::unpack(&data[..data.len() - 1]), invalid_instruction);
+        assert_eq!(StepInstruction::unpack(&[&data[..], &[0]].concat()), invalid_instruction);
+    }
+
+    #[test]
+    fn test_builder_account_metas() {
+        let program_id = Pubkey::new_unique();
+        let keys: Vec<Pubkey> = (0..8).map(|_| Pubkey::new_unique()).collect();
+
+        let instruction = deposit(&program_id, &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], 42);
+        assert_eq!(instruction.program_id, program_id);
+        assert_eq!(StepInstruction::unpack(&instruction.data).unwrap(), StepInstruction::Deposit(Deposit { amount: 42 }));
+        assert_eq!(instruction.accounts.len(), 8);
+        assert!(instruction.accounts[0].is_signer && instruction.accounts[0].is_writable);
+        assert!(instruction.accounts[1..5].iter().all(|meta| meta.is_writable && !meta.is_signer));
+        assert_eq!(instruction.accounts[5].pubkey, spl_token::id());
+
+        let swap_accounts = SwapAccountKeys {
+            program_id: keys[0],
+            swap: keys[1],
+            authority: keys[2],
+            input_token: keys[3],
+            output_token: keys[4],
+            pool_mint: keys[5],
+            fee_account: keys[6],
+        };
+        let instruction = execute(&program_id, &keys[7], &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &swap_accounts, &keys[6]);
+        assert_eq!(instruction.accounts.len(), 18);
+        assert!(instruction.accounts.iter().all(|meta| !meta.is_signer));
+        assert_eq!(instruction.accounts[7].pubkey, keys[0]);
+        assert_eq!(instruction.accounts[11].pubkey, keys[3]);
+        assert!(instruction.accounts[11].is_writable);
+        assert_eq!(instruction.accounts[15].pubkey, keys[6]);
+
+        let instruction = withdraw(&program_id, &keys[0], &keys[1], &keys[2], &keys[3], &keys[0], Some(&keys[4]), &keys[5..7], 1, None);
+        assert_eq!(instruction.accounts.len(), 13);
+        assert_eq!(instruction.accounts[7].pubkey, keys[4]);
+        assert_eq!(instruction.accounts[8].pubkey, spl_token::native_mint::id());
+        assert_eq!(instruction.accounts[12].pubkey, keys[6]);
+    }
+}
@@ -1,6 +1,8 @@
 pub mod error;
 pub mod instruction;
+pub mod pda;
 pub mod processor;
+pub mod schedule;
 pub mod state;
 
 #[cfg(not(feature = "no-entrypoint"))]
@@ -0,0 +1,166 @@
+//! Seeds and address derivation of the program derived accounts
+
+use solana_program::pubkey::Pubkey;
+
+use crate::state::PubkeyData;
+
+/// Seed of the program state account
+pub const PROGRAM_STATE_SEED: &[u8] = b"step_program_state-123456789abcb";
+
+/// Seed of a pool state account, derived from the pools mint and name
+pub const POOL_SEED: &[u8] = b"pool";
+
+/// Seed of a pool's token account, derived from the pool state account
+pub const VAULT_SEED: &[u8] = b"vault";
+
+/// Seed of a user account, derived from the pool state account and the users slot
+pub const USER_SEED: &[u8] = b"user";
+
+/// Seed of a stream account, derived from the user account and the streams slot
+pub const STREAM_SEED: &[u8] = b"stream";
+
+/// Seed of the rebalancing stream account, derived from the user account in the pool of its first mint
+pub const REBALANCE_SEED: &[u8] = b"rebalance";
+
+/// Seed of a TWAP order account, derived from the user account and the order id
+pub const TWAP_SEED: &[u8] = b"twap";
+
+/// Seed of the token account wrapped SOL is moved into to be unwrapped, derived from the pool state account
+pub const UNWRAP_SEED: &[u8] = b"unwrap";
+
+/// Seed of the route account registered for a pair of pools
+pub const ROUTE_SEED: &[u8] = b"route";
+
+/// Seed of the system account holding the lamports paid out as keeper rewards
+pub const REWARD_VAULT_SEED: &[u8] = b"keeper_reward";
+
+/// Program deriving the associated token accounts output is auto-delivered to
+pub mod associated_token {
+    solana_program::declare_id!("ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL");
+}
+
+/// Address of the program state account
+pub fn find_program_state_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[PROGRAM_STATE_SEED], program_id)
+}
+
+/// Address of the pool state account of `mint` named `name`
+pub fn find_pool_address(mint: &Pubkey, name: &str, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[POOL_SEED, mint.as_ref(), name.as_bytes()], program_id)
+}
+
+/// Address of the token account holding the deposits of a pool
+pub fn find_vault_address(pool_key: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[pool_key.as_ref(), VAULT_SEED], program_id)
+}
+
+/// Address of the user account in a slot of a pool
+pub fn find_user_address(pool_key: &Pubkey, user_index: u32, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[pool_key.as_ref(), USER_SEED, &user_index.to_le_bytes()], program_id)
+}
+
+/// Address of the stream account in a slot of a user account
+pub fn find_stream_address(user_key: &Pubkey, stream_index: u32, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[user_key.as_ref(), STREAM_SEED, &stream_index.to_le_bytes()], program_id)
+}
+
+/// Address of the rebalancing stream of a user account
+pub fn find_rebalance_stream_address(user_key: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[user_key.as_ref(), REBALANCE_SEED], program_id)
+}
+
+/// Address of a TWAP order of a user account
+pub fn find_twap_order_address(user_key: &Pubkey, order_id: u64, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[user_key.as_ref(), TWAP_SEED, &order_id.to_le_bytes()], program_id)
+}
+
+/// Address of the token account wrapped SOL of a pool is unwrapped through
+pub fn find_unwrap_address(pool_key: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[pool_key.as_ref(), UNWRAP_SEED], program_id)
+}
+
+/// Orders two pools so a pair derives the same route whichever side is the input
+pub fn sort_pools<'a>(pool_a_key: &'a Pubkey, pool_b_key: &'a Pubkey) -> (&'a Pubkey, &'a Pubkey) {
+    if pool_a_key.to_bytes() <= pool_b_key.to_bytes() {
+        (pool_a_key, pool_b_key)
+    }
+    else {
+        (pool_b_key, pool_a_key)
+    }
+}
+
+/// Address of the route account of a pair of pools
+pub fn find_route_address(pool_a_key: &Pubkey, pool_b_key: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    let (first_pool, second_pool) = sort_pools(pool_a_key, pool_b_key);
+    Pubkey::find_program_address(&[ROUTE_SEED, first_pool.as_ref(), second_pool.as_ref()], program_id)
+}
+
+/// Address of the system account keeper rewards are paid out of
+pub fn find_reward_vault_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[REWARD_VAULT_SEED], program_id)
+}
+
+/// Address of the associated token account of `owner` for `mint`
+pub fn find_associated_token_address(owner: &Pubkey, mint: &PubkeyData) -> Pubkey {
+    Pubkey::find_program_address(
+        &[owner.as_ref(), spl_token::id().as_ref(), mint],
+        &associated_token::id()
+    ).0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_addresses_match_seeds() {
+        let program_id = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+
+        let (program_state, nonce) = find_program_state_address(&program_id);
+        assert_eq!(
+            Pubkey::create_program_address(&[b"step_program_state-123456789abcb", &[nonce]], &program_id),
+            Ok(program_state)
+        );
+
+        let (pool, nonce) = find_pool_address(&mint, "sol", &program_id);
+        assert_eq!(Pubkey::create_program_address(&[b"pool", mint.as_ref(), b"sol", &[nonce]], &program_id), Ok(pool));
+        assert_ne!(find_pool_address(&mint, "sol-2", &program_id).0, pool);
+        assert_ne!(find_pool_address(&Pubkey::new_unique(), "sol", &program_id).0, pool);
+
+        let (vault, nonce) = find_vault_address(&pool, &program_id);
+        assert_eq!(Pubkey::create_program_address(&[pool.as_ref(), b"vault", &[nonce]], &program_id), Ok(vault));
+
+        let (user, nonce) = find_user_address(&pool, 3, &program_id);
+        assert_eq!(
+            Pubkey::create_program_address(&[pool.as_ref(), b"user", &3u32.to_le_bytes(), &[nonce]], &program_id),
+            Ok(user)
+        );
+        assert_ne!(find_user_address(&pool, 4, &program_id).0, user);
+
+        let (stream, nonce) = find_stream_address(&user, 7, &program_id);
+        assert_eq!(
+            Pubkey::create_program_address(&[user.as_ref(), b"stream", &7u32.to_le_bytes(), &[nonce]], &program_id),
+            Ok(stream)
+        );
+
+        let (order, nonce) = find_twap_order_address(&user, 9, &program_id);
+        assert_eq!(
+            Pubkey::create_program_address(&[user.as_ref(), b"twap", &9u64.to_le_bytes(), &[nonce]], &program_id),
+            Ok(order)
+        );
+
+        let (reward_vault, nonce) = find_reward_vault_address(&program_id);
+        assert_eq!(Pubkey::create_program_address(&[b"keeper_reward", &[nonce]], &program_id), Ok(reward_vault));
+    }
+
+    #[test]
+    fn test_route_address_is_symmetric() {
+        let program_id = Pubkey::new_unique();
+        let pool_a = Pubkey::new_unique();
+        let pool_b = Pubkey::new_unique();
+
+        assert_eq!(find_route_address(&pool_a, &pool_b, &program_id), find_route_address(&pool_b, &pool_a, &program_id));
+        assert_ne!(find_route_address(&pool_a, &pool_b, &program_id).0, find_route_address(&pool_a, &pool_a, &program_id).0);
+    }
+}
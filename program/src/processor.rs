@@ -1,29 +1,97 @@
+use num_traits::FromPrimitive;
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    decode_error::DecodeError,
     entrypoint::ProgramResult,
     msg,
     program::{invoke, invoke_signed},
-    program_error::ProgramError,
+    program_error::{PrintProgramError, ProgramError},
     program_pack::{IsInitialized, Pack},
     pubkey::Pubkey,
-    sysvar::{rent::Rent, Sysvar},
+    sysvar::{clock::Clock, rent::Rent, Sysvar},
     system_instruction,
-    native_token::sol_to_lamports,
     clock::UnixTimestamp,
 };
-use std::mem::size_of;
+use spl_token_swap::state::SwapVersion;
+use std::{convert::TryFrom, iter::Peekable};
 
 use crate::{
     error::StepError,
     instruction::StepInstruction,
+    instruction::StreamRelease,
+    instruction::{
+        CreateBasketStream,
+        CreateRebalanceStream,
+        CreateStream,
+        CreateTwapOrder,
+        Deposit,
+        DepositSol,
+        InitPool,
+        SetAutoDelivery,
+        SetKeeperReward,
+        SetMinRewardInput,
+        UpdateStream,
+        Withdraw,
+    },
+    pda::{
+        self,
+        POOL_SEED,
+        PROGRAM_STATE_SEED,
+        REBALANCE_SEED,
+        REWARD_VAULT_SEED,
+        ROUTE_SEED,
+        STREAM_SEED,
+        TWAP_SEED,
+        UNWRAP_SEED,
+        USER_SEED,
+        VAULT_SEED,
+    },
+    schedule::Schedule,
+    schedule::MAX_CATCH_UP_WINDOWS,
+    schedule::MIN_INTERVAL_SECONDS,
+    schedule::MAX_INTERVAL_SECONDS,
     state::StepProgramState,
     state::Pool,
     state::Stream,
-    state::MAX_SEED_SIZE_BYTES,
+    state::BasketOutput,
+    state::RebalanceStream,
+    state::RebalanceTarget,
+    state::TwapOrder,
+    state::Price,
+    state::StreamStatus,
+    state::StreamDirection,
+    state::StreamParams,
+    state::Strategy,
+    state::MAX_POOL_NAME_LEN,
     state::UserAccount,
-    state::PubkeyData
+    state::Route,
+    state::RoundState,
+    state::PubkeyData,
+    state::NULL_PUBKEY,
+    state::MAX_BASKET_OUTPUTS,
+    state::MAX_REBALANCE_TARGETS,
+    state::BPS_DENOMINATOR,
+    state::MAX_SLIPPAGE_BPS,
+    state::MIN_SLIPPAGE_BPS
 };
 
+/// Accounts paying out the output of a round to the streams with auto-delivery
+struct DeliveryAccounts<'a, 'b> {
+    output_pool_account: &'a AccountInfo<'b>,
+    output_token_account: &'a AccountInfo<'b>,
+    token_program: &'a AccountInfo<'b>,
+}
+
+/// Accounts of the token swap used to trade the imbalance of an execution
+struct SwapAccounts<'a, 'b> {
+    token_swap_program: &'a AccountInfo<'b>,
+    token_program: &'a AccountInfo<'b>,
+    swap_info: &'a AccountInfo<'b>,
+    authority_info: &'a AccountInfo<'b>,
+    pool_mint_info: &'a AccountInfo<'b>,
+    pool_fee_account_info: &'a AccountInfo<'b>,
+}
+
 pub struct Processor;
 impl Processor {
     pub fn process(
@@ -38,26 +106,154 @@ impl Processor {
                 msg!("Instruction: InitProgram");
                 Self::process_init_program(accounts, program_id)
             }
-            StepInstruction::InitPool { pda_seed } => {
+            StepInstruction::InitPool(InitPool { name }) => {
                 msg!("Instruction: InitPool");
-                Self::process_init_pool(accounts, program_id, pda_seed)
+                Self::process_init_pool(accounts, program_id, &name)
             }
-            StepInstruction::Deposit { amount } => {
+            StepInstruction::Deposit(Deposit { amount }) => {
                 msg!("Instruction: Deposit");
                 Self::process_deposit(accounts, program_id, amount)
             }
-            StepInstruction::CreateStream { input_token_pubkey, output_token_pubkey, interval_days, amount } => {
+            StepInstruction::CreateStream(CreateStream { input_token_pubkey, output_token_pubkey, direction, params }) => {
                 msg!("Instruction: Create Stream");
                 Self::process_create_stream(accounts,
                                             program_id,
                                             input_token_pubkey,
                                             output_token_pubkey,
-                                            interval_days,
-                                            amount)
+                                            Vec::new(),
+                                            direction,
+                                            params)
+            }
+            StepInstruction::CreateBasketStream(CreateBasketStream { input_token_pubkey, direction, outputs, params }) => {
+                msg!("Instruction: Create Basket Stream");
+                let basket = outputs
+                    .into_iter()
+                    .map(|(mint_pubkey, weight_bps)| BasketOutput::new(mint_pubkey, weight_bps))
+                    .collect();
+                Self::process_create_stream(accounts,
+                                            program_id,
+                                            input_token_pubkey,
+                                            NULL_PUBKEY,
+                                            basket,
+                                            direction,
+                                            params)
             }
-            StepInstruction::Execute { pda_seed } => {
+            StepInstruction::Execute { } => {
                 msg!("Instruction: Execute Trade");
-                Self::process_trade(accounts, program_id, pda_seed)
+                Self::process_trade(accounts, program_id)
+            }
+            StepInstruction::InitRoute { } => {
+                msg!("Instruction: InitRoute");
+                Self::process_init_route(accounts, program_id)
+            }
+            StepInstruction::SetKeeperReward(SetKeeperReward { keeper_reward_lamports }) => {
+                msg!("Instruction: SetKeeperReward");
+                Self::process_set_keeper_reward(accounts, program_id, keeper_reward_lamports)
+            }
+            StepInstruction::Gather { } => {
+                msg!("Instruction: Gather");
+                Self::process_gather(accounts, program_id)
+            }
+            StepInstruction::CancelStream { } => {
+                msg!("Instruction: Cancel Stream");
+                Self::process_cancel_stream(accounts, program_id)
+            }
+            StepInstruction::PauseStream { } => {
+                msg!("Instruction: Pause Stream");
+                Self::process_pause_stream(accounts, program_id)
+            }
+            StepInstruction::ResumeStream { } => {
+                msg!("Instruction: Resume Stream");
+                Self::process_resume_stream(accounts, program_id)
+            }
+            StepInstruction::UpdateStream(UpdateStream { params }) => {
+                msg!("Instruction: Update Stream");
+                Self::process_update_stream(accounts, program_id, params)
+            }
+            StepInstruction::Claim { } => {
+                msg!("Instruction: Claim");
+                Self::process_claim(accounts, program_id)
+            }
+            StepInstruction::CreateRebalanceStream(CreateRebalanceStream { schedule, drift_bps, max_trade_value, slippage_bps, targets }) => {
+                msg!("Instruction: Create Rebalance Stream");
+                let targets = targets
+                    .into_iter()
+                    .map(|(mint_pubkey, weight_bps)| RebalanceTarget { mint_pubkey, weight_bps })
+                    .collect();
+                Self::process_create_rebalance_stream(accounts,
+                                                      program_id,
+                                                      schedule,
+                                                      drift_bps,
+                                                      max_trade_value,
+                                                      slippage_bps,
+                                                      targets)
+            }
+            StepInstruction::Rebalance { } => {
+                msg!("Instruction: Rebalance");
+                Self::process_rebalance(accounts, program_id)
+            }
+            StepInstruction::CancelRebalanceStream { } => {
+                msg!("Instruction: Cancel Rebalance Stream");
+                Self::process_cancel_rebalance_stream(accounts, program_id)
+            }
+            StepInstruction::CreateTwapOrder(CreateTwapOrder {
+                order_id,
+                output_token_pubkey,
+                direction,
+                total_amount,
+                start_ts,
+                end_ts,
+                slice_count,
+                limit_price,
+                slippage_bps
+            }) => {
+                msg!("Instruction: Create TWAP Order");
+                Self::process_create_twap_order(accounts,
+                                                program_id,
+                                                order_id,
+                                                output_token_pubkey,
+                                                direction,
+                                                total_amount,
+                                                (start_ts, end_ts),
+                                                slice_count,
+                                                limit_price,
+                                                slippage_bps)
+            }
+            StepInstruction::ExecuteTwapSlice { } => {
+                msg!("Instruction: Execute TWAP Slice");
+                Self::process_execute_twap_slice(accounts, program_id)
+            }
+            StepInstruction::CloseTwapOrder { } => {
+                msg!("Instruction: Close TWAP Order");
+                Self::process_close_twap_order(accounts, program_id)
+            }
+            StepInstruction::SetAutoDelivery(SetAutoDelivery { enabled }) => {
+                msg!("Instruction: Set Auto Delivery");
+                Self::process_set_auto_delivery(accounts, program_id, enabled)
+            }
+            StepInstruction::DepositSol(DepositSol { amount }) => {
+                msg!("Instruction: Deposit SOL");
+                Self::process_deposit_sol(accounts, program_id, amount)
+            }
+            StepInstruction::ClaimSol { } => {
+                msg!("Instruction: Claim SOL");
+                Self::process_claim_sol(accounts, program_id)
+            }
+            StepInstruction::Withdraw(Withdraw { amount, release, unwrap }) => {
+                msg!("Instruction: Withdraw");
+                Self::process_withdraw(accounts, program_id, amount, release, unwrap)
+            }
+            StepInstruction::Deliver { } => {
+                msg!("Instruction: Deliver");
+                Self::process_deliver(accounts, program_id)
+            }
+            StepInstruction::SetMinRewardInput(SetMinRewardInput { min_reward_input }) => {
+                msg!("Instruction: SetMinRewardInput");
+                Self::process_set_min_reward_input(accounts, program_id, min_reward_input)
+            }
+            StepInstruction::AbortRound { } => {
+                msg!("Instruction: Abort Round");
+                Self::process_abort_round(accounts, program_id)
             }
         }
     }
@@ -76,12 +272,11 @@ impl Processor {
         let rent_sysvar_info = next_account_info(account_info_iter)?;
         let rent = &Rent::from_account_info(rent_sysvar_info)?;
 
-        let seed = b"step_program_state-123456789abcb";
-        let (pda, seed_nonce) = Pubkey::find_program_address(&[seed], program_id);
+        let (pda, seed_nonce) = pda::find_program_state_address(program_id);
 
         if pda != *program_state_account.key {
             msg!("Error: program state address derivation mismatch");
-            return Err(ProgramError::InvalidArgument);
+            return Err(StepError::AddressDerivationMismatch.into());
         }
 
         // figure this error handling out later
@@ -93,7 +288,7 @@ impl Processor {
         program_state.deployer_pubkey = *deployer.key;*/
 
         let program_state_signer_seeds: &[&[_]] = &[
-            seed, &[seed_nonce]
+            PROGRAM_STATE_SEED, &[seed_nonce]
         ];
 
         msg!("Creating program state account");
@@ -115,7 +310,8 @@ impl Processor {
 
         StepProgramState {
             is_initialized: true,
-            deployer_pubkey: deployer.key.to_bytes()
+            deployer_pubkey: deployer.key.to_bytes(),
+            keeper_reward_lamports: 0
         }.pack_into_slice(&mut program_state_account.data.borrow_mut());
 
         Ok(())
@@ -127,7 +323,7 @@ impl Processor {
     fn process_init_pool(
         accounts: &[AccountInfo],
         program_id: &Pubkey,
-        pool_pda_seed: [u8; MAX_SEED_SIZE_BYTES]
+        name: &str
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
 
@@ -135,6 +331,7 @@ impl Processor {
         let program_state_account = next_account_info(account_info_iter)?;
         let pool_account = next_account_info(account_info_iter)?;
         let program_token_account = next_account_info(account_info_iter)?;
+        let mint_info = next_account_info(account_info_iter)?;
 
         let program_state = StepProgramState::unpack_unchecked(&program_state_account.data.borrow())?;
         let deployer_key: Pubkey = Pubkey::new(&program_state.deployer_pubkey);
@@ -143,22 +340,21 @@ impl Processor {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
-        let token_account_seed = b"seedy"; // TODO: not hardcode this. each pool will have its own unique ID
-        let (pda_token_account, seed_nonce) = Pubkey::find_program_address(&[token_account_seed], program_id);
-
-        if pda_token_account != *program_token_account.key {
-            msg!("Error: program token address derivation mismatch");
-            return Err(ProgramError::InvalidArgument);
-        }
+        Self::validate_pool_name(name)?;
 
-        let (pda_pool_account, pool_seed_nonce) = Pubkey::find_program_address(&[&pool_pda_seed], program_id);
+        let (pda_pool_account, pool_seed_nonce) = pda::find_pool_address(mint_info.key, name, program_id);
 
         if pda_pool_account != *pool_account.key {
             msg!("Error: pool address derivation mismatch");
-            return Err(ProgramError::InvalidArgument);
+            return Err(StepError::AddressDerivationMismatch.into());
         }
 
-        let mint_info = next_account_info(account_info_iter)?;
+        let (pda_token_account, seed_nonce) = pda::find_vault_address(pool_account.key, program_id);
+
+        if pda_token_account != *program_token_account.key {
+            msg!("Error: program token address derivation mismatch");
+            return Err(StepError::AddressDerivationMismatch.into());
+        }
 
         if *mint_info.owner != spl_token::id() {
             return Err(ProgramError::IncorrectProgramId);
@@ -170,7 +366,7 @@ impl Processor {
         let rent = &Rent::from_account_info(rent_sysvar_info)?;
 
         let program_token_signer_seeds: &[&[_]] = &[
-            token_account_seed, &[seed_nonce]
+            pool_account.key.as_ref(), VAULT_SEED, &[seed_nonce]
         ];
 
         msg!("Creating token account");
@@ -207,7 +403,7 @@ impl Processor {
         )?;
 
         let pool_signer_seeds: &[&[_]] = &[
-            &pool_pda_seed, &[pool_seed_nonce]
+            POOL_SEED, mint_info.key.as_ref(), name.as_bytes(), &[pool_seed_nonce]
         ];
 
         msg!("Creating pool info account");
@@ -227,12 +423,7 @@ impl Processor {
             &[&pool_signer_seeds]
         )?;
 
-        Pool {
-            is_initialized: true,
-            mint_pubkey: mint_info.key.to_bytes(),
-            pda_seed: pool_pda_seed,
-            head_user: [0; size_of::<PubkeyData>()]
-        }.pack_into_slice(&mut pool_account.data.borrow_mut());
+        Pool::new(mint_info.key.to_bytes(), name).pack_into_slice(&mut pool_account.data.borrow_mut());
 
         Ok(())
     }
@@ -242,7 +433,7 @@ impl Processor {
     //================================
     fn process_deposit(
         accounts: &[AccountInfo],
-        _program_id: &Pubkey,
+        program_id: &Pubkey,
         amount: u64
     ) -> ProgramResult {
 
@@ -258,6 +449,21 @@ impl Processor {
         let pool_info_account = next_account_info(account_info_iter)?;
         let program_token_account = next_account_info(account_info_iter)?;
         let token_program = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let rent_sysvar_info = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(rent_sysvar_info)?;
+
+        if *pool_info_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut pool_state = Pool::unpack(&pool_info_account.data.borrow())?;
+        let (pda_token_account, _) = pda::find_vault_address(pool_info_account.key, program_id);
+
+        if pda_token_account != *program_token_account.key {
+            msg!("Error: program token address derivation mismatch");
+            return Err(StepError::AddressDerivationMismatch.into());
+        }
 
         msg!("Transferring {} from {} to {}", amount, depositor_token_account.key, program_token_account.key);
         let transfer_to_initializer_ix = spl_token::instruction::transfer(
@@ -280,31 +486,265 @@ impl Processor {
             ],
         )?;
 
-        let mut pool_state = Pool::unpack_unchecked(&pool_info_account.data.borrow())?;
+        let mut user_account = Self::load_or_create_user_account(
+            program_id,
+            depositor,
+            user_step_pool_account,
+            pool_info_account,
+            &mut pool_state,
+            system_program_info,
+            rent
+        )?;
 
-        if pool_state.head_user == [0; size_of::<PubkeyData>()] {
-            // First user depositing so setup the head of list
-            pool_state.head_user = user_step_pool_account.key.to_bytes();
-            Pool::pack(pool_state, &mut pool_info_account.data.borrow_mut())?;
+        user_account.balance = user_account.balance.checked_add(amount).ok_or(StepError::AmountOverflow)?;
+        UserAccount::pack(user_account, &mut user_step_pool_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    //================================
+    // Deposit SOL
+    //================================
+    fn process_deposit_sol(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        amount: u64
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let depositor = next_account_info(account_info_iter)?;
+        let user_step_pool_account = next_account_info(account_info_iter)?;
+        let pool_info_account = next_account_info(account_info_iter)?;
+        let program_token_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let rent_sysvar_info = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(rent_sysvar_info)?;
+
+        if !depositor.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if *pool_info_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut pool_state = Pool::unpack(&pool_info_account.data.borrow())?;
+
+        if pool_state.mint_pubkey != spl_token::native_mint::id().to_bytes() {
+            msg!("Error: pool does not hold wrapped SOL");
+            return Err(StepError::NotNativeMint.into());
+        }
+
+        let (pda_token_account, _) = pda::find_vault_address(pool_info_account.key, program_id);
+
+        if pda_token_account != *program_token_account.key {
+            msg!("Error: program token address derivation mismatch");
+            return Err(StepError::AddressDerivationMismatch.into());
         }
 
-        let mut user_account = UserAccount::unpack_unchecked(&user_step_pool_account.data.borrow())?;
-        user_account.balance += amount;
+        msg!("Wrapping {} lamports into {}", amount, program_token_account.key);
+        invoke(
+            &system_instruction::transfer(depositor.key, program_token_account.key, amount),
+            &[
+                depositor.clone(),
+                program_token_account.clone(),
+                system_program_info.clone()
+            ]
+        )?;
+
+        invoke(
+            &spl_token::instruction::sync_native(token_program.key, program_token_account.key)?,
+            &[
+                program_token_account.clone(),
+                token_program.clone()
+            ]
+        )?;
+
+        let mut user_account = Self::load_or_create_user_account(
+            program_id,
+            depositor,
+            user_step_pool_account,
+            pool_info_account,
+            &mut pool_state,
+            system_program_info,
+            rent
+        )?;
+
+        user_account.balance = user_account.balance.checked_add(amount).ok_or(StepError::AmountOverflow)?;
         UserAccount::pack(user_account, &mut user_step_pool_account.data.borrow_mut())?;
 
         Ok(())
     }
 
+    //================================
+    // Withdraw
+    //================================
+    fn process_withdraw(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        amount: u64,
+        release: Option<StreamRelease>,
+        unwrap: bool
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner = next_account_info(account_info_iter)?;
+        let user_step_pool_account = next_account_info(account_info_iter)?;
+        let pool_info_account = next_account_info(account_info_iter)?;
+        let program_token_account = next_account_info(account_info_iter)?;
+        let destination = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let clock_sysvar_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_sysvar_info)?;
+
+        if !owner.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if *user_step_pool_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut user_account = UserAccount::unpack(&user_step_pool_account.data.borrow())?;
+
+        if user_account.owner_pubkey != owner.key.to_bytes() {
+            msg!("Error: user account is not owned by the signer");
+            return Err(StepError::InvalidOwner.into());
+        }
+
+        let mut pool_state = Self::check_user_pool(program_id, &user_account, pool_info_account)?;
+
+        let unwrap_accounts = if unwrap {
+            if pool_state.mint_pubkey != spl_token::native_mint::id().to_bytes() {
+                msg!("Error: only wrapped SOL can be withdrawn as lamports");
+                return Err(StepError::NotNativeMint.into());
+            }
+
+            Some((
+                next_account_info(account_info_iter)?,
+                next_account_info(account_info_iter)?,
+                next_account_info(account_info_iter)?,
+                next_account_info(account_info_iter)?
+            ))
+        }
+        else {
+            None
+        };
+
+        if amount > user_account.balance {
+            msg!("Error: withdrawal exceeds the balance of {}", user_account.balance);
+            return Err(StepError::InsufficientBalance.into());
+        }
+
+        for stream_info in account_info_iter {
+            let release = match release {
+                Some(release) if user_account.free_balance() < amount => release,
+                _ => break
+            };
+
+            if *stream_info.owner != *program_id {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+
+            let mut stream = Stream::unpack(&stream_info.data.borrow())?;
+
+            if stream.user_pubkey != user_step_pool_account.key.to_bytes() {
+                msg!("Error: stream does not belong to the user account");
+                return Err(StepError::StreamMismatch.into());
+            }
+
+            let shortfall = amount.checked_sub(user_account.free_balance()).ok_or(StepError::AmountOverflow)?;
+            match (release, stream.strategy.clone()) {
+                (StreamRelease::Cancel, _) => {
+                    msg!("Cancelling stream {}", stream_info.key);
+                    user_account.recommit(&mut stream, 0)?;
+                    stream.complete(clock.unix_timestamp);
+                }
+                (StreamRelease::Shrink, Strategy::FixedAmount { amount: window_amount }) => {
+                    let freed = shortfall.min(stream.committed_amount);
+
+                    if freed >= window_amount {
+                        msg!("Error: stream {} cannot be shrunk to nothing, cancel it instead", stream_info.key);
+                        return Err(StepError::InsufficientBalance.into());
+                    }
+
+                    let shrunk_amount = window_amount.checked_sub(freed).ok_or(StepError::AmountOverflow)?;
+                    msg!("Shrinking stream {} to {} per window", stream_info.key, shrunk_amount);
+                    stream.strategy = Strategy::FixedAmount { amount: shrunk_amount };
+                    let commitment = stream.committed_amount.checked_sub(freed).ok_or(StepError::AmountOverflow)?;
+                    user_account.recommit(&mut stream, commitment)?;
+                }
+                (StreamRelease::Shrink, _) => {
+                    msg!("Error: only fixed amount streams can be shrunk");
+                    return Err(StepError::InvalidStrategy.into());
+                }
+            }
+
+            Stream::pack(stream, &mut stream_info.data.borrow_mut())?;
+        }
+
+        if user_account.free_balance() < amount {
+            msg!("Error: withdrawal exceeds the free balance of {}", user_account.free_balance());
+            return Err(StepError::InsufficientBalance.into());
+        }
+
+        match unwrap_accounts {
+            Some((unwrap_account, native_mint_info, system_program_info, rent_sysvar_info)) => {
+                Self::unwrap_from_vault(
+                    program_id,
+                    pool_info_account,
+                    program_token_account,
+                    unwrap_account,
+                    native_mint_info,
+                    owner,
+                    destination,
+                    token_program,
+                    system_program_info,
+                    rent_sysvar_info,
+                    amount
+                )?;
+            }
+            None => {
+                Self::transfer_from_vault(
+                    program_id,
+                    pool_info_account,
+                    program_token_account,
+                    destination,
+                    token_program,
+                    amount
+                )?;
+            }
+        }
+
+        msg!("Withdrew {}", amount);
+        user_account.balance = user_account.balance.checked_sub(amount).ok_or(StepError::AmountOverflow)?;
+
+        // An emptied account gives its user slot back to the pool
+        if user_account.is_closable() {
+            msg!("Closing user account in slot {} and refunding rent", user_account.user_index);
+            pool_state.free_user_slot(user_account.user_index);
+            Pool::pack(pool_state, &mut pool_info_account.data.borrow_mut())?;
+            Self::close_program_account(user_step_pool_account, owner)?;
+        }
+        else {
+            UserAccount::pack(user_account, &mut user_step_pool_account.data.borrow_mut())?;
+        }
+
+        Ok(())
+    }
+
     //================================
     // Create Stream
     //================================
     fn process_create_stream(
         accounts: &[AccountInfo],
-        _program_id: &Pubkey,
+        program_id: &Pubkey,
         input_token_pubkey: PubkeyData,
         output_token_pubkey: PubkeyData,
-        interval_days: UnixTimestamp,
-        amount: u64
+        basket: Vec<BasketOutput>,
+        direction: StreamDirection,
+        params: StreamParams
     ) -> ProgramResult {
 
         let account_info_iter = &mut accounts.iter();
@@ -317,126 +757,2579 @@ impl Processor {
         let user_step_pool_account = next_account_info(account_info_iter)?;
         let step_pool_info_account = next_account_info(account_info_iter)?;
         let new_stream_account = next_account_info(account_info_iter)?;
-        let last_user_stream = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let clock_sysvar_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_sysvar_info)?;
+        let rent_sysvar_info = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(rent_sysvar_info)?;
 
-        let mut user_account = UserAccount::unpack_unchecked(&user_step_pool_account.data.borrow())?;
+        if *step_pool_info_account.owner != *program_id || *user_step_pool_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
 
-        // Check if user has any streams
-        if user_account.head_stream == [0; size_of::<PubkeyData>()] {
-            // User has no streams yet. Set the head to the new stream.
-            user_account.head_stream = new_stream_account.key.to_bytes();
+        let mut user_account = UserAccount::unpack(&user_step_pool_account.data.borrow())?;
+
+        if user_account.owner_pubkey != user.key.to_bytes() {
+            msg!("Error: user account is not owned by the signer");
+            return Err(StepError::InvalidOwner.into());
         }
-        else {
-            let mut last_stream = Stream::unpack_unchecked(&last_user_stream.data.borrow())?;
-            last_stream.next_stream = new_stream_account.key.to_bytes();
+
+        if user_account.pool_pubkey != step_pool_info_account.key.to_bytes() {
+            msg!("Error: user account belongs to a different pool");
+            return Err(StepError::PoolMismatch.into());
+        }
+
+        let pool_state = Pool::unpack(&step_pool_info_account.data.borrow())?;
+
+        if input_token_pubkey != pool_state.mint_pubkey {
+            msg!("Error: user account holds no balance of the streams input mint");
+            return Err(StepError::MintMismatch.into());
         }
 
-        Stream::new(input_token_pubkey,
-                    output_token_pubkey,
-                    [0; size_of::<PubkeyData>()],
-                    interval_days,
-                    amount).pack_into_slice(&mut new_stream_account.data.borrow_mut());
+        if basket.is_empty() && output_token_pubkey == input_token_pubkey {
+            msg!("Error: stream output must differ from its input");
+            return Err(StepError::SameInputAndOutput.into());
+        }
+
+        if !basket.is_empty() {
+            Self::validate_basket(&basket, &input_token_pubkey, &params)?;
+        }
+
+        Self::validate_stream_params(&params, clock.unix_timestamp)?;
+
+        let stream_index = user_account.allocate_stream_slot().ok_or_else(|| {
+            msg!("Error: user has no free stream slot");
+            StepError::NoFreeSlot
+        })?;
+        let (pda_stream_account, stream_seed_nonce) = pda::find_stream_address(user_step_pool_account.key, stream_index, program_id);
+
+        if pda_stream_account != *new_stream_account.key {
+            msg!("Error: stream address derivation mismatch, expected slot {}", stream_index);
+            return Err(StepError::AddressDerivationMismatch.into());
+        }
+
+        let stream_index_bytes = stream_index.to_le_bytes();
+        let stream_signer_seeds: &[&[_]] = &[
+            user_step_pool_account.key.as_ref(), STREAM_SEED, &stream_index_bytes, &[stream_seed_nonce]
+        ];
+
+        msg!("Creating stream account in slot {}", stream_index);
+        Self::create_program_account(
+            program_id,
+            user,
+            new_stream_account,
+            system_program_info,
+            rent,
+            Stream::get_packed_len(),
+            stream_signer_seeds
+        )?;
+
+        let next_execution_ts = params.schedule.first_at_or_after(clock.unix_timestamp);
+        let mut stream = Stream::new(user_step_pool_account.key.to_bytes(),
+                                     stream_index,
+                                     input_token_pubkey,
+                                     output_token_pubkey,
+                                     direction,
+                                     params,
+                                     next_execution_ts);
+        stream.basket = basket;
+
+        // Streams only take what the deposit can still fund, and never an unfunded balance
+        let commitment = stream.window_commitment();
+        let free_balance = user_account.free_balance();
+        if free_balance == 0 || free_balance < commitment {
+            msg!("Error: free balance of {} cannot fund windows of {}", free_balance, commitment);
+            return Err(StepError::InsufficientBalance.into());
+        }
+
+        user_account.recommit(&mut stream, commitment)?;
+        stream.pack_into_slice(&mut new_stream_account.data.borrow_mut());
+
+        UserAccount::pack(user_account, &mut user_step_pool_account.data.borrow_mut())?;
 
         Ok(())
     }
 
     //================================
-    // Execute Trade
+    // Cancel Stream
     //================================
-    fn process_trade(
+    fn process_cancel_stream(
         accounts: &[AccountInfo],
-        program_id: &Pubkey,
-        pool_pda_seed: [u8; MAX_SEED_SIZE_BYTES]
+        program_id: &Pubkey
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
 
-        let token_account_seed = b"seedy";
-        let (pda_token_account, seed_nonce) = Pubkey::find_program_address(&[token_account_seed], program_id);
-
-        let deployer = next_account_info(account_info_iter)?;
-        let program_token_account = next_account_info(account_info_iter)?;
-        let token_swap_program = next_account_info(account_info_iter)?;
-        let token_program = next_account_info(account_info_iter)?;
-        let step_program = next_account_info(account_info_iter)?;
-        let swap_info = next_account_info(account_info_iter)?;
-        let authority_info = next_account_info(account_info_iter)?;
-        let swap_source_info = next_account_info(account_info_iter)?;
-        let swap_destination_info = next_account_info(account_info_iter)?;
-        let destination_info = next_account_info(account_info_iter)?;
-        let pool_mint_info = next_account_info(account_info_iter)?;
-        let pool_fee_account_info = next_account_info(account_info_iter)?;
+        let owner = next_account_info(account_info_iter)?;
+        let user_step_pool_account = next_account_info(account_info_iter)?;
+        let step_pool_info_account = next_account_info(account_info_iter)?;
+        let stream_account = next_account_info(account_info_iter)?;
 
-        let host_fee_account: std::option::Option<&Pubkey> = None;
+        let (mut user_account, mut stream) = Self::check_stream_owner(program_id, owner, user_step_pool_account, stream_account)?;
 
-        let trade_amount: u64 = sol_to_lamports(2.0);
+        let pool_state = Self::check_user_pool(program_id, &user_account, step_pool_info_account)?;
+        stream.settle_pending(&pool_state, &mut user_account)?;
 
-        let signer_seeds: &[&[_]] = &[
-            token_account_seed, &[seed_nonce]
-        ];
+        if stream.has_unsettled_input() {
+            msg!("Error: stream has input in a round which is not executed yet");
+            return Err(StepError::RoundPending.into());
+        }
 
-        // msg!("Approving transfer");
-        let approve_ix = spl_token::instruction::approve(
-            &spl_token::id(),
-            &program_token_account.key,
-            &token_swap_program.key,
-            &pda_token_account,
-            &[],
-            trade_amount,
-        ).unwrap();
-
-        //msg!("Calling the token program");
-        invoke_signed(
-            &approve_ix,
-            &[
-                deployer.clone(),
-                program_token_account.clone(),
-                token_swap_program.clone(),
-                token_program.clone()
-            ],
-            &[&signer_seeds]
-        )?;
+        Self::pay_out_outputs(program_id, &mut stream, account_info_iter)?;
 
-        //msg!("Creating swap instruction");
-        let swap = spl_token_swap::instruction::Swap {
-            amount_in: trade_amount,
-            minimum_amount_out: sol_to_lamports(1.0)
-        };
-        let swap_ix = spl_token_swap::instruction::swap(
-            token_swap_program.key,
-            token_program.key,
-            swap_info.key,
-            authority_info.key,
-            &pda_token_account,
-            &program_token_account.key,
-            swap_source_info.key,
-            swap_destination_info.key,
-            destination_info.key,
-            pool_mint_info.key,
-            pool_fee_account_info.key,
-            host_fee_account,
-            swap
-        )?;
+        // Basket input still waiting for the rounds of its pairs goes back to the deposit
+        user_account.balance = user_account.balance.checked_add(stream.take_queued_input()).ok_or(StepError::AmountOverflow)?;
+        user_account.recommit(&mut stream, 0)?;
+        user_account.free_stream_slot(stream.stream_index);
+        UserAccount::pack(user_account, &mut user_step_pool_account.data.borrow_mut())?;
 
-        //msg!("Calling the token swap program");
-        invoke_signed(
-            &swap_ix,
-            &[
-                deployer.clone(),
-                step_program.clone(),
-                token_swap_program.clone(),
-                token_program.clone(),
-                authority_info.clone(),
-                swap_info.clone(),
-                program_token_account.clone(),
-                swap_source_info.clone(),
-                swap_destination_info.clone(),
-                destination_info.clone(),
-                pool_mint_info.clone(),
-                pool_fee_account_info.clone()
-            ],
-            &[&signer_seeds]
-        )?;
+        msg!("Closing stream account and refunding rent");
+        Self::close_program_account(stream_account, owner)?;
 
         Ok(())
     }
+
+    //================================
+    // Claim
+    //================================
+    fn process_claim(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner = next_account_info(account_info_iter)?;
+        let user_step_pool_account = next_account_info(account_info_iter)?;
+        let step_pool_info_account = next_account_info(account_info_iter)?;
+        let stream_account = next_account_info(account_info_iter)?;
+
+        let (mut user_account, mut stream) = Self::check_stream_owner(program_id, owner, user_step_pool_account, stream_account)?;
+        let pool_state = Self::check_user_pool(program_id, &user_account, step_pool_info_account)?;
+        stream.settle_pending(&pool_state, &mut user_account)?;
+        UserAccount::pack(user_account, &mut user_step_pool_account.data.borrow_mut())?;
+
+        if stream.is_basket() {
+            for basket_output in stream.basket.iter() {
+                msg!("Stream traded {} for {} of output {}",
+                     basket_output.total_input,
+                     basket_output.total_output,
+                     Pubkey::new(&basket_output.mint_pubkey));
+            }
+        }
+        else {
+            let (base_amount, quote_amount) = stream.traded_amounts();
+            msg!("Stream traded {} of base against {} of quote", base_amount, quote_amount);
+        }
+
+        Self::pay_out_outputs(program_id, &mut stream, account_info_iter)?;
+        Stream::pack(stream, &mut stream_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    //================================
+    // Claim SOL
+    //================================
+    fn process_claim_sol(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner = next_account_info(account_info_iter)?;
+        let user_step_pool_account = next_account_info(account_info_iter)?;
+        let step_pool_info_account = next_account_info(account_info_iter)?;
+        let stream_account = next_account_info(account_info_iter)?;
+        let output_pool_account = next_account_info(account_info_iter)?;
+        let output_token_account = next_account_info(account_info_iter)?;
+        let unwrap_account = next_account_info(account_info_iter)?;
+        let native_mint_info = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let rent_sysvar_info = next_account_info(account_info_iter)?;
+
+        let (mut user_account, mut stream) = Self::check_stream_owner(program_id, owner, user_step_pool_account, stream_account)?;
+        let pool_state = Self::check_user_pool(program_id, &user_account, step_pool_info_account)?;
+        stream.settle_pending(&pool_state, &mut user_account)?;
+        UserAccount::pack(user_account, &mut user_step_pool_account.data.borrow_mut())?;
+
+        let native_mint = spl_token::native_mint::id().to_bytes();
+        let output_balance = if stream.is_basket() {
+            stream.basket.iter_mut()
+                .find(|basket_output| basket_output.mint_pubkey == native_mint)
+                .map(|basket_output| &mut basket_output.output_balance)
+        }
+        else if stream.output_token_pubkey == native_mint {
+            Some(&mut stream.output_balance)
+        }
+        else {
+            None
+        };
+
+        let output_balance = output_balance.ok_or_else(|| {
+            msg!("Error: stream does not buy wrapped SOL");
+            StepError::NotNativeMint
+        })?;
+
+        if *output_pool_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        if Pool::unpack(&output_pool_account.data.borrow())?.mint_pubkey != native_mint {
+            msg!("Error: output pool does not hold wrapped SOL");
+            return Err(StepError::NotNativeMint.into());
+        }
+
+        if *output_balance > 0 {
+            msg!("Paying out {} lamports of output", output_balance);
+            Self::unwrap_from_vault(
+                program_id,
+                output_pool_account,
+                output_token_account,
+                unwrap_account,
+                native_mint_info,
+                owner,
+                owner,
+                token_program,
+                system_program_info,
+                rent_sysvar_info,
+                *output_balance
+            )?;
+            *output_balance = 0;
+        }
+
+        Stream::pack(stream, &mut stream_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    //================================
+    // Pause Stream
+    //================================
+    fn process_pause_stream(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner = next_account_info(account_info_iter)?;
+        let user_step_pool_account = next_account_info(account_info_iter)?;
+        let stream_account = next_account_info(account_info_iter)?;
+
+        let (_, mut stream) = Self::check_stream_owner(program_id, owner, user_step_pool_account, stream_account)?;
+
+        if stream.status != StreamStatus::Active && stream.status != StreamStatus::Underfunded {
+            msg!("Error: stream is not active");
+            return Err(StepError::StreamNotActive.into());
+        }
+
+        stream.status = StreamStatus::Paused;
+        Stream::pack(stream, &mut stream_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    //================================
+    // Resume Stream
+    //================================
+    fn process_resume_stream(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner = next_account_info(account_info_iter)?;
+        let user_step_pool_account = next_account_info(account_info_iter)?;
+        let stream_account = next_account_info(account_info_iter)?;
+        let clock_sysvar_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_sysvar_info)?;
+
+        let (_, mut stream) = Self::check_stream_owner(program_id, owner, user_step_pool_account, stream_account)?;
+
+        if stream.status != StreamStatus::Paused {
+            msg!("Error: stream is not paused");
+            return Err(StepError::StreamNotPaused.into());
+        }
+
+        // Windows missed while paused are not caught up
+        stream.status = StreamStatus::Active;
+        stream.next_execution_ts = stream.schedule.first_at_or_after(stream.next_execution_ts.max(clock.unix_timestamp));
+        Stream::pack(stream, &mut stream_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    //================================
+    // Update Stream
+    //================================
+    fn process_update_stream(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        params: StreamParams
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner = next_account_info(account_info_iter)?;
+        let user_step_pool_account = next_account_info(account_info_iter)?;
+        let step_pool_info_account = next_account_info(account_info_iter)?;
+        let stream_account = next_account_info(account_info_iter)?;
+        let clock_sysvar_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_sysvar_info)?;
+
+        let (mut user_account, mut stream) = Self::check_stream_owner(program_id, owner, user_step_pool_account, stream_account)?;
+
+        let pool_state = Self::check_user_pool(program_id, &user_account, step_pool_info_account)?;
+        stream.settle_pending(&pool_state, &mut user_account)?;
+
+        // Input in a round in progress was gathered with the current limit price and slippage
+        if stream.has_unsettled_input() {
+            msg!("Error: stream has input in a round which is not executed yet");
+            return Err(StepError::RoundPending.into());
+        }
+
+        if stream.status == StreamStatus::Completed {
+            msg!("Error: stream is completed");
+            return Err(StepError::StreamCompleted.into());
+        }
+
+        if stream.is_basket() {
+            Self::validate_basket_params(&params)?;
+        }
+
+        Self::validate_stream_params(&params, clock.unix_timestamp)?;
+
+        // The next execution stays where the previous schedule put it, moved to the new schedules next window
+        stream.next_execution_ts = params.schedule.first_at_or_after(stream.next_execution_ts);
+        stream.set_params(params);
+
+        let commitment = stream.window_commitment();
+        let available = user_account.available_to(&stream)?;
+        if available < commitment {
+            msg!("Error: free balance of {} cannot fund windows of {}", available, commitment);
+            return Err(StepError::InsufficientBalance.into());
+        }
+
+        user_account.recommit(&mut stream, commitment)?;
+        Stream::pack(stream, &mut stream_account.data.borrow_mut())?;
+        UserAccount::pack(user_account, &mut user_step_pool_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    //================================
+    // Create Rebalance Stream
+    //================================
+    fn process_create_rebalance_stream(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        schedule: Schedule,
+        drift_bps: u16,
+        max_trade_value: u64,
+        slippage_bps: u16,
+        targets: Vec<RebalanceTarget>
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner = next_account_info(account_info_iter)?;
+        let user_step_pool_account = next_account_info(account_info_iter)?;
+        let step_pool_info_account = next_account_info(account_info_iter)?;
+        let new_stream_account = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let clock_sysvar_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_sysvar_info)?;
+        let rent_sysvar_info = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(rent_sysvar_info)?;
+
+        if !owner.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if *user_step_pool_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let user_account = UserAccount::unpack(&user_step_pool_account.data.borrow())?;
+
+        if user_account.owner_pubkey != owner.key.to_bytes() {
+            msg!("Error: user account is not owned by the signer");
+            return Err(StepError::InvalidOwner.into());
+        }
+
+        let pool_state = Self::check_user_pool(program_id, &user_account, step_pool_info_account)?;
+
+        let weighted_mints: Vec<_> = targets.iter().map(|target| (target.mint_pubkey, target.weight_bps)).collect();
+        Self::validate_weighted_mints(&weighted_mints, MAX_REBALANCE_TARGETS)?;
+
+        if targets[0].mint_pubkey != pool_state.mint_pubkey {
+            msg!("Error: the first target must be the mint of the user accounts pool");
+            return Err(StepError::MintMismatch.into());
+        }
+
+        if !schedule.is_valid() {
+            msg!("Error: stream schedule is invalid, intervals must be between {} and {} seconds", MIN_INTERVAL_SECONDS, MAX_INTERVAL_SECONDS);
+            return Err(StepError::InvalidSchedule.into());
+        }
+
+        if drift_bps == 0 || drift_bps >= BPS_DENOMINATOR || max_trade_value == 0 {
+            msg!("Error: rebalancing needs a drift below {} bps and positive drift and trade size", BPS_DENOMINATOR);
+            return Err(StepError::InvalidStrategy.into());
+        }
+
+        if slippage_bps < MIN_SLIPPAGE_BPS || slippage_bps > MAX_SLIPPAGE_BPS {
+            msg!("Error: stream slippage must be between {} and {} bps", MIN_SLIPPAGE_BPS, MAX_SLIPPAGE_BPS);
+            return Err(StepError::InvalidSlippage.into());
+        }
+
+        let (pda_stream_account, stream_seed_nonce) = pda::find_rebalance_stream_address(user_step_pool_account.key, program_id);
+
+        if pda_stream_account != *new_stream_account.key {
+            msg!("Error: rebalance stream address derivation mismatch");
+            return Err(StepError::AddressDerivationMismatch.into());
+        }
+
+        let stream_signer_seeds: &[&[_]] = &[
+            user_step_pool_account.key.as_ref(), REBALANCE_SEED, &[stream_seed_nonce]
+        ];
+
+        msg!("Creating rebalance stream account");
+        Self::create_program_account(
+            program_id,
+            owner,
+            new_stream_account,
+            system_program_info,
+            rent,
+            RebalanceStream::get_packed_len(),
+            stream_signer_seeds
+        )?;
+
+        let stream = RebalanceStream {
+            is_initialized: true,
+            user_pubkey: user_step_pool_account.key.to_bytes(),
+            owner_pubkey: owner.key.to_bytes(),
+            next_execution_ts: schedule.first_at_or_after(clock.unix_timestamp),
+            schedule,
+            drift_bps,
+            max_trade_value,
+            slippage_bps,
+            targets,
+            rebalances: 0
+        };
+        RebalanceStream::pack(stream, &mut new_stream_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    //================================
+    // Rebalance
+    //================================
+    fn process_rebalance(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let clock_sysvar_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_sysvar_info)?;
+        let stream_account = next_account_info(account_info_iter)?;
+
+        if *stream_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut stream = RebalanceStream::unpack(&stream_account.data.borrow())?;
+
+        if !stream.is_due(clock.unix_timestamp) {
+            msg!("Error: rebalance stream is not due before {}", stream.next_execution_ts);
+            return Err(StepError::NotDue.into());
+        }
+
+        // The pool and the owners user account of every target, in the streams order
+        let mut holdings = Vec::with_capacity(stream.targets.len());
+        for (i, target) in stream.targets.iter().enumerate() {
+            let pool_account = next_account_info(account_info_iter)?;
+            let user_info = next_account_info(account_info_iter)?;
+
+            if *pool_account.owner != *program_id || *user_info.owner != *program_id {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+
+            let pool = Pool::unpack(&pool_account.data.borrow())?;
+            let user_account = UserAccount::unpack(&user_info.data.borrow())?;
+
+            if pool.mint_pubkey != target.mint_pubkey
+                || user_account.pool_pubkey != pool_account.key.to_bytes()
+                || user_account.owner_pubkey != stream.owner_pubkey
+                || (i == 0 && user_info.key.to_bytes() != stream.user_pubkey) {
+                msg!("Error: user account {} does not hold the deposit of target {}", user_info.key, i);
+                return Err(StepError::MintMismatch.into());
+            }
+
+            holdings.push((pool_account, user_info, user_account));
+        }
+
+        // Every mint is valued in the first mint through the swap of their route
+        let first_pool_account = holdings[0].0;
+        let mut values = vec![holdings[0].2.balance];
+        let mut value_reserves = vec![(1, 1)];
+        for (i, (pool_account, _, user_account)) in holdings.iter().enumerate().skip(1) {
+            let route_account = next_account_info(account_info_iter)?;
+            let swap_info = next_account_info(account_info_iter)?;
+            let swap_first_info = next_account_info(account_info_iter)?;
+            let swap_target_info = next_account_info(account_info_iter)?;
+
+            let route = Self::check_route(program_id, route_account, first_pool_account.key, pool_account.key)?;
+
+            if route.swap_pubkey != swap_info.key.to_bytes() {
+                msg!("Error: swap is not the registered route of the pools");
+                return Err(StepError::InvalidRoute.into());
+            }
+
+            let (first_reserve, target_reserve) = Self::unpack_swap_reserves(
+                &Pubkey::new(&route.token_swap_program_pubkey),
+                swap_info,
+                swap_first_info,
+                swap_target_info,
+                &stream.targets[0].mint_pubkey,
+                &stream.targets[i].mint_pubkey
+            )?;
+            values.push(Self::quote(user_account.balance, target_reserve, first_reserve)?);
+            value_reserves.push((first_reserve, target_reserve));
+        }
+
+        stream.next_execution_ts = stream.schedule
+            .next_after_skipping(stream.next_execution_ts, clock.unix_timestamp)
+            .ok_or(StepError::AmountOverflow)?;
+
+        let (overweight, underweight, trade_value) = match stream.rebalance_step(&values) {
+            Some(step) => step,
+            None => {
+                msg!("Holdings are within {} bps of their targets", stream.drift_bps);
+                RebalanceStream::pack(stream, &mut stream_account.data.borrow_mut())?;
+                return Ok(());
+            }
+        };
+
+        let route_account = next_account_info(account_info_iter)?;
+        let overweight_token_account = next_account_info(account_info_iter)?;
+        let underweight_token_account = next_account_info(account_info_iter)?;
+        let token_swap_program = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let swap_info = next_account_info(account_info_iter)?;
+        let authority_info = next_account_info(account_info_iter)?;
+        let swap_overweight_info = next_account_info(account_info_iter)?;
+        let swap_underweight_info = next_account_info(account_info_iter)?;
+        let pool_mint_info = next_account_info(account_info_iter)?;
+        let pool_fee_account_info = next_account_info(account_info_iter)?;
+
+        let overweight_pool_account = holdings[overweight].0;
+        let underweight_pool_account = holdings[underweight].0;
+        let route = Self::check_route(program_id, route_account, overweight_pool_account.key, underweight_pool_account.key)?;
+
+        if route.token_swap_program_pubkey != token_swap_program.key.to_bytes()
+            || route.swap_pubkey != swap_info.key.to_bytes() {
+            msg!("Error: swap is not the registered route of the pools");
+            return Err(StepError::InvalidRoute.into());
+        }
+
+        let (overweight_vault, overweight_vault_nonce) = pda::find_vault_address(overweight_pool_account.key, program_id);
+        let (underweight_vault, _) = pda::find_vault_address(underweight_pool_account.key, program_id);
+
+        if overweight_vault != *overweight_token_account.key || underweight_vault != *underweight_token_account.key {
+            msg!("Error: program token address derivation mismatch");
+            return Err(StepError::AddressDerivationMismatch.into());
+        }
+
+        let (overweight_reserve, underweight_reserve) = Self::unpack_swap_reserves(
+            token_swap_program.key,
+            swap_info,
+            swap_overweight_info,
+            swap_underweight_info,
+            &stream.targets[overweight].mint_pubkey,
+            &stream.targets[underweight].mint_pubkey
+        )?;
+
+        // Back from a value in the first mint to an amount of the overweight mint, leaving what is
+        // reserved for streams
+        let (first_reserve, target_reserve) = value_reserves[overweight];
+        let amount_in = Self::quote(trade_value, first_reserve, target_reserve)?.min(holdings[overweight].2.free_balance());
+
+        if amount_in == 0 {
+            msg!("Rebalance step is too small to trade");
+            RebalanceStream::pack(stream, &mut stream_account.data.borrow_mut())?;
+            return Ok(());
+        }
+
+        msg!("Rebalancing {} of target {} into target {}", amount_in, overweight, underweight);
+        let swap_accounts = SwapAccounts {
+            token_swap_program,
+            token_program,
+            swap_info,
+            authority_info,
+            pool_mint_info,
+            pool_fee_account_info
+        };
+        let signer_seeds: &[&[_]] = &[
+            overweight_pool_account.key.as_ref(), VAULT_SEED, &[overweight_vault_nonce]
+        ];
+        let swapped = Self::swap(
+            &swap_accounts,
+            overweight_token_account,
+            swap_overweight_info,
+            swap_underweight_info,
+            underweight_token_account,
+            signer_seeds,
+            spl_token_swap::instruction::Swap {
+                amount_in,
+                minimum_amount_out: Self::minimum_amount_out(amount_in, overweight_reserve, underweight_reserve, stream.slippage_bps)?
+            }
+        )?;
+
+        holdings[overweight].2.balance = holdings[overweight].2.balance.checked_sub(amount_in).ok_or(StepError::AmountOverflow)?;
+        holdings[underweight].2.balance = holdings[underweight].2.balance.checked_add(swapped).ok_or(StepError::AmountOverflow)?;
+        for (_, user_info, user_account) in holdings.into_iter() {
+            UserAccount::pack(user_account, &mut user_info.data.borrow_mut())?;
+        }
+
+        stream.rebalances = stream.rebalances.checked_add(1).ok_or(StepError::AmountOverflow)?;
+        RebalanceStream::pack(stream, &mut stream_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    //================================
+    // Cancel Rebalance Stream
+    //================================
+    fn process_cancel_rebalance_stream(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner = next_account_info(account_info_iter)?;
+        let stream_account = next_account_info(account_info_iter)?;
+
+        if !owner.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if *stream_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let stream = RebalanceStream::unpack(&stream_account.data.borrow())?;
+
+        if stream.owner_pubkey != owner.key.to_bytes() {
+            msg!("Error: rebalance stream is not owned by the signer");
+            return Err(StepError::InvalidOwner.into());
+        }
+
+        msg!("Closing rebalance stream account and refunding rent");
+        Self::close_program_account(stream_account, owner)?;
+
+        Ok(())
+    }
+
+    //================================
+    // Create TWAP Order
+    //================================
+    fn process_create_twap_order(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        order_id: u64,
+        output_token_pubkey: PubkeyData,
+        direction: StreamDirection,
+        total_amount: u64,
+        (start_ts, end_ts): (UnixTimestamp, UnixTimestamp),
+        slice_count: u32,
+        limit_price: Option<Price>,
+        slippage_bps: u16
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner = next_account_info(account_info_iter)?;
+        let user_step_pool_account = next_account_info(account_info_iter)?;
+        let step_pool_info_account = next_account_info(account_info_iter)?;
+        let new_order_account = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let clock_sysvar_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_sysvar_info)?;
+        let rent_sysvar_info = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(rent_sysvar_info)?;
+
+        if !owner.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if *user_step_pool_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut user_account = UserAccount::unpack(&user_step_pool_account.data.borrow())?;
+
+        if user_account.owner_pubkey != owner.key.to_bytes() {
+            msg!("Error: user account is not owned by the signer");
+            return Err(StepError::InvalidOwner.into());
+        }
+
+        let pool_state = Self::check_user_pool(program_id, &user_account, step_pool_info_account)?;
+
+        if output_token_pubkey == pool_state.mint_pubkey {
+            msg!("Error: order output must differ from its input");
+            return Err(StepError::SameInputAndOutput.into());
+        }
+
+        if total_amount == 0 || slice_count == 0 {
+            msg!("Error: order amount and slice count must be positive");
+            return Err(StepError::InvalidAmount.into());
+        }
+
+        // Every slice needs a distinct second
+        if end_ts <= clock.unix_timestamp || end_ts.checked_sub(start_ts).ok_or(StepError::AmountOverflow)? < slice_count as UnixTimestamp {
+            msg!("Error: order must end in the future and last at least one second per slice");
+            return Err(StepError::InvalidSchedule.into());
+        }
+
+        if slippage_bps < MIN_SLIPPAGE_BPS || slippage_bps > MAX_SLIPPAGE_BPS {
+            msg!("Error: order slippage must be between {} and {} bps", MIN_SLIPPAGE_BPS, MAX_SLIPPAGE_BPS);
+            return Err(StepError::InvalidSlippage.into());
+        }
+
+        if limit_price.map_or(false, |price| price.quote_amount == 0 || price.base_amount == 0) {
+            msg!("Error: order limit price must be positive");
+            return Err(StepError::InvalidLimitPrice.into());
+        }
+
+        if user_account.free_balance() < total_amount {
+            msg!("Error: order amount exceeds the users free balance of {}", user_account.free_balance());
+            return Err(StepError::InsufficientBalance.into());
+        }
+
+        let (pda_order_account, order_seed_nonce) = pda::find_twap_order_address(user_step_pool_account.key, order_id, program_id);
+
+        if pda_order_account != *new_order_account.key {
+            msg!("Error: order address derivation mismatch");
+            return Err(StepError::AddressDerivationMismatch.into());
+        }
+
+        let order_id_bytes = order_id.to_le_bytes();
+        let order_signer_seeds: &[&[_]] = &[
+            user_step_pool_account.key.as_ref(), TWAP_SEED, &order_id_bytes, &[order_seed_nonce]
+        ];
+
+        msg!("Creating TWAP order {} of {} in {} slices", order_id, total_amount, slice_count);
+        Self::create_program_account(
+            program_id,
+            owner,
+            new_order_account,
+            system_program_info,
+            rent,
+            TwapOrder::get_packed_len(),
+            order_signer_seeds
+        )?;
+
+        let order = TwapOrder {
+            is_initialized: true,
+            user_pubkey: user_step_pool_account.key.to_bytes(),
+            pool_pubkey: step_pool_info_account.key.to_bytes(),
+            owner_pubkey: owner.key.to_bytes(),
+            order_id,
+            input_token_pubkey: pool_state.mint_pubkey,
+            output_token_pubkey,
+            direction,
+            total_amount,
+            start_ts,
+            end_ts,
+            slice_count,
+            limit_price,
+            slippage_bps,
+            next_slice: 0,
+            filled_input: 0,
+            total_output: 0,
+            output_balance: 0
+        };
+        TwapOrder::pack(order, &mut new_order_account.data.borrow_mut())?;
+
+        // The input of the order is set aside from the deposit straight away
+        user_account.balance = user_account.balance.checked_sub(total_amount).ok_or(StepError::AmountOverflow)?;
+        user_account.order_count = user_account.order_count.checked_add(1).ok_or(StepError::AmountOverflow)?;
+        UserAccount::pack(user_account, &mut user_step_pool_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    //================================
+    // Execute TWAP Slice
+    //================================
+    fn process_execute_twap_slice(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let keeper = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+        let route_account = next_account_info(account_info_iter)?;
+        let input_pool_account = next_account_info(account_info_iter)?;
+        let input_token_account = next_account_info(account_info_iter)?;
+        let output_pool_account = next_account_info(account_info_iter)?;
+        let output_token_account = next_account_info(account_info_iter)?;
+        let token_swap_program = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let swap_info = next_account_info(account_info_iter)?;
+        let authority_info = next_account_info(account_info_iter)?;
+        let swap_input_info = next_account_info(account_info_iter)?;
+        let swap_output_info = next_account_info(account_info_iter)?;
+        let pool_mint_info = next_account_info(account_info_iter)?;
+        let pool_fee_account_info = next_account_info(account_info_iter)?;
+        let reward_vault = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let rent_sysvar_info = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(rent_sysvar_info)?;
+        let clock_sysvar_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_sysvar_info)?;
+        let order_account = next_account_info(account_info_iter)?;
+
+        if *program_state_account.owner != *program_id
+            || *input_pool_account.owner != *program_id
+            || *output_pool_account.owner != *program_id
+            || *order_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let program_state = StepProgramState::unpack(&program_state_account.data.borrow())?;
+        let mut order = TwapOrder::unpack(&order_account.data.borrow())?;
+        let input_pool = Pool::unpack(&input_pool_account.data.borrow())?;
+        let output_pool = Pool::unpack(&output_pool_account.data.borrow())?;
+
+        if order.pool_pubkey != input_pool_account.key.to_bytes() || order.output_token_pubkey != output_pool.mint_pubkey {
+            msg!("Error: pools do not match the orders pair");
+            return Err(StepError::PoolMismatch.into());
+        }
+
+        let route = Self::check_route(program_id, route_account, input_pool_account.key, output_pool_account.key)?;
+
+        if route.token_swap_program_pubkey != token_swap_program.key.to_bytes()
+            || route.swap_pubkey != swap_info.key.to_bytes() {
+            msg!("Error: swap is not the registered route of the pools");
+            return Err(StepError::InvalidRoute.into());
+        }
+
+        let (input_vault, input_vault_nonce) = pda::find_vault_address(input_pool_account.key, program_id);
+        let (output_vault, _) = pda::find_vault_address(output_pool_account.key, program_id);
+
+        if input_vault != *input_token_account.key || output_vault != *output_token_account.key {
+            msg!("Error: program token address derivation mismatch");
+            return Err(StepError::AddressDerivationMismatch.into());
+        }
+
+        let (slice, amount_in) = order.due_input(clock.unix_timestamp)?.ok_or_else(|| {
+            msg!("Error: no slice of the order is due");
+            StepError::NotDue
+        })?;
+
+        let (input_reserve, output_reserve) = Self::unpack_swap_reserves(
+            token_swap_program.key,
+            swap_info,
+            swap_input_info,
+            swap_output_info,
+            &order.input_token_pubkey,
+            &order.output_token_pubkey
+        )?;
+
+        // Unfilled slices roll forward into the next one
+        order.next_slice = slice;
+
+        if amount_in == 0 || !order.limit_price.map_or(true, |limit| limit.allows(order.direction, input_reserve, output_reserve)) {
+            msg!("Skipping slice {}: limit price not met", slice - 1);
+            TwapOrder::pack(order, &mut order_account.data.borrow_mut())?;
+            return Ok(());
+        }
+
+        let swap_accounts = SwapAccounts {
+            token_swap_program,
+            token_program,
+            swap_info,
+            authority_info,
+            pool_mint_info,
+            pool_fee_account_info
+        };
+        let signer_seeds: &[&[_]] = &[
+            input_pool_account.key.as_ref(), VAULT_SEED, &[input_vault_nonce]
+        ];
+        let swapped = Self::swap(
+            &swap_accounts,
+            input_token_account,
+            swap_input_info,
+            swap_output_info,
+            output_token_account,
+            signer_seeds,
+            spl_token_swap::instruction::Swap {
+                amount_in,
+                minimum_amount_out: Self::minimum_amount_out(amount_in, input_reserve, output_reserve, order.slippage_bps)?
+            }
+        )?;
+
+        msg!("Slice {} filled {} for {}", slice - 1, amount_in, swapped);
+        order.filled_input = order.filled_input.checked_add(amount_in).ok_or(StepError::AmountOverflow)?;
+        order.total_output = order.total_output.checked_add(swapped).ok_or(StepError::AmountOverflow)?;
+        order.output_balance = order.output_balance.checked_add(swapped).ok_or(StepError::AmountOverflow)?;
+        TwapOrder::pack(order, &mut order_account.data.borrow_mut())?;
+
+        // Slices too small to be worth a transaction are filled without paying the keeper
+        let is_rewarded = amount_in >= input_pool.min_reward_input.max(1);
+        if !is_rewarded {
+            msg!("Slice input is below the minimum paying the keeper reward");
+        }
+
+        Self::pay_keeper_reward(
+            program_id,
+            if is_rewarded { program_state.keeper_reward_lamports } else { 0 },
+            keeper,
+            reward_vault,
+            system_program_info,
+            rent
+        )
+    }
+
+    //================================
+    // Close TWAP Order
+    //================================
+    fn process_close_twap_order(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner = next_account_info(account_info_iter)?;
+        let user_step_pool_account = next_account_info(account_info_iter)?;
+        let order_account = next_account_info(account_info_iter)?;
+        let output_pool_account = next_account_info(account_info_iter)?;
+        let output_token_account = next_account_info(account_info_iter)?;
+        let owner_output_token_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+
+        if !owner.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if *user_step_pool_account.owner != *program_id || *order_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut user_account = UserAccount::unpack(&user_step_pool_account.data.borrow())?;
+        let mut order = TwapOrder::unpack(&order_account.data.borrow())?;
+
+        if order.owner_pubkey != owner.key.to_bytes() || order.user_pubkey != user_step_pool_account.key.to_bytes() {
+            msg!("Error: order is not owned by the signer");
+            return Err(StepError::InvalidOwner.into());
+        }
+
+        Self::pay_out_output(
+            program_id,
+            &order.output_token_pubkey,
+            &mut order.output_balance,
+            output_pool_account,
+            output_token_account,
+            owner_output_token_account,
+            token_program
+        )?;
+
+        let unfilled_input = order.unfilled_input()?;
+        msg!("Returning {} of unfilled input", unfilled_input);
+        user_account.balance = user_account.balance.checked_add(unfilled_input).ok_or(StepError::AmountOverflow)?;
+        user_account.order_count = user_account.order_count.checked_sub(1).ok_or(StepError::AmountOverflow)?;
+        UserAccount::pack(user_account, &mut user_step_pool_account.data.borrow_mut())?;
+
+        msg!("Closing order account and refunding rent");
+        Self::close_program_account(order_account, owner)?;
+
+        Ok(())
+    }
+
+    //================================
+    // Set Auto Delivery
+    //================================
+    fn process_set_auto_delivery(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        enabled: bool
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner = next_account_info(account_info_iter)?;
+        let user_step_pool_account = next_account_info(account_info_iter)?;
+
+        if !owner.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if *user_step_pool_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut user_account = UserAccount::unpack(&user_step_pool_account.data.borrow())?;
+
+        if user_account.owner_pubkey != owner.key.to_bytes() {
+            msg!("Error: user account is not owned by the signer");
+            return Err(StepError::InvalidOwner.into());
+        }
+
+        user_account.auto_deliver = enabled;
+        UserAccount::pack(user_account, &mut user_step_pool_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    //================================
+    // Deliver
+    //================================
+    fn process_deliver(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let pool_account = next_account_info(account_info_iter)?;
+        let output_pool_account = next_account_info(account_info_iter)?;
+        let output_token_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+
+        if *pool_account.owner != *program_id || *output_pool_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        // The pools token account signs deliveries, so only the token program may be invoked
+        if *token_program.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let pool = Pool::unpack(&pool_account.data.borrow())?;
+        let output_mint = Pool::unpack(&output_pool_account.data.borrow())?.mint_pubkey;
+        let delivery_accounts = DeliveryAccounts {
+            output_pool_account,
+            output_token_account,
+            token_program
+        };
+
+        while let Some(user_info) = account_info_iter.next() {
+            let stream_info = next_account_info(account_info_iter)?;
+            let destination = next_account_info(account_info_iter)?;
+
+            if *user_info.owner != *program_id || *stream_info.owner != *program_id {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+
+            let mut user_account = UserAccount::unpack(&user_info.data.borrow())?;
+            let mut stream = Stream::unpack(&stream_info.data.borrow())?;
+
+            if user_account.pool_pubkey != pool_account.key.to_bytes() {
+                msg!("Error: user account {} belongs to a different pool", user_info.key);
+                return Err(StepError::PoolMismatch.into());
+            }
+
+            if stream.user_pubkey != user_info.key.to_bytes() {
+                msg!("Error: stream {} does not belong to the user account", stream_info.key);
+                return Err(StepError::StreamMismatch.into());
+            }
+
+            if !user_account.auto_deliver {
+                msg!("Skipping stream {}: auto-delivery is off", stream_info.key);
+                continue;
+            }
+
+            stream.settle_pending(&pool, &mut user_account)?;
+            Self::deliver_output(
+                program_id,
+                &mut stream,
+                &Pubkey::new(&user_account.owner_pubkey),
+                &output_mint,
+                &delivery_accounts,
+                destination
+            )?;
+
+            UserAccount::pack(user_account, &mut user_info.data.borrow_mut())?;
+            Stream::pack(stream, &mut stream_info.data.borrow_mut())?;
+        }
+
+        Ok(())
+    }
+
+    //================================
+    // Initialize Route
+    //================================
+    fn process_init_route(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let deployer = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+        let route_account = next_account_info(account_info_iter)?;
+        let pool_a_account = next_account_info(account_info_iter)?;
+        let pool_b_account = next_account_info(account_info_iter)?;
+        let token_swap_program = next_account_info(account_info_iter)?;
+        let swap_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let rent_sysvar_info = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(rent_sysvar_info)?;
+
+        Self::check_deployer(program_id, deployer, program_state_account)?;
+
+        if *pool_a_account.owner != *program_id || *pool_b_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let pool_a = Pool::unpack(&pool_a_account.data.borrow())?;
+        let pool_b = Pool::unpack(&pool_b_account.data.borrow())?;
+
+        if swap_info.owner != token_swap_program.key {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let swap_state = SwapVersion::unpack(&swap_info.data.borrow())?;
+        let swap_mints = [swap_state.token_a_mint().to_bytes(), swap_state.token_b_mint().to_bytes()];
+
+        if pool_a.mint_pubkey == pool_b.mint_pubkey
+            || !swap_mints.contains(&pool_a.mint_pubkey)
+            || !swap_mints.contains(&pool_b.mint_pubkey) {
+            msg!("Error: swap does not trade the pools mints");
+            return Err(StepError::InvalidRoute.into());
+        }
+
+        let (pda_route_account, route_seed_nonce) = pda::find_route_address(pool_a_account.key, pool_b_account.key, program_id);
+
+        if pda_route_account != *route_account.key {
+            msg!("Error: route address derivation mismatch");
+            return Err(StepError::AddressDerivationMismatch.into());
+        }
+
+        let (first_pool, second_pool) = pda::sort_pools(pool_a_account.key, pool_b_account.key);
+        let route_signer_seeds: &[&[_]] = &[
+            ROUTE_SEED, first_pool.as_ref(), second_pool.as_ref(), &[route_seed_nonce]
+        ];
+
+        msg!("Creating route account");
+        invoke_signed(
+            &system_instruction::create_account(
+                deployer.key,
+                route_account.key,
+                1.max(rent.minimum_balance(Route::get_packed_len())),
+                Route::get_packed_len() as u64,
+                program_id
+            ),
+            &[
+                deployer.clone(),
+                route_account.clone(),
+                system_program_info.clone()
+            ],
+            &[&route_signer_seeds]
+        )?;
+
+        Route {
+            is_initialized: true,
+            token_swap_program_pubkey: token_swap_program.key.to_bytes(),
+            swap_pubkey: swap_info.key.to_bytes()
+        }.pack_into_slice(&mut route_account.data.borrow_mut());
+
+        Ok(())
+    }
+
+    //================================
+    // Set Keeper Reward
+    //================================
+    fn process_set_keeper_reward(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        keeper_reward_lamports: u64
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let deployer = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+
+        let mut program_state = Self::check_deployer(program_id, deployer, program_state_account)?;
+        program_state.keeper_reward_lamports = keeper_reward_lamports;
+        StepProgramState::pack(program_state, &mut program_state_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    //================================
+    // Set Min Reward Input
+    //================================
+    fn process_set_min_reward_input(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        min_reward_input: u64
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let deployer = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+        let pool_account = next_account_info(account_info_iter)?;
+
+        Self::check_deployer(program_id, deployer, program_state_account)?;
+
+        if *pool_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut pool = Pool::unpack(&pool_account.data.borrow())?;
+        pool.min_reward_input = min_reward_input;
+        Pool::pack(pool, &mut pool_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    //================================
+    // Abort Round
+    //================================
+    fn process_abort_round(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let deployer = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+        let pool_account = next_account_info(account_info_iter)?;
+        let other_pool_account = next_account_info(account_info_iter)?;
+
+        Self::check_deployer(program_id, deployer, program_state_account)?;
+
+        if *pool_account.owner != *program_id || *other_pool_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut pool = Pool::unpack(&pool_account.data.borrow())?;
+        let mut other_pool = Pool::unpack(&other_pool_account.data.borrow())?;
+
+        if pool.round_state == RoundState::Idle
+            || other_pool.round_state == RoundState::Idle
+            || pool.round_output_mint != other_pool.mint_pubkey
+            || other_pool.round_output_mint != pool.mint_pubkey {
+            msg!("Error: pools are not in a round with each other");
+            return Err(StepError::RoundNotGathered.into());
+        }
+
+        msg!("Aborting rounds {} and {}", pool.round_id, other_pool.round_id);
+        pool.abort_round();
+        other_pool.abort_round();
+        Pool::pack(pool, &mut pool_account.data.borrow_mut())?;
+        Pool::pack(other_pool, &mut other_pool_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    //================================
+    // Execute Trade
+    //================================
+    fn process_trade(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let keeper = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+        let route_account = next_account_info(account_info_iter)?;
+        let input_pool_account = next_account_info(account_info_iter)?;
+        let input_token_account = next_account_info(account_info_iter)?;
+        let output_pool_account = next_account_info(account_info_iter)?;
+        let output_token_account = next_account_info(account_info_iter)?;
+        let token_swap_program = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let swap_info = next_account_info(account_info_iter)?;
+        let authority_info = next_account_info(account_info_iter)?;
+        let swap_input_info = next_account_info(account_info_iter)?;
+        let swap_output_info = next_account_info(account_info_iter)?;
+        let pool_mint_info = next_account_info(account_info_iter)?;
+        let pool_fee_account_info = next_account_info(account_info_iter)?;
+        let reward_vault = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let rent_sysvar_info = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(rent_sysvar_info)?;
+
+        if *program_state_account.owner != *program_id
+            || *input_pool_account.owner != *program_id
+            || *output_pool_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let program_state = StepProgramState::unpack(&program_state_account.data.borrow())?;
+        let route = Self::check_route(program_id, route_account, input_pool_account.key, output_pool_account.key)?;
+
+        if route.token_swap_program_pubkey != token_swap_program.key.to_bytes()
+            || route.swap_pubkey != swap_info.key.to_bytes() {
+            msg!("Error: swap is not the registered route of the pools");
+            return Err(StepError::InvalidRoute.into());
+        }
+
+        let mut input_pool = Pool::unpack(&input_pool_account.data.borrow())?;
+        let mut output_pool = Pool::unpack(&output_pool_account.data.borrow())?;
+
+        if input_pool.round_state != RoundState::Gathered
+            || output_pool.round_state != RoundState::Gathered
+            || input_pool.round_output_mint != output_pool.mint_pubkey
+            || output_pool.round_output_mint != input_pool.mint_pubkey {
+            msg!("Error: pools have not gathered a round with each other");
+            return Err(StepError::RoundNotGathered.into());
+        }
+
+        let swap_accounts = SwapAccounts {
+            token_swap_program,
+            token_program,
+            swap_info,
+            authority_info,
+            pool_mint_info,
+            pool_fee_account_info
+        };
+
+        let (input_vault, input_vault_nonce) = pda::find_vault_address(input_pool_account.key, program_id);
+        let (output_vault, output_vault_nonce) = pda::find_vault_address(output_pool_account.key, program_id);
+
+        if input_vault != *input_token_account.key || output_vault != *output_token_account.key {
+            msg!("Error: program token address derivation mismatch");
+            return Err(StepError::AddressDerivationMismatch.into());
+        }
+
+        let (input_reserve, output_reserve) = Self::unpack_swap_reserves(
+            token_swap_program.key,
+            swap_info,
+            swap_input_info,
+            swap_output_info,
+            &input_pool.mint_pubkey,
+            &output_pool.mint_pubkey
+        )?;
+
+        // The input of the limit prices the reserves are not within anymore stays out of the round
+        let input_withheld = input_pool.withhold_unmet_limits(input_reserve, output_reserve)?;
+        let output_withheld = output_pool.withhold_unmet_limits(output_reserve, input_reserve)?;
+        if input_withheld > 0 || output_withheld > 0 {
+            msg!("Withheld {} and {} of input whose limit price is not met", input_withheld, output_withheld);
+        }
+
+        let input_due = input_pool.round_input;
+        let output_due = output_pool.round_input;
+        // Rounds too small to be worth a transaction are executed without paying the keeper
+        let is_rewarded = input_due >= input_pool.min_reward_input.max(1) || output_due >= output_pool.min_reward_input.max(1);
+
+        if input_due == 0 && output_due == 0 {
+            msg!("No streams due");
+            input_pool.settle_round(0);
+            output_pool.settle_round(0);
+            Pool::pack(input_pool, &mut input_pool_account.data.borrow_mut())?;
+            Pool::pack(output_pool, &mut output_pool_account.data.borrow_mut())?;
+            return Ok(());
+        }
+
+        // The swap has to satisfy the strictest stream of both sides
+        let slippage_bps = input_pool.round_slippage_bps.min(output_pool.round_slippage_bps);
+
+        // Match both sides against each other at the swap's mid-price. Any rounding dust of the
+        // matched side stays in its pools token account.
+        let input_matched = input_due.min(Self::quote(output_due, output_reserve, input_reserve)?);
+        let output_matched = Self::quote(input_matched, input_reserve, output_reserve)?;
+        msg!("Matched {} of input against {} of output", input_matched, output_matched);
+
+        // Only the imbalance is traded through the swap
+        let (input_side_output, output_side_output) = if input_due > input_matched {
+            let amount_in = input_due.checked_sub(input_matched).ok_or(StepError::AmountOverflow)?;
+            let signer_seeds: &[&[_]] = &[
+                input_pool_account.key.as_ref(), VAULT_SEED, &[input_vault_nonce]
+            ];
+            let swapped = Self::swap(
+                &swap_accounts,
+                input_token_account,
+                swap_input_info,
+                swap_output_info,
+                output_token_account,
+                signer_seeds,
+                spl_token_swap::instruction::Swap {
+                    amount_in,
+                    minimum_amount_out: Self::minimum_amount_out(amount_in, input_reserve, output_reserve, slippage_bps)?
+                }
+            )?;
+            (output_matched.checked_add(swapped).ok_or(StepError::AmountOverflow)?, input_matched)
+        }
+        else if output_due > output_matched {
+            let amount_in = output_due.checked_sub(output_matched).ok_or(StepError::AmountOverflow)?;
+            let signer_seeds: &[&[_]] = &[
+                output_pool_account.key.as_ref(), VAULT_SEED, &[output_vault_nonce]
+            ];
+            let swapped = Self::swap(
+                &swap_accounts,
+                output_token_account,
+                swap_output_info,
+                swap_input_info,
+                input_token_account,
+                signer_seeds,
+                spl_token_swap::instruction::Swap {
+                    amount_in,
+                    minimum_amount_out: Self::minimum_amount_out(amount_in, output_reserve, input_reserve, slippage_bps)?
+                }
+            )?;
+            (output_matched, input_matched.checked_add(swapped).ok_or(StepError::AmountOverflow)?)
+        }
+        else {
+            (output_matched, input_matched)
+        };
+
+        msg!("Round {} credited {} of output, round {} credited {} of input",
+             input_pool.round_id, input_side_output, output_pool.round_id, output_side_output);
+        input_pool.settle_round(input_side_output);
+        output_pool.settle_round(output_side_output);
+        Pool::pack(input_pool, &mut input_pool_account.data.borrow_mut())?;
+        Pool::pack(output_pool, &mut output_pool_account.data.borrow_mut())?;
+
+        if !is_rewarded {
+            msg!("Round input is below the minimum paying the keeper reward");
+        }
+
+        Self::pay_keeper_reward(
+            program_id,
+            if is_rewarded { program_state.keeper_reward_lamports } else { 0 },
+            keeper,
+            reward_vault,
+            system_program_info,
+            rent
+        )
+    }
+
+    //================================
+    // Gather
+    //================================
+    fn process_gather(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let pool_account = next_account_info(account_info_iter)?;
+        let other_pool_account = next_account_info(account_info_iter)?;
+        let route_account = next_account_info(account_info_iter)?;
+        let clock_sysvar_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_sysvar_info)?;
+        let swap_info = next_account_info(account_info_iter)?;
+        let swap_pool_token_info = next_account_info(account_info_iter)?;
+        let swap_other_token_info = next_account_info(account_info_iter)?;
+        let other_token_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+
+        if *pool_account.owner != *program_id || *other_pool_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        // The pools token account signs deliveries, so only the token program may be invoked
+        if *token_program.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        if pool_account.key == other_pool_account.key {
+            msg!("Error: a pool cannot be paired with itself");
+            return Err(StepError::InvalidRoute.into());
+        }
+
+        // Rounds are only started for pairs that can be executed
+        let route = Self::check_route(program_id, route_account, pool_account.key, other_pool_account.key)?;
+
+        if route.swap_pubkey != swap_info.key.to_bytes() {
+            msg!("Error: swap is not the registered route of the pools");
+            return Err(StepError::InvalidRoute.into());
+        }
+
+        let mut pool = Pool::unpack(&pool_account.data.borrow())?;
+        let mut other_pool = Pool::unpack(&other_pool_account.data.borrow())?;
+
+        // Limit prices are checked against these reserves to skip streams early, and again against
+        // the reserves the round is executed at
+        let reserves = Self::unpack_swap_reserves(
+            &Pubkey::new(&route.token_swap_program_pubkey),
+            swap_info,
+            swap_pool_token_info,
+            swap_other_token_info,
+            &pool.mint_pubkey,
+            &other_pool.mint_pubkey
+        )?;
+
+        if pool.round_state == RoundState::Idle && other_pool.round_state == RoundState::Idle {
+            pool.start_round(other_pool.mint_pubkey)?;
+            other_pool.start_round(pool.mint_pubkey)?;
+            msg!("Starting rounds {} and {}", pool.round_id, other_pool.round_id);
+        }
+
+        if pool.round_state != RoundState::Gathering
+            || pool.round_output_mint != other_pool.mint_pubkey
+            || other_pool.round_output_mint != pool.mint_pubkey {
+            msg!("Error: pool is not gathering a round with the other pool");
+            return Err(StepError::RoundNotGathered.into());
+        }
+
+        let delivery_accounts = DeliveryAccounts {
+            output_pool_account: other_pool_account,
+            output_token_account: other_token_account,
+            token_program
+        };
+        Self::gather_page(
+            program_id,
+            pool_account.key,
+            &mut pool,
+            clock.unix_timestamp,
+            reserves,
+            &delivery_accounts,
+            &mut account_info_iter.peekable()
+        )?;
+
+        if pool.is_cursor_done() {
+            msg!("Gathered {} for round {}", pool.round_input, pool.round_id);
+            pool.round_state = RoundState::Gathered;
+        }
+
+        Pool::pack(pool, &mut pool_account.data.borrow_mut())?;
+        Pool::pack(other_pool, &mut other_pool_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    /// Walks the user and stream slots of a pool from its cursor, taking the accounts of the used
+    /// slots in order, until the accounts run out. Settles the input of streams in the last settled
+    /// round and debits every due stream buying the rounds output mint whose limit price is met
+    /// by the swap `reserves` of the pools mint and the output mint. Limit prices are checked again
+    /// when the round is executed. Streams of users with auto-delivery take the account their
+    /// output is delivered to after their own, when the next account is the owners associated
+    /// token account of the output mint.
+    fn gather_page<'a, 'b: 'a, I: Iterator<Item = &'a AccountInfo<'b>>>(
+        program_id: &Pubkey,
+        pool_key: &Pubkey,
+        pool: &mut Pool,
+        now: UnixTimestamp,
+        reserves: (u64, u64),
+        delivery_accounts: &DeliveryAccounts<'a, 'b>,
+        account_info_iter: &mut Peekable<I>
+    ) -> ProgramResult {
+        'users: while !pool.is_cursor_done() {
+            if !pool.is_user_slot_used(pool.cursor_user) {
+                pool.cursor_user += 1;
+                pool.cursor_stream = 0;
+                continue;
+            }
+
+            let user_info = match account_info_iter.next() {
+                Some(user_info) => user_info,
+                None => break
+            };
+
+            if *user_info.owner != *program_id {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+
+            let mut user_account = UserAccount::unpack(&user_info.data.borrow())?;
+
+            if user_account.pool_pubkey != pool_key.to_bytes() || user_account.user_index != pool.cursor_user {
+                msg!("Error: user account {} is not in slot {}", user_info.key, pool.cursor_user);
+                return Err(StepError::AddressDerivationMismatch.into());
+            }
+
+            while pool.cursor_stream < user_account.stream_count {
+                if !user_account.is_stream_slot_used(pool.cursor_stream) {
+                    pool.cursor_stream += 1;
+                    continue;
+                }
+
+                let stream_info = match account_info_iter.next() {
+                    Some(stream_info) => stream_info,
+                    None => {
+                        UserAccount::pack(user_account, &mut user_info.data.borrow_mut())?;
+                        break 'users;
+                    }
+                };
+
+                if *stream_info.owner != *program_id {
+                    return Err(ProgramError::IncorrectProgramId);
+                }
+
+                let mut stream = Stream::unpack(&stream_info.data.borrow())?;
+
+                if stream.user_pubkey != user_info.key.to_bytes() || stream.stream_index != pool.cursor_stream {
+                    msg!("Error: stream account {} is not in slot {}", stream_info.key, pool.cursor_stream);
+                    return Err(StepError::AddressDerivationMismatch.into());
+                }
+
+                if stream.input_token_pubkey != pool.mint_pubkey {
+                    msg!("Error: stream {} spends a mint other than its pools", stream_info.key);
+                    return Err(StepError::MintMismatch.into());
+                }
+
+                stream.settle_pending(pool, &mut user_account)?;
+
+                // Basket streams execute their windows in any round, their outputs wait for the rounds of their pairs
+                if (stream.is_basket() || stream.output_token_pubkey == pool.round_output_mint) && stream.is_due(now) {
+                    let windows = stream.due_windows(now)?;
+                    let amount = stream.next_input(windows, reserves.0, reserves.1);
+
+                    if stream.is_end_reached(now) {
+                        stream.complete(now);
+                        msg!("Stream {} completed", stream_info.key);
+                    }
+                    else if !stream.is_price_allowed(reserves.0, reserves.1) {
+                        msg!("Skipping stream {}: limit price not met", stream_info.key);
+
+                        if stream.carry_over {
+                            stream.carried_amount = amount;
+                        }
+                        stream.advance_schedule(now)?;
+
+                        if stream.is_end_reached(stream.next_execution_ts) {
+                            stream.complete(now);
+                            msg!("Stream {} completed", stream_info.key);
+                        }
+                    }
+                    else if user_account.available_to(&stream)? < amount {
+                        msg!("Skipping stream {}: underfunded", stream_info.key);
+                        stream.status = StreamStatus::Underfunded;
+                    }
+                    else if !stream.limit_price.map_or(true, |limit| pool.has_room_for_limit(limit.input_per_output(stream.direction))) {
+                        // Left due for the next round
+                        msg!("Skipping stream {}: round holds the most limit prices it can", stream_info.key);
+                    }
+                    else {
+                        user_account.balance = user_account.balance.checked_sub(amount).ok_or(StepError::AmountOverflow)?;
+                        stream.status = StreamStatus::Active;
+                        stream.total_input = stream.total_input.checked_add(amount).ok_or(StepError::AmountOverflow)?;
+                        stream.executions = stream.executions.checked_add(windows).ok_or(StepError::AmountOverflow)?;
+                        stream.carried_amount = 0;
+                        stream.advance_schedule(now)?;
+
+                        if stream.is_basket() {
+                            stream.queue_basket_input(amount)?;
+                        }
+                        else {
+                            stream.pending_round = pool.round_id;
+                            stream.pending_input = amount;
+                            stream.pending_windows = windows;
+                            stream.pending_limit = stream.limit_price;
+
+                            match stream.limit_price {
+                                Some(limit) => pool.gather_limited_input(limit.input_per_output(stream.direction), amount)?,
+                                None => pool.round_input = pool.round_input.checked_add(amount).ok_or(StepError::AmountOverflow)?
+                            }
+                            pool.round_slippage_bps = pool.round_slippage_bps.min(stream.slippage_bps);
+                        }
+
+                        // Complete straight away when the next window would be past an end condition
+                        if stream.is_end_reached(stream.next_execution_ts) {
+                            stream.complete(now);
+                            msg!("Stream {} completed", stream_info.key);
+                        }
+                    }
+                }
+
+                let basket_input = stream.gather_basket_output(&pool.round_output_mint, pool.round_id)?;
+                if basket_input > 0 {
+                    pool.round_input = pool.round_input.checked_add(basket_input).ok_or(StepError::AmountOverflow)?;
+                    pool.round_slippage_bps = pool.round_slippage_bps.min(stream.slippage_bps);
+                }
+
+                // Reserve the next window with what the balance still covers, nothing once completed
+                let commitment = stream.window_commitment().min(user_account.available_to(&stream)?);
+                user_account.recommit(&mut stream, commitment)?;
+
+                // A missing destination leaves the output credited, the next account is then another stream or user
+                if user_account.auto_deliver {
+                    let owner = Pubkey::new(&user_account.owner_pubkey);
+                    let destination_key = pda::find_associated_token_address(&owner, &pool.round_output_mint);
+
+                    if account_info_iter.peek().map_or(false, |destination| *destination.key == destination_key) {
+                        let destination = next_account_info(account_info_iter)?;
+                        Self::deliver_output(
+                            program_id,
+                            &mut stream,
+                            &owner,
+                            &pool.round_output_mint,
+                            delivery_accounts,
+                            destination
+                        )?;
+                    }
+                }
+
+                Stream::pack(stream, &mut stream_info.data.borrow_mut())?;
+                pool.cursor_stream += 1;
+            }
+
+            UserAccount::pack(user_account, &mut user_info.data.borrow_mut())?;
+            pool.cursor_user += 1;
+            pool.cursor_stream = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the reserves of the swap for the input and output mints
+    fn unpack_swap_reserves(
+        token_swap_program_key: &Pubkey,
+        swap_info: &AccountInfo,
+        swap_input_info: &AccountInfo,
+        swap_output_info: &AccountInfo,
+        input_mint: &PubkeyData,
+        output_mint: &PubkeyData
+    ) -> Result<(u64, u64), ProgramError> {
+        if swap_info.owner != token_swap_program_key {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let swap_state = SwapVersion::unpack(&swap_info.data.borrow())?;
+        let swap_token_accounts = [*swap_state.token_a_account(), *swap_state.token_b_account()];
+
+        if swap_input_info.key == swap_output_info.key
+            || !swap_token_accounts.contains(swap_input_info.key)
+            || !swap_token_accounts.contains(swap_output_info.key) {
+            msg!("Error: token accounts do not belong to the swap");
+            return Err(StepError::InvalidRoute.into());
+        }
+
+        let swap_input = spl_token::state::Account::unpack(&swap_input_info.data.borrow())?;
+        let swap_output = spl_token::state::Account::unpack(&swap_output_info.data.borrow())?;
+
+        if swap_input.mint.to_bytes() != *input_mint || swap_output.mint.to_bytes() != *output_mint {
+            msg!("Error: swap does not trade the pools mints");
+            return Err(StepError::InvalidRoute.into());
+        }
+
+        if swap_input.amount == 0 || swap_output.amount == 0 {
+            msg!("Error: swap has no liquidity");
+            return Err(StepError::NoLiquidity.into());
+        }
+
+        Ok((swap_input.amount, swap_output.amount))
+    }
+
+    /// Swaps out of a pools token account and returns the amount received by `destination_info`
+    fn swap<'a, 'b>(
+        swap_accounts: &SwapAccounts<'a, 'b>,
+        source_info: &AccountInfo<'b>,
+        swap_source_info: &AccountInfo<'b>,
+        swap_destination_info: &AccountInfo<'b>,
+        destination_info: &AccountInfo<'b>,
+        signer_seeds: &[&[u8]],
+        swap: spl_token_swap::instruction::Swap
+    ) -> Result<u64, ProgramError> {
+        let host_fee_account: std::option::Option<&Pubkey> = None;
+        let balance_before = spl_token::state::Account::unpack(&destination_info.data.borrow())?.amount;
+        let minimum_amount_out = swap.minimum_amount_out;
+
+        msg!("Swapping {} for at least {}", swap.amount_in, minimum_amount_out);
+        let swap_ix = spl_token_swap::instruction::swap(
+            swap_accounts.token_swap_program.key,
+            swap_accounts.token_program.key,
+            swap_accounts.swap_info.key,
+            swap_accounts.authority_info.key,
+            source_info.key,
+            source_info.key,
+            swap_source_info.key,
+            swap_destination_info.key,
+            destination_info.key,
+            swap_accounts.pool_mint_info.key,
+            swap_accounts.pool_fee_account_info.key,
+            host_fee_account,
+            swap
+        )?;
+
+        invoke_signed(
+            &swap_ix,
+            &[
+                swap_accounts.token_swap_program.clone(),
+                swap_accounts.token_program.clone(),
+                swap_accounts.swap_info.clone(),
+                swap_accounts.authority_info.clone(),
+                source_info.clone(),
+                swap_source_info.clone(),
+                swap_destination_info.clone(),
+                destination_info.clone(),
+                swap_accounts.pool_mint_info.clone(),
+                swap_accounts.pool_fee_account_info.clone()
+            ],
+            &[signer_seeds]
+        )?;
+
+        let balance_after = spl_token::state::Account::unpack(&destination_info.data.borrow())?.amount;
+        let received = balance_after.checked_sub(balance_before).ok_or(StepError::AmountOverflow)?;
+        if received < minimum_amount_out {
+            msg!("Error: swap returned {} below the minimum of {}", received, minimum_amount_out);
+            return Err(StepError::SlippageExceeded.into());
+        }
+        Ok(received)
+    }
+
+    /// Pays the keeper reward out of the reward vault, as long as the vault stays rent exempt
+    fn pay_keeper_reward<'a>(
+        program_id: &Pubkey,
+        keeper_reward_lamports: u64,
+        keeper: &AccountInfo<'a>,
+        reward_vault: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        rent: &Rent
+    ) -> ProgramResult {
+        let (pda_reward_vault, reward_vault_nonce) = pda::find_reward_vault_address(program_id);
+
+        if pda_reward_vault != *reward_vault.key {
+            msg!("Error: reward vault address derivation mismatch");
+            return Err(StepError::AddressDerivationMismatch.into());
+        }
+
+        if keeper_reward_lamports == 0 {
+            return Ok(());
+        }
+
+        let available = reward_vault.lamports().saturating_sub(rent.minimum_balance(0));
+
+        if available < keeper_reward_lamports {
+            msg!("Reward vault cannot cover the keeper reward, skipping it");
+            return Ok(());
+        }
+
+        if keeper.lamports().checked_add(keeper_reward_lamports).is_none() {
+            msg!("Error: keeper reward overflows the keepers balance");
+            return Err(StepError::AmountOverflow.into());
+        }
+
+        let reward_vault_signer_seeds: &[&[_]] = &[
+            REWARD_VAULT_SEED, &[reward_vault_nonce]
+        ];
+
+        msg!("Paying keeper reward of {} lamports to {}", keeper_reward_lamports, keeper.key);
+        invoke_signed(
+            &system_instruction::transfer(
+                reward_vault.key,
+                keeper.key,
+                keeper_reward_lamports
+            ),
+            &[
+                reward_vault.clone(),
+                keeper.clone(),
+                system_program_info.clone()
+            ],
+            &[&reward_vault_signer_seeds]
+        )
+    }
+
+    /// Checks a pool name fits a seed and survives the zero padding it is stored with
+    fn validate_pool_name(name: &str) -> ProgramResult {
+        if name.is_empty() || name.len() > MAX_POOL_NAME_LEN || name.contains('\0') {
+            msg!("Error: pool name must be 1 to {} bytes without zero bytes", MAX_POOL_NAME_LEN);
+            return Err(StepError::InvalidPoolName.into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks the parameters a stream is created or updated with
+    fn validate_stream_params(params: &StreamParams, now: UnixTimestamp) -> ProgramResult {
+        let StreamParams { schedule, catch_up, strategy, slippage_bps, end_conditions, limit_price, carry_over } = params;
+
+        match *strategy {
+            Strategy::FixedAmount { amount } => {
+                if amount == 0 {
+                    msg!("Error: stream amount must be positive");
+                    return Err(StepError::InvalidAmount.into());
+                }
+            }
+            Strategy::ValueAveraging { target_growth, min_amount, max_amount } => {
+                if target_growth == 0 || max_amount == 0 || min_amount > max_amount {
+                    msg!("Error: value averaging needs a positive target growth and a positive maximum not below the minimum");
+                    return Err(StepError::InvalidStrategy.into());
+                }
+
+                // Missed value is made up by the target, carrying amounts over would count it twice
+                if *carry_over {
+                    msg!("Error: value averaging streams cannot carry amounts over");
+                    return Err(StepError::InvalidStrategy.into());
+                }
+            }
+        }
+
+        if !schedule.is_valid() {
+            msg!("Error: stream schedule is invalid, intervals must be between {} and {} seconds", MIN_INTERVAL_SECONDS, MAX_INTERVAL_SECONDS);
+            return Err(StepError::InvalidSchedule.into());
+        }
+
+        if !catch_up.is_valid() {
+            msg!("Error: stream catch-up must combine between 1 and {} windows", MAX_CATCH_UP_WINDOWS);
+            return Err(StepError::InvalidSchedule.into());
+        }
+
+        if *slippage_bps < MIN_SLIPPAGE_BPS || *slippage_bps > MAX_SLIPPAGE_BPS {
+            msg!("Error: stream slippage must be between {} and {} bps", MIN_SLIPPAGE_BPS, MAX_SLIPPAGE_BPS);
+            return Err(StepError::InvalidSlippage.into());
+        }
+
+        if end_conditions.end_ts.map_or(false, |end_ts| end_ts <= now)
+            || end_conditions.max_executions == Some(0)
+            || end_conditions.max_total_input == Some(0) {
+            msg!("Error: stream end conditions must leave at least one execution");
+            return Err(StepError::InvalidEndConditions.into());
+        }
+
+        if limit_price.map_or(false, |price| price.quote_amount == 0 || price.base_amount == 0) {
+            msg!("Error: stream limit price must be positive");
+            return Err(StepError::InvalidLimitPrice.into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks the outputs of a basket stream and the parameters it is created with
+    fn validate_basket(basket: &[BasketOutput], input_token_pubkey: &PubkeyData, params: &StreamParams) -> ProgramResult {
+        let weighted_mints: Vec<_> = basket.iter().map(|output| (output.mint_pubkey, output.weight_bps)).collect();
+        Self::validate_weighted_mints(&weighted_mints, MAX_BASKET_OUTPUTS)?;
+
+        if basket.iter().any(|output| output.mint_pubkey == *input_token_pubkey) {
+            msg!("Error: basket outputs must differ from the input");
+            return Err(StepError::SameInputAndOutput.into());
+        }
+
+        Self::validate_basket_params(params)
+    }
+
+    /// Checks a set of between 2 and `max_count` distinct mints with positive weights adding up to a whole
+    fn validate_weighted_mints(weighted_mints: &[(PubkeyData, u16)], max_count: usize) -> ProgramResult {
+        if weighted_mints.len() < 2 || weighted_mints.len() > max_count {
+            msg!("Error: between 2 and {} weighted mints are needed", max_count);
+            return Err(StepError::InvalidWeights.into());
+        }
+
+        for (i, (mint_pubkey, weight_bps)) in weighted_mints.iter().enumerate() {
+            if weighted_mints[..i].iter().any(|(other_mint, _)| other_mint == mint_pubkey) {
+                msg!("Error: weighted mints must be distinct");
+                return Err(StepError::InvalidWeights.into());
+            }
+
+            if *weight_bps == 0 {
+                msg!("Error: mint weights must be positive");
+                return Err(StepError::InvalidWeights.into());
+            }
+        }
+
+        if weighted_mints.iter().map(|(_, weight_bps)| *weight_bps as u32).sum::<u32>() != BPS_DENOMINATOR as u32 {
+            msg!("Error: mint weights must add up to {} bps", BPS_DENOMINATOR);
+            return Err(StepError::InvalidWeights.into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks the parameters of a basket stream, whose outputs trade against different prices
+    fn validate_basket_params(params: &StreamParams) -> ProgramResult {
+        if let Strategy::ValueAveraging { .. } = params.strategy {
+            msg!("Error: basket streams spend a fixed amount");
+            return Err(StepError::InvalidStrategy.into());
+        }
+
+        if params.limit_price.is_some() {
+            msg!("Error: basket streams cannot have a limit price");
+            return Err(StepError::InvalidLimitPrice.into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks the signer owns the user account and the stream belongs to it, and returns both
+    fn check_stream_owner(
+        program_id: &Pubkey,
+        owner: &AccountInfo,
+        user_info: &AccountInfo,
+        stream_info: &AccountInfo
+    ) -> Result<(UserAccount, Stream), ProgramError> {
+        if !owner.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if *user_info.owner != *program_id || *stream_info.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let user_account = UserAccount::unpack(&user_info.data.borrow())?;
+
+        if user_account.owner_pubkey != owner.key.to_bytes() {
+            msg!("Error: user account is not owned by the signer");
+            return Err(StepError::InvalidOwner.into());
+        }
+
+        let stream = Stream::unpack(&stream_info.data.borrow())?;
+
+        if stream.user_pubkey != user_info.key.to_bytes() {
+            msg!("Error: stream does not belong to the user account");
+            return Err(StepError::StreamMismatch.into());
+        }
+
+        Ok((user_account, stream))
+    }
+
+    /// Loads the depositors pool user account, creating it in the pools lowest free user slot on
+    /// the first deposit
+    fn load_or_create_user_account<'a>(
+        program_id: &Pubkey,
+        depositor: &AccountInfo<'a>,
+        user_step_pool_account: &AccountInfo<'a>,
+        pool_info_account: &AccountInfo<'a>,
+        pool_state: &mut Pool,
+        system_program_info: &AccountInfo<'a>,
+        rent: &Rent
+    ) -> Result<UserAccount, ProgramError> {
+        if user_step_pool_account.data_is_empty() {
+            // First deposit of this user so give them the lowest free user slot of the pool
+            let user_index = pool_state.allocate_user_slot().ok_or_else(|| {
+                msg!("Error: pool has no free user slot");
+                StepError::NoFreeSlot
+            })?;
+            let (pda_user_account, user_seed_nonce) = pda::find_user_address(pool_info_account.key, user_index, program_id);
+
+            if pda_user_account != *user_step_pool_account.key {
+                msg!("Error: user address derivation mismatch, expected slot {}", user_index);
+                return Err(StepError::AddressDerivationMismatch.into());
+            }
+
+            let user_index_bytes = user_index.to_le_bytes();
+            let user_signer_seeds: &[&[_]] = &[
+                pool_info_account.key.as_ref(), USER_SEED, &user_index_bytes, &[user_seed_nonce]
+            ];
+
+            msg!("Creating user account in slot {}", user_index);
+            Self::create_program_account(
+                program_id,
+                depositor,
+                user_step_pool_account,
+                system_program_info,
+                rent,
+                UserAccount::get_packed_len(),
+                user_signer_seeds
+            )?;
+            Pool::pack(pool_state.clone(), &mut pool_info_account.data.borrow_mut())?;
+
+            Ok(UserAccount::new(depositor.key.to_bytes(), pool_info_account.key.to_bytes(), user_index))
+        }
+        else {
+            if *user_step_pool_account.owner != *program_id {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+
+            let user_account = UserAccount::unpack(&user_step_pool_account.data.borrow())?;
+
+            if user_account.pool_pubkey != pool_info_account.key.to_bytes() {
+                msg!("Error: user account belongs to a different pool");
+                return Err(StepError::PoolMismatch.into());
+            }
+
+            if user_account.owner_pubkey != depositor.key.to_bytes() {
+                msg!("Error: user account is not owned by the depositor");
+                return Err(StepError::InvalidOwner.into());
+            }
+
+            Ok(user_account)
+        }
+    }
+
+    /// Transfers tokens out of a pools token account, signed by the token account itself
+    fn transfer_from_vault<'a>(
+        program_id: &Pubkey,
+        pool_account: &AccountInfo<'a>,
+        vault: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>,
+        amount: u64
+    ) -> ProgramResult {
+        let (pda_vault, vault_nonce) = pda::find_vault_address(pool_account.key, program_id);
+
+        if pda_vault != *vault.key {
+            msg!("Error: program token address derivation mismatch");
+            return Err(StepError::AddressDerivationMismatch.into());
+        }
+
+        // The transfer carries the vaults signature, so only the token program may be invoked
+        if *token_program.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let vault_signer_seeds: &[&[_]] = &[
+            pool_account.key.as_ref(), VAULT_SEED, &[vault_nonce]
+        ];
+
+        invoke_signed(
+            &spl_token::instruction::transfer(
+                token_program.key,
+                vault.key,
+                destination.key,
+                vault.key,
+                &[],
+                amount
+            )?,
+            &[
+                vault.clone(),
+                destination.clone(),
+                token_program.clone()
+            ],
+            &[&vault_signer_seeds]
+        )
+    }
+
+    /// Pays `amount` of wrapped SOL out of a pools token account as lamports. The amount is moved
+    /// into a token account of the program created for it at the expense of `payer`, which is then
+    /// closed back into `payer` before `amount` is passed on to `destination`.
+    fn unwrap_from_vault<'a>(
+        program_id: &Pubkey,
+        pool_account: &AccountInfo<'a>,
+        vault: &AccountInfo<'a>,
+        unwrap_account: &AccountInfo<'a>,
+        native_mint_info: &AccountInfo<'a>,
+        payer: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        rent_sysvar_info: &AccountInfo<'a>,
+        amount: u64
+    ) -> ProgramResult {
+        let rent = &Rent::from_account_info(rent_sysvar_info)?;
+        let (pda_unwrap_account, unwrap_nonce) = pda::find_unwrap_address(pool_account.key, program_id);
+
+        if pda_unwrap_account != *unwrap_account.key {
+            msg!("Error: unwrap token address derivation mismatch");
+            return Err(StepError::AddressDerivationMismatch.into());
+        }
+
+        if *token_program.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let unwrap_signer_seeds: &[&[_]] = &[
+            pool_account.key.as_ref(), UNWRAP_SEED, &[unwrap_nonce]
+        ];
+
+        Self::create_pda_account(
+            &spl_token::id(),
+            payer,
+            unwrap_account,
+            system_program_info,
+            rent,
+            spl_token::state::Account::get_packed_len(),
+            unwrap_signer_seeds
+        )?;
+
+        invoke(
+            &spl_token::instruction::initialize_account(
+                token_program.key,
+                unwrap_account.key,
+                native_mint_info.key,
+                unwrap_account.key
+            )?,
+            &[
+                unwrap_account.clone(),
+                native_mint_info.clone(),
+                rent_sysvar_info.clone(),
+                token_program.clone()
+            ]
+        )?;
+
+        Self::transfer_from_vault(program_id, pool_account, vault, unwrap_account, token_program, amount)?;
+
+        // Closing the account hands the payer its rent back along with the unwrapped amount
+        invoke_signed(
+            &spl_token::instruction::close_account(
+                token_program.key,
+                unwrap_account.key,
+                payer.key,
+                unwrap_account.key,
+                &[]
+            )?,
+            &[
+                unwrap_account.clone(),
+                payer.clone(),
+                token_program.clone()
+            ],
+            &[&unwrap_signer_seeds]
+        )?;
+
+        if destination.key != payer.key {
+            invoke(
+                &system_instruction::transfer(payer.key, destination.key, amount),
+                &[
+                    payer.clone(),
+                    destination.clone(),
+                    system_program_info.clone()
+                ]
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks the pool is the one of the user account and returns it
+    fn check_user_pool(
+        program_id: &Pubkey,
+        user_account: &UserAccount,
+        pool_account: &AccountInfo
+    ) -> Result<Pool, ProgramError> {
+        if *pool_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        if user_account.pool_pubkey != pool_account.key.to_bytes() {
+            msg!("Error: user account belongs to a different pool");
+            return Err(StepError::PoolMismatch.into());
+        }
+
+        Pool::unpack(&pool_account.data.borrow())
+    }
+
+    /// Pays the output credited to a stream out of the output pools token accounts. Takes the output
+    /// pool, its token account and the destination for each output of the stream in order, followed
+    /// by the token program.
+    fn pay_out_outputs<'a, 'b: 'a, I: Iterator<Item = &'a AccountInfo<'b>>>(
+        program_id: &Pubkey,
+        stream: &mut Stream,
+        account_info_iter: &mut I
+    ) -> ProgramResult {
+        let output_count = stream.basket.len().max(1);
+        let output_accounts = (0..output_count * 3)
+            .map(|_| next_account_info(account_info_iter))
+            .collect::<Result<Vec<_>, _>>()?;
+        let token_program = next_account_info(account_info_iter)?;
+
+        if !stream.is_basket() {
+            return Self::pay_out_output(
+                program_id,
+                &stream.output_token_pubkey,
+                &mut stream.output_balance,
+                output_accounts[0],
+                output_accounts[1],
+                output_accounts[2],
+                token_program
+            );
+        }
+
+        for (basket_output, accounts) in stream.basket.iter_mut().zip(output_accounts.chunks(3)) {
+            Self::pay_out_output(
+                program_id,
+                &basket_output.mint_pubkey,
+                &mut basket_output.output_balance,
+                accounts[0],
+                accounts[1],
+                accounts[2],
+                token_program
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Pays the output of `mint` credited to a stream to the owners associated token account. The
+    /// output stays credited when the account does not exist.
+    fn deliver_output<'a>(
+        program_id: &Pubkey,
+        stream: &mut Stream,
+        owner: &Pubkey,
+        mint: &PubkeyData,
+        delivery_accounts: &DeliveryAccounts<'_, 'a>,
+        destination: &AccountInfo<'a>
+    ) -> ProgramResult {
+        if *destination.key != pda::find_associated_token_address(owner, mint) {
+            msg!("Error: {} is not the associated token account of the owner", destination.key);
+            return Err(StepError::AddressDerivationMismatch.into());
+        }
+
+        if *destination.owner != spl_token::id() || destination.data_is_empty() {
+            msg!("No associated token account to deliver to, output stays credited");
+            return Ok(());
+        }
+
+        let output_balance = if stream.is_basket() {
+            match stream.basket.iter_mut().find(|basket_output| basket_output.mint_pubkey == *mint) {
+                Some(basket_output) => &mut basket_output.output_balance,
+                None => return Ok(())
+            }
+        }
+        else if stream.output_token_pubkey == *mint {
+            &mut stream.output_balance
+        }
+        else {
+            return Ok(());
+        };
+
+        Self::pay_out_output(
+            program_id,
+            mint,
+            output_balance,
+            delivery_accounts.output_pool_account,
+            delivery_accounts.output_token_account,
+            destination,
+            delivery_accounts.token_program
+        )
+    }
+
+    /// Pays `output_balance` of `output_mint` out of the output pools token account
+    fn pay_out_output<'a>(
+        program_id: &Pubkey,
+        output_mint: &PubkeyData,
+        output_balance: &mut u64,
+        output_pool_account: &AccountInfo<'a>,
+        output_token_account: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>
+    ) -> ProgramResult {
+        if *output_pool_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let output_pool = Pool::unpack(&output_pool_account.data.borrow())?;
+
+        if output_pool.mint_pubkey != *output_mint {
+            msg!("Error: output pool does not hold the streams output mint");
+            return Err(StepError::MintMismatch.into());
+        }
+
+        if *output_balance == 0 {
+            return Ok(());
+        }
+
+        msg!("Paying out {} of output", output_balance);
+        Self::transfer_from_vault(
+            program_id,
+            output_pool_account,
+            output_token_account,
+            destination,
+            token_program,
+            *output_balance
+        )?;
+        *output_balance = 0;
+
+        Ok(())
+    }
+
+    /// Closes an account of the program, moving its rent to `destination`
+    fn close_program_account(account: &AccountInfo, destination: &AccountInfo) -> ProgramResult {
+        let lamports = account.lamports();
+        **destination.lamports.borrow_mut() = destination.lamports().checked_add(lamports).ok_or(StepError::AmountOverflow)?;
+        **account.lamports.borrow_mut() = 0;
+        for byte in account.data.borrow_mut().iter_mut() {
+            *byte = 0;
+        }
+        Ok(())
+    }
+
+    /// Creates an account owned by the program at a PDA, paid for by `payer`
+    fn create_program_account<'a>(
+        program_id: &Pubkey,
+        payer: &AccountInfo<'a>,
+        new_account: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        rent: &Rent,
+        space: usize,
+        signer_seeds: &[&[u8]]
+    ) -> ProgramResult {
+        Self::create_pda_account(program_id, payer, new_account, system_program_info, rent, space, signer_seeds)
+    }
+
+    /// Creates an account owned by `owner` at a PDA, paid for by `payer`. PDAs are predictable, so
+    /// anyone can send lamports to one before it is created, which would make `create_account`
+    /// fail. A funded address is instead topped up to rent exemption, allocated and assigned.
+    fn create_pda_account<'a>(
+        owner: &Pubkey,
+        payer: &AccountInfo<'a>,
+        new_account: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        rent: &Rent,
+        space: usize,
+        signer_seeds: &[&[u8]]
+    ) -> ProgramResult {
+        let required_lamports = 1.max(rent.minimum_balance(space));
+
+        if new_account.lamports() == 0 {
+            return invoke_signed(
+                &system_instruction::create_account(
+                    payer.key,
+                    new_account.key,
+                    required_lamports,
+                    space as u64,
+                    owner
+                ),
+                &[
+                    payer.clone(),
+                    new_account.clone(),
+                    system_program_info.clone()
+                ],
+                &[signer_seeds]
+            );
+        }
+
+        let missing_lamports = required_lamports.saturating_sub(new_account.lamports());
+        if missing_lamports > 0 {
+            invoke(
+                &system_instruction::transfer(payer.key, new_account.key, missing_lamports),
+                &[
+                    payer.clone(),
+                    new_account.clone(),
+                    system_program_info.clone()
+                ]
+            )?;
+        }
+
+        invoke_signed(
+            &system_instruction::allocate(new_account.key, space as u64),
+            &[
+                new_account.clone(),
+                system_program_info.clone()
+            ],
+            &[signer_seeds]
+        )?;
+
+        invoke_signed(
+            &system_instruction::assign(new_account.key, owner),
+            &[
+                new_account.clone(),
+                system_program_info.clone()
+            ],
+            &[signer_seeds]
+        )
+    }
+
+    /// Checks the route account is the registered route of a pair of pools and returns it
+    fn check_route(
+        program_id: &Pubkey,
+        route_account: &AccountInfo,
+        pool_a_key: &Pubkey,
+        pool_b_key: &Pubkey
+    ) -> Result<Route, ProgramError> {
+        if *route_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let (pda_route_account, _) = pda::find_route_address(pool_a_key, pool_b_key, program_id);
+
+        if pda_route_account != *route_account.key {
+            msg!("Error: route address derivation mismatch");
+            return Err(StepError::AddressDerivationMismatch.into());
+        }
+
+        Route::unpack(&route_account.data.borrow())
+    }
+
+    /// Checks the signer is the deployer recorded in the program state and returns the state
+    fn check_deployer(
+        program_id: &Pubkey,
+        deployer: &AccountInfo,
+        program_state_account: &AccountInfo
+    ) -> Result<StepProgramState, ProgramError> {
+        if *program_state_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let program_state = StepProgramState::unpack(&program_state_account.data.borrow())?;
+
+        if !deployer.is_signer || program_state.deployer_pubkey != deployer.key.to_bytes() {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        Ok(program_state)
+    }
+
+    /// Values `amount` of one side of the swap in the other side at the mid-price
+    fn quote(amount: u64, reserve_in: u64, reserve_out: u64) -> Result<u64, ProgramError> {
+        let value = (amount as u128)
+            .checked_mul(reserve_out as u128)
+            .and_then(|value| value.checked_div(reserve_in as u128))
+            .ok_or(StepError::AmountOverflow)?;
+        u64::try_from(value).map_err(|_| StepError::AmountOverflow.into())
+    }
+
+    /// Lowest acceptable swap output for `amount_in`, `slippage_bps` below the mid-price quote
+    fn minimum_amount_out(amount_in: u64, reserve_in: u64, reserve_out: u64, slippage_bps: u16) -> Result<u64, ProgramError> {
+        let tolerance_bps = BPS_DENOMINATOR.checked_sub(slippage_bps).ok_or(StepError::AmountOverflow)?;
+        let minimum = (Self::quote(amount_in, reserve_in, reserve_out)? as u128)
+            .checked_mul(tolerance_bps as u128)
+            .ok_or(StepError::AmountOverflow)?
+            / BPS_DENOMINATOR as u128;
+        u64::try_from(minimum).map_err(|_| StepError::AmountOverflow.into())
+    }
+}
+
+impl PrintProgramError for StepError {
+    fn print<E>(&self)
+    where
+        E: 'static + std::error::Error + DecodeError<E> + PrintProgramError + FromPrimitive,
+    {
+        match self {
+            StepError::InvalidInstruction => msg!("Error: Invalid instruction"),
+            StepError::NotRentExempt => msg!("Error: Lamport balance below rent-exempt threshold"),
+            StepError::ExpectedAmountMismatch => msg!("Error: Expected amount mismatch"),
+            StepError::AmountOverflow => msg!("Error: Amount overflow"),
+            StepError::AddressDerivationMismatch => msg!("Error: Account is not at the address derived from its seeds"),
+            StepError::InvalidOwner => msg!("Error: Account is not owned by the signer"),
+            StepError::PoolMismatch => msg!("Error: User account belongs to a different pool"),
+            StepError::StreamMismatch => msg!("Error: Stream belongs to a different user account"),
+            StepError::MintMismatch => msg!("Error: Account holds a different mint than expected"),
+            StepError::NotNativeMint => msg!("Error: Pool does not hold wrapped SOL"),
+            StepError::SameInputAndOutput => msg!("Error: Input and output are the same mint"),
+            StepError::NoFreeSlot => msg!("Error: No free slot left"),
+            StepError::NotDue => msg!("Error: Nothing is due yet"),
+            StepError::StreamNotActive => msg!("Error: Stream is not active"),
+            StepError::StreamNotPaused => msg!("Error: Stream is not paused"),
+            StepError::StreamCompleted => msg!("Error: Stream is completed"),
+            StepError::RoundPending => msg!("Error: Stream has input in a round which is not executed yet"),
+            StepError::RoundNotGathered => msg!("Error: Pools have not gathered a round with each other"),
+            StepError::InsufficientBalance => msg!("Error: Insufficient balance"),
+            StepError::SlippageExceeded => msg!("Error: Swap output below the minimum amount out"),
+            StepError::InvalidRoute => msg!("Error: Swap is not the registered route of the pools"),
+            StepError::NoLiquidity => msg!("Error: Swap has no liquidity"),
+            StepError::InvalidAmount => msg!("Error: Amount must be positive"),
+            StepError::InvalidSchedule => msg!("Error: Invalid schedule"),
+            StepError::InvalidSlippage => msg!("Error: Slippage out of bounds"),
+            StepError::InvalidLimitPrice => msg!("Error: Invalid limit price"),
+            StepError::InvalidStrategy => msg!("Error: Invalid strategy"),
+            StepError::InvalidEndConditions => msg!("Error: End conditions leave no execution"),
+            StepError::InvalidWeights => msg!("Error: Invalid mint weights"),
+            StepError::InvalidPoolName => msg!("Error: Invalid pool name"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn overflow() -> ProgramError {
+        StepError::AmountOverflow.into()
+    }
+
+    #[test]
+    fn test_quote_at_u64_max() {
+        assert_eq!(Processor::quote(u64::MAX, u64::MAX, u64::MAX), Ok(u64::MAX));
+        assert_eq!(Processor::quote(u64::MAX, 2, 1), Ok(u64::MAX / 2));
+        assert_eq!(Processor::quote(u64::MAX, 1, u64::MAX), Err(overflow()));
+        assert_eq!(Processor::quote(u64::MAX / 2 + 1, 1, 2), Err(overflow()));
+        assert_eq!(Processor::quote(u64::MAX / 2, 1, 2), Ok(u64::MAX - 1));
+        assert_eq!(Processor::quote(1, 0, 1), Err(overflow()));
+    }
+
+    #[test]
+    fn test_minimum_amount_out_at_u64_max() {
+        assert_eq!(Processor::minimum_amount_out(u64::MAX, 1, 1, 0), Ok(u64::MAX));
+        assert_eq!(
+            Processor::minimum_amount_out(u64::MAX, 1, 1, 100),
+            Ok((u64::MAX as u128 * 9_900 / 10_000) as u64)
+        );
+        assert_eq!(Processor::minimum_amount_out(u64::MAX, 1, 2, 0), Err(overflow()));
+        assert_eq!(Processor::minimum_amount_out(1, 1, 1, BPS_DENOMINATOR + 1), Err(overflow()));
+    }
+
+    #[test]
+    fn test_validate_pool_name() {
+        let invalid_pool_name = Err(StepError::InvalidPoolName.into());
+        assert_eq!(Processor::validate_pool_name("sol"), Ok(()));
+        assert_eq!(Processor::validate_pool_name(&"x".repeat(MAX_POOL_NAME_LEN)), Ok(()));
+        assert_eq!(Processor::validate_pool_name(&"x".repeat(MAX_POOL_NAME_LEN + 1)), invalid_pool_name);
+        assert_eq!(Processor::validate_pool_name(""), invalid_pool_name);
+        assert_eq!(Processor::validate_pool_name("sol\0"), invalid_pool_name);
+
+        let pool = Pool::new([1; 32], "sol");
+        assert_eq!(pool.name(), "sol");
+        assert_eq!(Pool::new([1; 32], &"x".repeat(MAX_POOL_NAME_LEN)).name(), "x".repeat(MAX_POOL_NAME_LEN));
+    }
 }
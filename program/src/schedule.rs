@@ -0,0 +1,313 @@
+use borsh::{BorshDeserialize, BorshSchema, BorshSerialize};
+use solana_program::clock::UnixTimestamp;
+
+pub const SECONDS_PER_HOUR: UnixTimestamp = 60 * 60;
+pub const SECONDS_PER_DAY: UnixTimestamp = 24 * SECONDS_PER_HOUR;
+
+/// Shortest interval a stream can be executed at
+pub const MIN_INTERVAL_SECONDS: UnixTimestamp = 60;
+
+/// Longest interval a stream can be executed at
+pub const MAX_INTERVAL_SECONDS: UnixTimestamp = 365 * SECONDS_PER_DAY;
+
+/// Most missed windows a stream can combine into one catch-up execution
+pub const MAX_CATCH_UP_WINDOWS: u32 = 64;
+
+/// When a stream is executed. Calendar schedules are in UTC and computed from the
+/// `unix_timestamp` of the Clock sysvar.
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub enum Schedule {
+    /// Every `interval_seconds` from the first execution
+    Interval { interval_seconds: UnixTimestamp },
+    /// Every day at `hour`
+    Daily { hour: u8 },
+    /// Every week on `weekday` at `hour`, weekdays counting from 0 for Monday
+    Weekly { weekday: u8, hour: u8 },
+    /// Every month on `day` at `hour`, on the last day of months shorter than `day`
+    Monthly { day: u8, hour: u8 }
+}
+
+impl Schedule {
+    pub const LEN: usize = 1 + 8;
+
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Schedule::Interval { interval_seconds } => (MIN_INTERVAL_SECONDS..=MAX_INTERVAL_SECONDS).contains(&interval_seconds),
+            Schedule::Daily { hour } => hour < 24,
+            Schedule::Weekly { weekday, hour } => weekday < 7 && hour < 24,
+            Schedule::Monthly { day, hour } => (1..=31).contains(&day) && hour < 24
+        }
+    }
+
+    /// First execution time of the schedule at or after `ts`. Interval schedules start at `ts`.
+    pub fn first_at_or_after(&self, ts: UnixTimestamp) -> UnixTimestamp {
+        let day = ts.div_euclid(SECONDS_PER_DAY);
+
+        match *self {
+            Schedule::Interval { .. } => ts,
+            Schedule::Daily { hour } => {
+                let candidate = day * SECONDS_PER_DAY + hour as UnixTimestamp * SECONDS_PER_HOUR;
+                if candidate >= ts { candidate } else { candidate + SECONDS_PER_DAY }
+            }
+            Schedule::Weekly { weekday, hour } => {
+                let days_ahead = (weekday as i64 - weekday_from_days(day) as i64).rem_euclid(7);
+                let candidate = (day + days_ahead) * SECONDS_PER_DAY + hour as UnixTimestamp * SECONDS_PER_HOUR;
+                if candidate >= ts { candidate } else { candidate + 7 * SECONDS_PER_DAY }
+            }
+            Schedule::Monthly { day: day_of_month, hour } => {
+                let (year, month, _) = civil_from_days(day);
+                let candidate = monthly_ts(year, month, day_of_month, hour);
+                if candidate >= ts {
+                    candidate
+                } else if month == 12 {
+                    monthly_ts(year + 1, 1, day_of_month, hour)
+                } else {
+                    monthly_ts(year, month + 1, day_of_month, hour)
+                }
+            }
+        }
+    }
+
+    /// Execution time following an execution scheduled at `ts`, none if it overflows
+    pub fn next_after(&self, ts: UnixTimestamp) -> Option<UnixTimestamp> {
+        match *self {
+            Schedule::Interval { interval_seconds } => ts.checked_add(interval_seconds),
+            _ => Some(self.first_at_or_after(ts.checked_add(1)?))
+        }
+    }
+
+    /// Execution time following an execution scheduled at `ts`, skipping the windows up to `now`
+    pub fn next_after_skipping(&self, ts: UnixTimestamp, now: UnixTimestamp) -> Option<UnixTimestamp> {
+        match *self {
+            Schedule::Interval { interval_seconds } if now >= ts => {
+                let skipped = (now.checked_sub(ts)? / interval_seconds).checked_add(1)?;
+                ts.checked_add(skipped.checked_mul(interval_seconds)?)
+            }
+            _ => self.next_after(ts.max(now))
+        }
+    }
+
+    /// Number of windows from the one scheduled at `ts` up to `now`, counting at most `max`
+    pub fn windows_until(&self, ts: UnixTimestamp, now: UnixTimestamp, max: u64) -> u64 {
+        if now < ts {
+            return 0;
+        }
+
+        match *self {
+            Schedule::Interval { interval_seconds } => ((now - ts) / interval_seconds + 1).min(max as i64) as u64,
+            _ => {
+                let mut windows = 0;
+                let mut window_ts = ts;
+                while windows < max && window_ts <= now {
+                    windows += 1;
+                    window_ts = match self.next_after(window_ts) {
+                        Some(next_ts) => next_ts,
+                        None => break
+                    };
+                }
+                windows
+            }
+        }
+    }
+}
+
+/// What a stream does with the windows it missed while no round was executed
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub enum CatchUpPolicy {
+    /// Execute once for the latest window and skip the ones missed before it
+    SkipMissed,
+    /// Execute the missed windows in one combined execution, skipping the ones past `max_windows`
+    Combined { max_windows: u32 },
+    /// Execute one missed window per round until the stream is back on schedule
+    OneWindowPerCrank
+}
+
+impl CatchUpPolicy {
+    pub const LEN: usize = 1 + 4;
+
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            CatchUpPolicy::Combined { max_windows } => max_windows > 0 && max_windows <= MAX_CATCH_UP_WINDOWS,
+            _ => true
+        }
+    }
+}
+
+/// Time of `day_of_month` at `hour` in a month, clamped to the months last day
+fn monthly_ts(year: i64, month: u32, day_of_month: u8, hour: u8) -> UnixTimestamp {
+    let day = (day_of_month as u32).min(days_in_month(year, month));
+    days_from_civil(year, month, day) * SECONDS_PER_DAY + hour as UnixTimestamp * SECONDS_PER_HOUR
+}
+
+pub fn is_leap_year(year: i64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+pub fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    }
+}
+
+/// Weekday of a day since the unix epoch, counting from 0 for Monday. The epoch was a Thursday.
+pub fn weekday_from_days(days: i64) -> u32 {
+    (days + 3).rem_euclid(7) as u32
+}
+
+/// Days since the unix epoch of a proleptic Gregorian date
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Proleptic Gregorian date of a day since the unix epoch, as (year, month, day)
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = (if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 }) as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ts(year: i64, month: u32, day: u32, hour: i64) -> UnixTimestamp {
+        days_from_civil(year, month, day) * SECONDS_PER_DAY + hour * SECONDS_PER_HOUR
+    }
+
+    #[test]
+    fn test_civil_round_trip() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+
+        for days in -800_000..800_000 {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn test_leap_years() {
+        assert!(is_leap_year(2000));
+        assert!(is_leap_year(2024));
+        assert!(!is_leap_year(1900));
+        assert!(!is_leap_year(2023));
+        assert!(!is_leap_year(2100));
+        assert_eq!(days_in_month(2024, 2), 29);
+        assert_eq!(days_in_month(2100, 2), 28);
+        assert_eq!(civil_from_days(days_from_civil(2024, 2, 28) + 1), (2024, 2, 29));
+        assert_eq!(civil_from_days(days_from_civil(2023, 2, 28) + 1), (2023, 3, 1));
+    }
+
+    #[test]
+    fn test_weekday() {
+        assert_eq!(weekday_from_days(0), 3);
+        assert_eq!(weekday_from_days(days_from_civil(2024, 2, 29)), 3);
+        assert_eq!(weekday_from_days(days_from_civil(2021, 3, 1)), 0);
+    }
+
+    #[test]
+    fn test_interval() {
+        let schedule = Schedule::Interval { interval_seconds: 90 };
+        assert!(schedule.is_valid());
+        assert!(!Schedule::Interval { interval_seconds: MIN_INTERVAL_SECONDS - 1 }.is_valid());
+        assert!(Schedule::Interval { interval_seconds: MAX_INTERVAL_SECONDS }.is_valid());
+        assert!(!Schedule::Interval { interval_seconds: MAX_INTERVAL_SECONDS + 1 }.is_valid());
+        assert_eq!(schedule.first_at_or_after(1_000), 1_000);
+        assert_eq!(schedule.next_after(1_000), Some(1_090));
+        assert_eq!(schedule.next_after(UnixTimestamp::MAX - 10), None);
+        assert_eq!(schedule.next_after_skipping(UnixTimestamp::MAX - 100, UnixTimestamp::MAX - 5), None);
+    }
+
+    #[test]
+    fn test_daily() {
+        let schedule = Schedule::Daily { hour: 14 };
+        assert_eq!(schedule.first_at_or_after(ts(2024, 2, 28, 9)), ts(2024, 2, 28, 14));
+        assert_eq!(schedule.first_at_or_after(ts(2024, 2, 28, 14)), ts(2024, 2, 28, 14));
+        assert_eq!(schedule.next_after(ts(2024, 2, 28, 14)), Some(ts(2024, 2, 29, 14)));
+        assert_eq!(schedule.next_after(ts(2023, 12, 31, 14)), Some(ts(2024, 1, 1, 14)));
+        assert!(!Schedule::Daily { hour: 24 }.is_valid());
+    }
+
+    #[test]
+    fn test_weekly() {
+        // Mondays at 08:00, 2024-02-26 is a Monday
+        let schedule = Schedule::Weekly { weekday: 0, hour: 8 };
+        assert_eq!(schedule.first_at_or_after(ts(2024, 2, 26, 8)), ts(2024, 2, 26, 8));
+        assert_eq!(schedule.first_at_or_after(ts(2024, 2, 26, 9)), ts(2024, 3, 4, 8));
+        assert_eq!(schedule.first_at_or_after(ts(2024, 2, 29, 0)), ts(2024, 3, 4, 8));
+        assert_eq!(schedule.next_after(ts(2024, 2, 26, 8)), Some(ts(2024, 3, 4, 8)));
+        assert!(!Schedule::Weekly { weekday: 7, hour: 0 }.is_valid());
+    }
+
+    #[test]
+    fn test_monthly_clamps_to_end_of_month() {
+        let schedule = Schedule::Monthly { day: 31, hour: 0 };
+        assert_eq!(schedule.next_after(ts(2024, 1, 31, 0)), Some(ts(2024, 2, 29, 0)));
+        assert_eq!(schedule.next_after(ts(2024, 2, 29, 0)), Some(ts(2024, 3, 31, 0)));
+        assert_eq!(schedule.next_after(ts(2023, 1, 31, 0)), Some(ts(2023, 2, 28, 0)));
+        assert_eq!(schedule.next_after(ts(2100, 1, 31, 0)), Some(ts(2100, 2, 28, 0)));
+        assert_eq!(schedule.next_after(ts(2000, 1, 31, 0)), Some(ts(2000, 2, 29, 0)));
+        assert_eq!(schedule.next_after(ts(2024, 4, 30, 0)), Some(ts(2024, 5, 31, 0)));
+        assert_eq!(schedule.next_after(ts(2024, 12, 31, 0)), Some(ts(2025, 1, 31, 0)));
+
+        let schedule = Schedule::Monthly { day: 29, hour: 12 };
+        assert_eq!(schedule.first_at_or_after(ts(2023, 2, 1, 0)), ts(2023, 2, 28, 12));
+        assert_eq!(schedule.next_after(ts(2023, 2, 28, 12)), Some(ts(2023, 3, 29, 12)));
+        assert_eq!(schedule.first_at_or_after(ts(2024, 2, 29, 13)), ts(2024, 3, 29, 12));
+        assert!(!Schedule::Monthly { day: 0, hour: 0 }.is_valid());
+        assert!(!Schedule::Monthly { day: 32, hour: 0 }.is_valid());
+    }
+
+    #[test]
+    fn test_skipping_missed_windows() {
+        let schedule = Schedule::Interval { interval_seconds: 100 };
+        assert_eq!(schedule.next_after_skipping(1_000, 999), Some(1_100));
+        assert_eq!(schedule.next_after_skipping(1_000, 1_000), Some(1_100));
+        assert_eq!(schedule.next_after_skipping(1_000, 1_250), Some(1_300));
+        assert_eq!(schedule.next_after_skipping(1_000, 1_300), Some(1_400));
+
+        let schedule = Schedule::Monthly { day: 31, hour: 0 };
+        assert_eq!(schedule.next_after_skipping(ts(2024, 1, 31, 0), ts(2024, 4, 2, 0)), Some(ts(2024, 4, 30, 0)));
+        assert_eq!(schedule.next_after_skipping(ts(2024, 1, 31, 0), ts(2024, 1, 31, 0)), Some(ts(2024, 2, 29, 0)));
+    }
+
+    #[test]
+    fn test_windows_until() {
+        let schedule = Schedule::Interval { interval_seconds: 100 };
+        assert_eq!(schedule.windows_until(1_000, 999, 10), 0);
+        assert_eq!(schedule.windows_until(1_000, 1_000, 10), 1);
+        assert_eq!(schedule.windows_until(1_000, 1_299, 10), 3);
+        assert_eq!(schedule.windows_until(1_000, 1_300, 10), 4);
+        assert_eq!(schedule.windows_until(1_000, 1_000_000, 10), 10);
+
+        let schedule = Schedule::Daily { hour: 0 };
+        assert_eq!(schedule.windows_until(ts(2024, 2, 27, 0), ts(2024, 3, 1, 12), 10), 4);
+        assert_eq!(schedule.windows_until(ts(2024, 2, 27, 0), ts(2024, 3, 1, 12), 2), 2);
+    }
+
+    #[test]
+    fn test_catch_up_policy() {
+        assert!(CatchUpPolicy::SkipMissed.is_valid());
+        assert!(CatchUpPolicy::OneWindowPerCrank.is_valid());
+        assert!(CatchUpPolicy::Combined { max_windows: MAX_CATCH_UP_WINDOWS }.is_valid());
+        assert!(!CatchUpPolicy::Combined { max_windows: 0 }.is_valid());
+        assert!(!CatchUpPolicy::Combined { max_windows: MAX_CATCH_UP_WINDOWS + 1 }.is_valid());
+    }
+}
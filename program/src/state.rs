@@ -7,15 +7,71 @@ use solana_program::{
     msg
 };
 
-use std::mem::size_of;
+use std::mem::{replace, size_of};
+
+use crate::error::StepError;
+use crate::schedule::{CatchUpPolicy, Schedule};
 
 // Wrapper for Pubkey for use with Borsh
 pub type PubkeyData = [u8; 32];
 
+/// Placeholder for an unset pubkey
+pub const NULL_PUBKEY: PubkeyData = [0; size_of::<PubkeyData>()];
+
+/// Bounds of the slippage a stream tolerates on the part of an execution swapped through the AMM
+pub const MIN_SLIPPAGE_BPS: u16 = 10;
+pub const MAX_SLIPPAGE_BPS: u16 = 1_000;
+
+/// Basis points in a whole
+pub const BPS_DENOMINATOR: u16 = 10_000;
+
+/// Number of user slots of a pool
+pub const MAX_USERS_PER_POOL: u32 = 1024;
+
+/// Number of stream slots of a user
+pub const MAX_STREAMS_PER_USER: u32 = 64;
+
+/// Number of output mints a basket stream can split its input across
+pub const MAX_BASKET_OUTPUTS: usize = 8;
+
+/// Number of mints a rebalancing stream can hold
+pub const MAX_REBALANCE_TARGETS: usize = 8;
+
+/// Number of distinct limit prices the streams gathered in a round can have
+pub const MAX_ROUND_LIMITS: usize = 4;
+
+const USER_SLOT_WORDS: usize = MAX_USERS_PER_POOL as usize / 64;
+
+/// Whether a slot is taken in a bitmap of slots
+fn is_slot_used(slots: &[u64], index: u32) -> bool {
+    slots.get(index as usize / 64).map_or(false, |word| word & (1 << (index % 64)) != 0)
+}
+
+/// Takes the lowest free slot of a bitmap of slots
+fn allocate_slot(slots: &mut [u64]) -> Option<u32> {
+    for (i, word) in slots.iter_mut().enumerate() {
+        if *word != u64::MAX {
+            let bit = (!*word).trailing_zeros();
+            *word |= 1 << bit;
+            return Some(i as u32 * 64 + bit);
+        }
+    }
+    None
+}
+
+/// Releases a slot of a bitmap of slots so it can be reused
+fn free_slot(slots: &mut [u64], index: u32) {
+    if let Some(word) = slots.get_mut(index as usize / 64) {
+        *word &= !(1 << (index % 64));
+    }
+}
+
 #[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
 pub struct StepProgramState {
     pub is_initialized: bool,
-    pub deployer_pubkey: [u8; 32]
+    pub deployer_pubkey: [u8; 32],
+    /// Lamports paid from the reward vault to whoever cranks an execution that trades
+    pub keeper_reward_lamports: u64
 }
 
 impl Sealed for StepProgramState {}
@@ -27,7 +83,7 @@ impl IsInitialized for StepProgramState {
 }
 
 impl Pack for StepProgramState {
-    const LEN: usize = 33;
+    const LEN: usize = (1 + 32 + 8);
 
     fn pack_into_slice(&self, dst: &mut [u8]) {
         let data = self.try_to_vec().unwrap();
@@ -46,35 +102,491 @@ impl Pack for StepProgramState {
     }
 }
 
+/// Whether a stream takes part in executions
+#[derive(Clone, Copy, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub enum StreamStatus {
+    Active,
+    /// Kept with its history but skipped by executions until resumed
+    Paused,
+    /// One of the streams end conditions was reached, it is never executed again
+    Completed,
+    /// The free balance of the user could not fund the streams last due window. The stream stays
+    /// due and is executed again once the balance is topped up.
+    Underfunded
+}
+
+/// Which way a stream trades its pair. Buy streams spend a quote asset such as a stablecoin to
+/// accumulate the base asset, sell streams scale out of a base asset position into the quote asset.
+/// The input pool always holds the deposit, the direction only decides which mint is the base.
+#[derive(Clone, Copy, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub enum StreamDirection {
+    Buy,
+    Sell
+}
+
+/// How a stream sizes the input of each window
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub enum Strategy {
+    /// Spends the same amount every window
+    FixedAmount { amount: u64 },
+    /// Spends what brings the value of the output accumulated so far, at the swap price and in
+    /// input tokens, up to `target_growth` per executed window. Buys more when the price of the
+    /// output drops and less when it rises, within `min_amount` and `max_amount` per window.
+    ValueAveraging { target_growth: u64, min_amount: u64, max_amount: u64 }
+}
+
+impl Strategy {
+    pub const LEN: usize = 1 + 8 * 3;
+}
+
+/// Optional conditions after which a stream stops executing
+#[derive(Clone, Debug, Default, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct EndConditions {
+    /// Time from which the stream no longer executes
+    pub end_ts: Option<UnixTimestamp>,
+    /// Number of executions after which the stream stops
+    pub max_executions: Option<u64>,
+    /// Total input the stream spends at most, the last execution is reduced to fit
+    pub max_total_input: Option<u64>
+}
+
+impl EndConditions {
+    pub const LEN: usize = (1 + 8) * 3;
+}
+
+/// Price of a pairs base asset in its quote asset, as `quote_amount` for `base_amount`
+#[derive(Clone, Copy, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct Price {
+    pub quote_amount: u64,
+    pub base_amount: u64
+}
+
+impl Price {
+    pub const LEN: usize = 8 + 8;
+
+    /// Whether the swap reserves of the input and output mints are within this price, as a
+    /// ceiling when buying and a floor when selling
+    pub fn allows(&self, direction: StreamDirection, input_reserve: u64, output_reserve: u64) -> bool {
+        // Compare quote_reserve / base_reserve against quote_amount / base_amount
+        let (base_reserve, quote_reserve) = match direction {
+            StreamDirection::Buy => (output_reserve, input_reserve),
+            StreamDirection::Sell => (input_reserve, output_reserve)
+        };
+        let price = quote_reserve as u128 * self.base_amount as u128;
+        let limit = self.quote_amount as u128 * base_reserve as u128;
+
+        match direction {
+            StreamDirection::Buy => price <= limit,
+            StreamDirection::Sell => price >= limit
+        }
+    }
+
+    /// Most input the price gives for an amount of output, as `(input_amount, output_amount)`
+    pub fn input_per_output(&self, direction: StreamDirection) -> (u64, u64) {
+        match direction {
+            StreamDirection::Buy => (self.quote_amount, self.base_amount),
+            StreamDirection::Sell => (self.base_amount, self.quote_amount)
+        }
+    }
+}
+
+/// Input gathered into a round from the streams sharing a limit price. It is only swapped when the
+/// swap reserves are still within the limit as the round is executed.
+#[derive(Clone, Copy, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct RoundLimit {
+    /// Most input the streams give for `output_amount` of output
+    pub input_amount: u64,
+    pub output_amount: u64,
+    pub input: u64,
+    /// Whether the limit was met when the round was executed
+    pub is_met: bool
+}
+
+impl RoundLimit {
+    pub const LEN: usize = 8 + 8 + 8 + 1;
+
+    fn is_limit(&self, (input_amount, output_amount): (u64, u64)) -> bool {
+        self.input_amount as u128 * output_amount as u128 == input_amount as u128 * self.output_amount as u128
+    }
+
+    /// Whether the swap reserves of the input and output mints are within the limit
+    pub fn allows(&self, input_reserve: u64, output_reserve: u64) -> bool {
+        input_reserve as u128 * self.output_amount as u128 <= self.input_amount as u128 * output_reserve as u128
+    }
+}
+
+/// One output mint of a basket stream, bought with its weight of every window in the rounds of
+/// the pair of the streams input mint and this mint
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct BasketOutput {
+    pub mint_pubkey: PubkeyData,
+    pub weight_bps: u16,
+    /// Input debited for this output waiting for a round of its pair
+    pub queued_input: u64,
+    /// Round in which `pending_input` was gathered
+    pub pending_round: u64,
+    /// Input gathered in `pending_round` whose output is not credited yet
+    pub pending_input: u64,
+    pub total_input: u64,
+    pub total_output: u64,
+    /// Output credited that has not been claimed yet
+    pub output_balance: u64
+}
+
+impl BasketOutput {
+    pub const LEN: usize = 32 + 2 + 8 * 6;
+
+    pub fn new(mint_pubkey: PubkeyData, weight_bps: u16) -> Self {
+        Self {
+            mint_pubkey,
+            weight_bps,
+            queued_input: 0,
+            pending_round: 0,
+            pending_input: 0,
+            total_input: 0,
+            total_output: 0,
+            output_balance: 0
+        }
+    }
+}
+
+/// Parameters of a stream chosen by its owner, set on creation and changeable with UpdateStream
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct StreamParams {
+    pub schedule: Schedule,
+    pub catch_up: CatchUpPolicy,
+    pub strategy: Strategy,
+    pub slippage_bps: u16,
+    pub end_conditions: EndConditions,
+    /// Ceiling for buys or floor for sells on the swap price the stream executes at
+    pub limit_price: Option<Price>,
+    /// Whether the amount of windows skipped for the limit price is added to the next execution
+    pub carry_over: bool
+}
+
 #[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
 pub struct Stream {
+    pub is_initialized: bool,
+    /// The user account the stream belongs to
+    pub user_pubkey: PubkeyData,
+    /// Slot of the stream in its user account
+    pub stream_index: u32,
+    pub status: StreamStatus,
     pub input_token_pubkey: PubkeyData,
+    /// Output mint, unset for basket streams
     pub output_token_pubkey: PubkeyData,
-    pub next_stream: PubkeyData,
-    pub interval_days: UnixTimestamp,
-    pub amount: u64
+    /// Output mints of a basket stream, empty for streams with a single output
+    pub basket: Vec<BasketOutput>,
+    pub direction: StreamDirection,
+    pub schedule: Schedule,
+    pub catch_up: CatchUpPolicy,
+    pub strategy: Strategy,
+    /// Largest shortfall from the mid-price the stream accepts when swapped through the AMM
+    pub slippage_bps: u16,
+    pub end_conditions: EndConditions,
+    pub limit_price: Option<Price>,
+    pub carry_over: bool,
+    /// Amount of windows skipped for the limit price waiting to be added to the next execution
+    pub carried_amount: u64,
+    /// Part of the user accounts balance reserved for the next window of the stream
+    pub committed_amount: u64,
+    /// Number of windows the stream was executed for
+    pub executions: u64,
+    /// Time at which the stream was completed
+    pub completed_ts: Option<UnixTimestamp>,
+    /// Earliest time at which the stream is due for its next execution
+    pub next_execution_ts: UnixTimestamp,
+    /// Total input spent by the stream across all executions
+    pub total_input: u64,
+    /// Total output credited to the stream across all executions
+    pub total_output: u64,
+    /// Output credited to the stream that has not been claimed yet
+    pub output_balance: u64,
+    /// Round in which `pending_input` was gathered
+    pub pending_round: u64,
+    /// Input gathered in `pending_round` whose output is not credited yet
+    pub pending_input: u64,
+    /// Number of windows `pending_input` was gathered for
+    pub pending_windows: u64,
+    /// Limit price `pending_input` was gathered with, which later updates do not change
+    pub pending_limit: Option<Price>
 }
 
 impl Stream {
-    pub fn new(input_token_pubkey: PubkeyData,
+    pub fn new(user_pubkey: PubkeyData,
+               stream_index: u32,
+               input_token_pubkey: PubkeyData,
                output_token_pubkey: PubkeyData,
-               next_stream: PubkeyData,
-               interval_days: UnixTimestamp,
-               amount: u64) -> Self {
+               direction: StreamDirection,
+               params: StreamParams,
+               next_execution_ts: UnixTimestamp) -> Self {
+        let StreamParams { schedule, catch_up, strategy, slippage_bps, end_conditions, limit_price, carry_over } = params;
+
         Self {
+            is_initialized: true,
+            user_pubkey: user_pubkey,
+            stream_index: stream_index,
+            status: StreamStatus::Active,
             input_token_pubkey: input_token_pubkey,
             output_token_pubkey: output_token_pubkey,
-            next_stream: next_stream,
-            interval_days: interval_days,
-            amount: amount
+            basket: Vec::new(),
+            direction: direction,
+            schedule: schedule,
+            catch_up: catch_up,
+            strategy: strategy,
+            slippage_bps: slippage_bps,
+            end_conditions: end_conditions,
+            limit_price: limit_price,
+            carry_over: carry_over,
+            carried_amount: 0,
+            committed_amount: 0,
+            executions: 0,
+            completed_ts: None,
+            next_execution_ts: next_execution_ts,
+            total_input: 0,
+            total_output: 0,
+            output_balance: 0,
+            pending_round: 0,
+            pending_input: 0,
+            pending_windows: 0,
+            pending_limit: None
         }
     }
+
+    /// Whether the stream should take part in an execution happening at `now`
+    pub fn is_due(&self, now: UnixTimestamp) -> bool {
+        let is_running = self.status == StreamStatus::Active || self.status == StreamStatus::Underfunded;
+        self.is_initialized && is_running && now >= self.next_execution_ts
+    }
+
+    /// Amount a window of the stream is expected to spend, reserved from the users balance while
+    /// the stream is not completed. Value averaging streams reserve their minimum.
+    pub fn window_commitment(&self) -> u64 {
+        if self.status == StreamStatus::Completed {
+            return 0;
+        }
+
+        match self.strategy {
+            Strategy::FixedAmount { amount } => amount,
+            Strategy::ValueAveraging { min_amount, .. } => min_amount
+        }
+    }
+
+    /// Whether an end condition is reached at time `at`
+    pub fn is_end_reached(&self, at: UnixTimestamp) -> bool {
+        let end = &self.end_conditions;
+        end.end_ts.map_or(false, |end_ts| at >= end_ts)
+            || end.max_executions.map_or(false, |max| self.executions >= max)
+            || end.max_total_input.map_or(false, |max| self.total_input >= max)
+    }
+
+    /// Number of windows the stream executes for at `now`, as allowed by its catch-up policy
+    /// and limited to the windows left before an end condition
+    pub fn due_windows(&self, now: UnixTimestamp) -> Result<u64, ProgramError> {
+        let windows = match self.catch_up {
+            CatchUpPolicy::Combined { max_windows } => {
+                let until = match self.end_conditions.end_ts {
+                    Some(end_ts) => now.min(end_ts.checked_sub(1).ok_or(StepError::AmountOverflow)?),
+                    None => now
+                };
+                self.schedule.windows_until(self.next_execution_ts, until, max_windows as u64).max(1)
+            }
+            _ => 1
+        };
+
+        Ok(match self.end_conditions.max_executions {
+            Some(max) => windows.min(max.saturating_sub(self.executions)),
+            None => windows
+        })
+    }
+
+    /// Replaces the parameters of the stream, keeping its history and schedule position
+    pub fn set_params(&mut self, params: StreamParams) {
+        self.schedule = params.schedule;
+        self.catch_up = params.catch_up;
+        self.strategy = params.strategy;
+        self.slippage_bps = params.slippage_bps;
+        self.end_conditions = params.end_conditions;
+        self.limit_price = params.limit_price;
+        self.carry_over = params.carry_over;
+
+        if !self.carry_over {
+            self.carried_amount = 0;
+        }
+    }
+
+    /// Whether the swap reserves of the input and output mints satisfy the streams limit price
+    pub fn is_price_allowed(&self, input_reserve: u64, output_reserve: u64) -> bool {
+        self.limit_price.map_or(true, |limit| limit.allows(self.direction, input_reserve, output_reserve))
+    }
+
+    /// Input of the next execution for `windows` windows and any carried over amount, reduced to
+    /// what is left of the input budget. Value averaging streams value their accumulated output
+    /// with the swap reserves of the input and output mints.
+    pub fn next_input(&self, windows: u64, input_reserve: u64, output_reserve: u64) -> u64 {
+        let amount = match self.strategy {
+            Strategy::FixedAmount { amount } => amount.saturating_mul(windows).saturating_add(self.carried_amount),
+            Strategy::ValueAveraging { target_growth, min_amount, max_amount } => {
+                let target = target_growth as u128 * self.executions.saturating_add(windows) as u128;
+                let value = self.total_output as u128 * input_reserve as u128 / output_reserve as u128;
+                target.saturating_sub(value)
+                    .max(min_amount as u128 * windows as u128)
+                    .min(max_amount as u128 * windows as u128)
+                    .min(u64::MAX as u128) as u64
+            }
+        };
+
+        match self.end_conditions.max_total_input {
+            Some(max) => amount.min(max.saturating_sub(self.total_input)),
+            None => amount
+        }
+    }
+
+    pub fn complete(&mut self, now: UnixTimestamp) {
+        self.status = StreamStatus::Completed;
+        self.completed_ts = Some(now);
+    }
+
+    pub fn is_basket(&self) -> bool {
+        !self.basket.is_empty()
+    }
+
+    /// Whether the stream has input in a round whose output is not known yet
+    pub fn has_unsettled_input(&self) -> bool {
+        self.pending_input > 0 || self.basket.iter().any(|output| output.pending_input > 0)
+    }
+
+    /// Credits the output of the input gathered in an earlier round, once that round is settled.
+    /// Input whose limit price was not met when the round was executed, or of a round that settled
+    /// without swapping any input, goes back to the balance of `user_account`. It is carried over to
+    /// the next execution if the stream carries over.
+    pub fn settle_pending(&mut self, pool: &Pool, user_account: &mut UserAccount) -> Result<(), ProgramError> {
+        let settled_round = match pool.last_settled_round() {
+            Some(settled_round) => settled_round,
+            None => return Ok(())
+        };
+        let share = |input: u64| (input as u128 * pool.settled_output as u128 / pool.settled_input as u128) as u64;
+
+        if self.pending_input > 0 && self.pending_round == settled_round {
+            let is_swapped = pool.settled_input > 0 && match self.pending_limit {
+                Some(limit) => pool.is_settled_limit_met(limit.input_per_output(self.direction)),
+                None => true
+            };
+
+            if is_swapped {
+                let output = share(self.pending_input);
+                self.total_output = self.total_output.checked_add(output).ok_or(StepError::AmountOverflow)?;
+                self.output_balance = self.output_balance.checked_add(output).ok_or(StepError::AmountOverflow)?;
+            }
+            else {
+                user_account.balance = user_account.balance.checked_add(self.pending_input).ok_or(StepError::AmountOverflow)?;
+                self.total_input = self.total_input.checked_sub(self.pending_input).ok_or(StepError::AmountOverflow)?;
+                self.executions = self.executions.checked_sub(self.pending_windows).ok_or(StepError::AmountOverflow)?;
+
+                if self.carry_over && self.status != StreamStatus::Completed {
+                    self.carried_amount = self.carried_amount.checked_add(self.pending_input).ok_or(StepError::AmountOverflow)?;
+                }
+            }
+            self.pending_input = 0;
+            self.pending_windows = 0;
+            self.pending_limit = None;
+        }
+
+        let mut refunded: u64 = 0;
+        for basket_output in self.basket.iter_mut() {
+            if basket_output.pending_input > 0 && basket_output.pending_round == settled_round {
+                if pool.settled_input > 0 {
+                    let output = share(basket_output.pending_input);
+                    basket_output.total_output = basket_output.total_output.checked_add(output).ok_or(StepError::AmountOverflow)?;
+                    basket_output.output_balance = basket_output.output_balance.checked_add(output).ok_or(StepError::AmountOverflow)?;
+                }
+                else {
+                    basket_output.total_input = basket_output.total_input.checked_sub(basket_output.pending_input).ok_or(StepError::AmountOverflow)?;
+                    refunded = refunded.checked_add(basket_output.pending_input).ok_or(StepError::AmountOverflow)?;
+                }
+                basket_output.pending_input = 0;
+            }
+        }
+
+        if refunded > 0 {
+            user_account.balance = user_account.balance.checked_add(refunded).ok_or(StepError::AmountOverflow)?;
+            self.total_input = self.total_input.checked_sub(refunded).ok_or(StepError::AmountOverflow)?;
+        }
+
+        Ok(())
+    }
+
+    /// Splits the input of a basket stream execution across its outputs by weight, the rounding
+    /// remainder going to the first output
+    pub fn queue_basket_input(&mut self, amount: u64) -> Result<(), ProgramError> {
+        let mut remainder = amount;
+        for basket_output in self.basket.iter_mut() {
+            let slice = (amount as u128 * basket_output.weight_bps as u128 / BPS_DENOMINATOR as u128) as u64;
+            basket_output.queued_input = basket_output.queued_input.checked_add(slice).ok_or(StepError::AmountOverflow)?;
+            remainder = remainder.checked_sub(slice).ok_or(StepError::AmountOverflow)?;
+        }
+
+        if let Some(first) = self.basket.first_mut() {
+            first.queued_input = first.queued_input.checked_add(remainder).ok_or(StepError::AmountOverflow)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the queued input of the basket output buying `mint` into round `round_id`, and
+    /// returns the amount moved
+    pub fn gather_basket_output(&mut self, mint: &PubkeyData, round_id: u64) -> Result<u64, ProgramError> {
+        let basket_output = match self.basket.iter_mut().find(|output| output.mint_pubkey == *mint) {
+            Some(basket_output) if basket_output.pending_input == 0 => basket_output,
+            _ => return Ok(0)
+        };
+
+        let input = basket_output.queued_input;
+        if input > 0 {
+            basket_output.total_input = basket_output.total_input.checked_add(input).ok_or(StepError::AmountOverflow)?;
+            basket_output.queued_input = 0;
+            basket_output.pending_round = round_id;
+            basket_output.pending_input = input;
+        }
+
+        Ok(input)
+    }
+
+    /// Takes back the input of a basket stream still waiting for the rounds of its pairs
+    pub fn take_queued_input(&mut self) -> u64 {
+        self.basket.iter_mut().map(|output| replace(&mut output.queued_input, 0)).sum()
+    }
+
+    /// Total amounts traded by the stream as (base, quote)
+    pub fn traded_amounts(&self) -> (u64, u64) {
+        match self.direction {
+            StreamDirection::Buy => (self.total_output, self.total_input),
+            StreamDirection::Sell => (self.total_input, self.total_output)
+        }
+    }
+
+    /// Moves the stream past the windows it was just executed for at `now`. Windows missed
+    /// before `now` are left for later rounds only when catching up one window per crank.
+    pub fn advance_schedule(&mut self, now: UnixTimestamp) -> Result<(), ProgramError> {
+        let next_execution_ts = match self.catch_up {
+            CatchUpPolicy::OneWindowPerCrank => self.schedule.next_after(self.next_execution_ts),
+            _ => self.schedule.next_after_skipping(self.next_execution_ts, now)
+        };
+        self.next_execution_ts = next_execution_ts.ok_or(StepError::AmountOverflow)?;
+        Ok(())
+    }
 }
 
 impl Sealed for Stream {}
 
+impl IsInitialized for Stream {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
 impl Pack for Stream {
-    const LEN: usize = 8;
+    const LEN: usize = (1 + 32 + 4 + 1 + 32 + 32 + (4 + MAX_BASKET_OUTPUTS * BasketOutput::LEN) + 1 + Schedule::LEN + CatchUpPolicy::LEN + Strategy::LEN + 2 + EndConditions::LEN +
+        (1 + Price::LEN) + 1 + 8 + 8 + 8 + (1 + 8) + 8 + 8 + 8 + 8 + 8 + 8 + 8 + (1 + Price::LEN));
 
     fn pack_into_slice(&self, dst: &mut [u8]) {
         let data = self.try_to_vec().unwrap();
@@ -93,27 +605,290 @@ impl Pack for Stream {
     }
 }
 
+/// A one-off order spending `total_amount` of a users deposit in equal slices between `start_ts`
+/// and `end_ts`. The input is taken out of the deposit when the order is created.
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct TwapOrder {
+    pub is_initialized: bool,
+    /// The user account the order spends the deposit of
+    pub user_pubkey: PubkeyData,
+    /// The pool of the user account, whose token account the slices are swapped out of
+    pub pool_pubkey: PubkeyData,
+    pub owner_pubkey: PubkeyData,
+    pub order_id: u64,
+    pub input_token_pubkey: PubkeyData,
+    pub output_token_pubkey: PubkeyData,
+    pub direction: StreamDirection,
+    pub total_amount: u64,
+    pub start_ts: UnixTimestamp,
+    pub end_ts: UnixTimestamp,
+    pub slice_count: u32,
+    /// Ceiling for buys or floor for sells on the swap price every slice executes at
+    pub limit_price: Option<Price>,
+    /// Largest shortfall from the mid-price a slice accepts
+    pub slippage_bps: u16,
+    /// Index of the next slice, the slices before it were executed or rolled forward
+    pub next_slice: u32,
+    /// Input swapped so far
+    pub filled_input: u64,
+    pub total_output: u64,
+    /// Output credited to the order that has not been paid out yet
+    pub output_balance: u64
+}
+
+impl TwapOrder {
+    /// Number of slices whose time has come at `now`
+    pub fn elapsed_slices(&self, now: UnixTimestamp) -> Result<u32, ProgramError> {
+        if now < self.start_ts {
+            return Ok(0);
+        }
+
+        // Slice i starts at start_ts + i * duration / slice_count
+        let duration = self.end_ts.checked_sub(self.start_ts).ok_or(StepError::AmountOverflow)? as u128;
+        let elapsed = now.checked_sub(self.start_ts).ok_or(StepError::AmountOverflow)? as u128 + 1;
+        let slices = (elapsed * self.slice_count as u128 + duration - 1) / duration;
+        Ok(slices.min(self.slice_count as u128) as u32)
+    }
+
+    /// Slices and input due at `now`, the input of slices that were not filled rolling into the
+    /// next one. Once every slice has come, what is left of the order stays due.
+    pub fn due_input(&self, now: UnixTimestamp) -> Result<Option<(u32, u64)>, ProgramError> {
+        let elapsed = self.elapsed_slices(now)?;
+        let is_last_unfilled = elapsed == self.slice_count && self.filled_input < self.total_amount;
+
+        if elapsed == 0 || (elapsed <= self.next_slice && !is_last_unfilled) {
+            return Ok(None);
+        }
+
+        let target = (self.total_amount as u128 * elapsed as u128 / self.slice_count as u128) as u64;
+        let input = target.checked_sub(self.filled_input).ok_or(StepError::AmountOverflow)?;
+        Ok(Some((elapsed, input)))
+    }
+
+    pub fn unfilled_input(&self) -> Result<u64, ProgramError> {
+        self.total_amount.checked_sub(self.filled_input).ok_or_else(|| StepError::AmountOverflow.into())
+    }
+}
+
+impl Sealed for TwapOrder {}
+
+impl IsInitialized for TwapOrder {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for TwapOrder {
+    const LEN: usize = (1 + 32 + 32 + 32 + 8 + 32 + 32 + 1 + 8 + 8 + 8 + 4 + (1 + Price::LEN) + 2 + 4 + 8 + 8 + 8);
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let data = self.try_to_vec().unwrap();
+        dst[..data.len()].copy_from_slice(&data);
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let mut mut_src: &[u8] = src;
+        Self::deserialize(&mut mut_src).map_err(|err| {
+            msg!(
+                "Error: failed to deserialize twap order account: {}",
+                err
+            );
+            ProgramError::InvalidAccountData
+        })
+    }
+}
+
+/// Share of one mint in the allocation kept by a rebalancing stream
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct RebalanceTarget {
+    pub mint_pubkey: PubkeyData,
+    pub weight_bps: u16
+}
+
+impl RebalanceTarget {
+    pub const LEN: usize = 32 + 2;
+}
+
+/// Keeps the deposits of a user across the pools of a set of mints at target weights. Holdings
+/// are valued in the first mint through the swaps of the routes between it and the other mints.
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct RebalanceStream {
+    pub is_initialized: bool,
+    /// The user account in the pool of the first mint the stream belongs to
+    pub user_pubkey: PubkeyData,
+    /// Owner of the user accounts whose deposits are rebalanced
+    pub owner_pubkey: PubkeyData,
+    pub schedule: Schedule,
+    /// Earliest time at which the stream is due for its next rebalance
+    pub next_execution_ts: UnixTimestamp,
+    /// Distance of a weight from its target in bps past which the stream trades
+    pub drift_bps: u16,
+    /// Largest value, in the first mint, traded by a single rebalance step
+    pub max_trade_value: u64,
+    /// Largest shortfall from the mid-price accepted when trading through the AMM
+    pub slippage_bps: u16,
+    pub targets: Vec<RebalanceTarget>,
+    /// Number of rebalance steps traded
+    pub rebalances: u64
+}
+
+impl RebalanceStream {
+    pub fn is_due(&self, now: UnixTimestamp) -> bool {
+        self.is_initialized && now >= self.next_execution_ts
+    }
+
+    /// Picks the trade of a rebalance step for holdings worth `values` in the first mint, as the
+    /// most overweight target, the most underweight target and the value to move between them.
+    /// Returns None while every weight is within the drift threshold of its target.
+    pub fn rebalance_step(&self, values: &[u64]) -> Option<(usize, usize, u64)> {
+        let total: u128 = values.iter().map(|value| *value as u128).sum();
+        if total == 0 {
+            return None;
+        }
+
+        // Distance of every holding from its target value, positive when overweight
+        let deviations: Vec<i128> = self.targets
+            .iter()
+            .zip(values)
+            .map(|(target, value)| *value as i128 - (total * target.weight_bps as u128 / BPS_DENOMINATOR as u128) as i128)
+            .collect();
+        let (overweight, excess) = deviations.iter().enumerate().max_by_key(|(_, deviation)| **deviation)?;
+        let (underweight, shortfall) = deviations.iter().enumerate().min_by_key(|(_, deviation)| **deviation)?;
+        let excess = (*excess).max(0) as u128;
+        let shortfall = (-*shortfall).max(0) as u128;
+
+        if excess.max(shortfall) * (BPS_DENOMINATOR as u128) <= total * self.drift_bps as u128 {
+            return None;
+        }
+
+        let trade_value = excess.min(shortfall).min(self.max_trade_value as u128) as u64;
+        if trade_value == 0 {
+            return None;
+        }
+
+        Some((overweight, underweight, trade_value))
+    }
+}
+
+impl Sealed for RebalanceStream {}
+
+impl IsInitialized for RebalanceStream {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for RebalanceStream {
+    const LEN: usize = (1 + 32 + 32 + Schedule::LEN + 8 + 2 + 8 + 2 + (4 + MAX_REBALANCE_TARGETS * RebalanceTarget::LEN) + 8);
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let data = self.try_to_vec().unwrap();
+        dst[..data.len()].copy_from_slice(&data);
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let mut mut_src: &[u8] = src;
+        Self::deserialize(&mut mut_src).map_err(|err| {
+            msg!(
+                "Error: failed to deserialize rebalance stream account: {}",
+                err
+            );
+            ProgramError::InvalidAccountData
+        })
+    }
+}
+
+/// A users deposit in one pool, of the pools mint. Users hold one account in the pool of every
+/// mint they deposit, so each mint is funded and debited separately.
 #[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
 pub struct UserAccount {
+    pub is_initialized: bool,
+    pub owner_pubkey: PubkeyData,
+    pub pool_pubkey: PubkeyData,
+    /// Slot of the user in its pool
+    pub user_index: u32,
     pub balance: u64,
-    pub next_user: PubkeyData,
-    pub head_stream: PubkeyData,
+    /// Part of the balance reserved for the next window of the users streams
+    pub committed: u64,
+    /// Number of stream slots ever used by the user
+    pub stream_count: u32,
+    /// Bitmap of the stream slots currently taken
+    pub stream_slots: u64,
+    /// Number of open TWAP orders, which hold the part of the deposit they have not swapped yet
+    pub order_count: u32,
+    /// Whether settled output is sent to the owners associated token accounts instead of being
+    /// credited to the streams
+    pub auto_deliver: bool,
 }
 
 impl UserAccount {
-    pub fn new() -> Self {
+    pub fn new(owner_pubkey: PubkeyData, pool_pubkey: PubkeyData, user_index: u32) -> Self {
         Self {
+            is_initialized: true,
+            owner_pubkey: owner_pubkey,
+            pool_pubkey: pool_pubkey,
+            user_index: user_index,
             balance: 0,
-            next_user: [0; size_of::<PubkeyData>()],
-            head_stream: [0; size_of::<PubkeyData>()]
+            committed: 0,
+            stream_count: 0,
+            stream_slots: 0,
+            order_count: 0,
+            auto_deliver: false
         }
     }
+
+    /// Balance not reserved for any stream
+    pub fn free_balance(&self) -> u64 {
+        self.balance.saturating_sub(self.committed)
+    }
+
+    /// Balance a stream can spend, which is the free balance and what is reserved for the stream
+    pub fn available_to(&self, stream: &Stream) -> Result<u64, ProgramError> {
+        let committed_to_others = self.committed.checked_sub(stream.committed_amount).ok_or(StepError::AmountOverflow)?;
+        Ok(self.balance.saturating_sub(committed_to_others))
+    }
+
+    /// Replaces what is reserved for the next window of a stream with `amount`
+    pub fn recommit(&mut self, stream: &mut Stream, amount: u64) -> Result<(), ProgramError> {
+        self.committed = self.committed
+            .checked_sub(stream.committed_amount)
+            .and_then(|committed| committed.checked_add(amount))
+            .ok_or(StepError::AmountOverflow)?;
+        stream.committed_amount = amount;
+        Ok(())
+    }
+
+    /// Whether nothing is left in the account or spends it, so it can be closed and its user slot freed
+    pub fn is_closable(&self) -> bool {
+        self.balance == 0 && self.committed == 0 && self.stream_slots == 0 && self.order_count == 0
+    }
+
+    pub fn is_stream_slot_used(&self, index: u32) -> bool {
+        is_slot_used(std::slice::from_ref(&self.stream_slots), index)
+    }
+
+    /// Takes the lowest free stream slot, growing `stream_count` if needed
+    pub fn allocate_stream_slot(&mut self) -> Option<u32> {
+        let index = allocate_slot(std::slice::from_mut(&mut self.stream_slots))?;
+        self.stream_count = self.stream_count.max(index + 1);
+        Some(index)
+    }
+
+    pub fn free_stream_slot(&mut self, index: u32) {
+        free_slot(std::slice::from_mut(&mut self.stream_slots), index);
+    }
 }
 
 impl Sealed for UserAccount {}
 
+impl IsInitialized for UserAccount {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
 impl Pack for UserAccount {
-    const LEN: usize = 8;
+    const LEN: usize = (1 + 32 + 32 + 4 + 8 + 8 + 4 + 8 + 4 + 1);
 
     fn pack_into_slice(&self, dst: &mut [u8]) {
         let data = self.try_to_vec().unwrap();
@@ -132,21 +907,211 @@ impl Pack for UserAccount {
     }
 }
 
+/// Longest name a pool can be created with, in bytes
+pub const MAX_POOL_NAME_LEN: usize = 32;
 
-pub const MAX_SEED_SIZE_BYTES: usize = 32;
+/// Phase of a pools execution round
+#[derive(Clone, Copy, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub enum RoundState {
+    /// No round in progress
+    Idle,
+    /// The pools streams are being gathered page by page
+    Gathering,
+    /// All streams are gathered and the round is waiting to be executed
+    Gathered
+}
 
 #[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
 pub struct Pool {
     pub is_initialized: bool,
     pub mint_pubkey: PubkeyData,
-    pub pda_seed: [u8; MAX_SEED_SIZE_BYTES],
-    pub head_user: PubkeyData
+    /// Name the pool was created with, padded with zeros
+    pub name: [u8; MAX_POOL_NAME_LEN],
+    /// Number of user slots ever used in the pool
+    pub user_count: u32,
+    /// Bitmap of the user slots currently taken
+    pub user_slots: [u64; USER_SLOT_WORDS],
+    /// Id of the round in progress, or of the last round when idle
+    pub round_id: u64,
+    pub round_state: RoundState,
+    /// Mint bought by the streams gathered in the round in progress
+    pub round_output_mint: PubkeyData,
+    /// Slot of the next user to gather in the round in progress
+    pub cursor_user: u32,
+    /// Slot of the next stream of `cursor_user` to gather
+    pub cursor_stream: u32,
+    /// Input gathered so far in the round in progress
+    pub round_input: u64,
+    /// Strictest slippage tolerance of the streams gathered in the round in progress
+    pub round_slippage_bps: u16,
+    /// Input gathered in the last settled round
+    pub settled_input: u64,
+    /// Output credited to the last settled round
+    pub settled_output: u64,
+    /// Least input a round must gather for its execution to pay the keeper reward
+    pub min_reward_input: u64,
+    /// Limit prices of the streams gathered in the round in progress
+    pub round_limits: Vec<RoundLimit>,
+    /// Limit prices of the streams of the last settled round
+    pub settled_limits: Vec<RoundLimit>
+}
+
+impl Pool {
+    pub fn new(mint_pubkey: PubkeyData, name: &str) -> Self {
+        let mut padded_name = [0; MAX_POOL_NAME_LEN];
+        padded_name[..name.len()].copy_from_slice(name.as_bytes());
+
+        Self {
+            is_initialized: true,
+            mint_pubkey: mint_pubkey,
+            name: padded_name,
+            user_count: 0,
+            user_slots: [0; USER_SLOT_WORDS],
+            round_id: 0,
+            round_state: RoundState::Idle,
+            round_output_mint: NULL_PUBKEY,
+            cursor_user: 0,
+            cursor_stream: 0,
+            round_input: 0,
+            round_slippage_bps: BPS_DENOMINATOR,
+            settled_input: 0,
+            settled_output: 0,
+            min_reward_input: 0,
+            round_limits: Vec::new(),
+            settled_limits: Vec::new()
+        }
+    }
+
+    /// Name the pool was created with, without its padding
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|byte| *byte == 0).unwrap_or(MAX_POOL_NAME_LEN);
+        std::str::from_utf8(&self.name[..len]).unwrap_or_default()
+    }
+
+    /// Starts gathering a new round of streams buying `output_mint`
+    pub fn start_round(&mut self, output_mint: PubkeyData) -> Result<(), ProgramError> {
+        self.round_id = self.round_id.checked_add(1).ok_or(StepError::AmountOverflow)?;
+        self.round_state = RoundState::Gathering;
+        self.round_output_mint = output_mint;
+        self.cursor_user = 0;
+        self.cursor_stream = 0;
+        self.round_input = 0;
+        self.round_slippage_bps = BPS_DENOMINATOR;
+        self.round_limits.clear();
+        Ok(())
+    }
+
+    pub fn is_user_slot_used(&self, index: u32) -> bool {
+        is_slot_used(&self.user_slots, index)
+    }
+
+    /// Takes the lowest free user slot, growing `user_count` if needed
+    pub fn allocate_user_slot(&mut self) -> Option<u32> {
+        let index = allocate_slot(&mut self.user_slots)?;
+        self.user_count = self.user_count.max(index + 1);
+        Some(index)
+    }
+
+    pub fn free_user_slot(&mut self, index: u32) {
+        free_slot(&mut self.user_slots, index);
+    }
+
+    /// Whether the cursor has visited every user slot of the round in progress
+    pub fn is_cursor_done(&self) -> bool {
+        self.cursor_user >= self.user_count
+    }
+
+    /// Whether a stream with the limit price `input_per_output` can be gathered into the round in
+    /// progress, which holds at most `MAX_ROUND_LIMITS` limit prices
+    pub fn has_room_for_limit(&self, input_per_output: (u64, u64)) -> bool {
+        self.round_limits.len() < MAX_ROUND_LIMITS
+            || self.round_limits.iter().any(|limit| limit.is_limit(input_per_output))
+    }
+
+    /// Adds the input of a stream with the limit price `input_per_output` to the round in progress
+    pub fn gather_limited_input(&mut self, input_per_output: (u64, u64), amount: u64) -> Result<(), ProgramError> {
+        let index = match self.round_limits.iter().position(|limit| limit.is_limit(input_per_output)) {
+            Some(index) => index,
+            None if self.round_limits.len() < MAX_ROUND_LIMITS => {
+                self.round_limits.push(RoundLimit {
+                    input_amount: input_per_output.0,
+                    output_amount: input_per_output.1,
+                    input: 0,
+                    is_met: true
+                });
+                self.round_limits.len() - 1
+            }
+            None => return Err(StepError::NoFreeSlot.into())
+        };
+
+        let round_limit = &mut self.round_limits[index];
+        round_limit.input = round_limit.input.checked_add(amount).ok_or(StepError::AmountOverflow)?;
+        self.round_input = self.round_input.checked_add(amount).ok_or(StepError::AmountOverflow)?;
+        Ok(())
+    }
+
+    /// Takes the input of the limit prices the swap reserves the round is executed at are not within
+    /// out of the round in progress, and returns the amount taken out
+    pub fn withhold_unmet_limits(&mut self, input_reserve: u64, output_reserve: u64) -> Result<u64, ProgramError> {
+        let mut withheld: u64 = 0;
+        for round_limit in self.round_limits.iter_mut() {
+            round_limit.is_met = round_limit.allows(input_reserve, output_reserve);
+
+            if !round_limit.is_met {
+                withheld = withheld.checked_add(round_limit.input).ok_or(StepError::AmountOverflow)?;
+            }
+        }
+
+        self.round_input = self.round_input.checked_sub(withheld).ok_or(StepError::AmountOverflow)?;
+        Ok(withheld)
+    }
+
+    /// Whether the limit price `input_per_output` was met by the last settled round
+    pub fn is_settled_limit_met(&self, input_per_output: (u64, u64)) -> bool {
+        self.settled_limits.iter()
+            .find(|limit| limit.is_limit(input_per_output))
+            .map_or(true, |limit| limit.is_met)
+    }
+
+    /// Records the output of the round in progress and returns the pool to idle
+    pub fn settle_round(&mut self, output: u64) {
+        self.settled_input = self.round_input;
+        self.settled_output = output;
+        self.settled_limits = replace(&mut self.round_limits, Vec::new());
+        self.round_input = 0;
+        self.round_state = RoundState::Idle;
+    }
+
+    /// Ends the round in progress without executing it. The round settles without input, so the
+    /// input of its streams goes back to their balances as they are settled.
+    pub fn abort_round(&mut self) {
+        self.round_input = 0;
+        self.round_limits.clear();
+        self.settle_round(0);
+    }
+
+    /// Id of the last round whose output is known, if any
+    pub fn last_settled_round(&self) -> Option<u64> {
+        match self.round_state {
+            RoundState::Idle if self.round_id > 0 => Some(self.round_id),
+            RoundState::Idle => None,
+            _ if self.round_id > 1 => Some(self.round_id - 1),
+            _ => None
+        }
+    }
 }
 
 impl Sealed for Pool {}
 
+impl IsInitialized for Pool {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
 impl Pack for Pool {
-    const LEN: usize = (1 + 32 + 32 + 12);
+    const LEN: usize = (1 + 32 + 32 + 4 + 8 * USER_SLOT_WORDS + 8 + 1 + 32 + 4 + 4 + 8 + 2 + 8 + 8 + 8 +
+        (4 + MAX_ROUND_LIMITS * RoundLimit::LEN) * 2);
 
     fn pack_into_slice(&self, dst: &mut [u8]) {
         let data = self.try_to_vec().unwrap();
@@ -163,4 +1128,308 @@ impl Pack for Pool {
             ProgramError::InvalidAccountData
         })
     }
-}
\ No newline at end of file
+}
+/// The swap registered by the deployer to trade between two pools
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, BorshSchema, PartialEq)]
+pub struct Route {
+    pub is_initialized: bool,
+    pub token_swap_program_pubkey: PubkeyData,
+    pub swap_pubkey: PubkeyData
+}
+
+impl Sealed for Route {}
+
+impl IsInitialized for Route {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for Route {
+    const LEN: usize = (1 + 32 + 32);
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let data = self.try_to_vec().unwrap();
+        dst[..data.len()].copy_from_slice(&data);
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let mut mut_src: &[u8] = src;
+        Self::deserialize(&mut mut_src).map_err(|err| {
+            msg!(
+                "Error: failed to deserialize route account: {}",
+                err
+            );
+            ProgramError::InvalidAccountData
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stream_params(amount: u64) -> StreamParams {
+        StreamParams {
+            schedule: Schedule::Interval { interval_seconds: 100 },
+            catch_up: CatchUpPolicy::SkipMissed,
+            strategy: Strategy::FixedAmount { amount },
+            slippage_bps: MIN_SLIPPAGE_BPS,
+            end_conditions: EndConditions::default(),
+            limit_price: None,
+            carry_over: false
+        }
+    }
+
+    /// Stream of user account `[1; 32]` due at 1_000
+    fn stream(stream_index: u32, params: StreamParams) -> Stream {
+        Stream::new([1; 32], stream_index, [2; 32], [3; 32], StreamDirection::Buy, params, 1_000)
+    }
+
+    #[test]
+    fn test_slot_bitmaps() {
+        let mut pool = Pool::new([2; 32], "pool");
+        assert_eq!(pool.allocate_user_slot(), Some(0));
+        assert_eq!(pool.allocate_user_slot(), Some(1));
+        assert_eq!(pool.allocate_user_slot(), Some(2));
+
+        // Freed slots are reused lowest first without growing the count
+        pool.free_user_slot(1);
+        pool.free_user_slot(0);
+        assert!(!pool.is_user_slot_used(1));
+        assert_eq!(pool.allocate_user_slot(), Some(0));
+        assert_eq!(pool.allocate_user_slot(), Some(1));
+        assert_eq!(pool.user_count, 3);
+
+        // Slots past the first word of the bitmap
+        for index in 3..MAX_USERS_PER_POOL {
+            assert_eq!(pool.allocate_user_slot(), Some(index));
+        }
+        assert_eq!(pool.user_count, MAX_USERS_PER_POOL);
+        assert_eq!(pool.allocate_user_slot(), None);
+        pool.free_user_slot(700);
+        assert_eq!(pool.allocate_user_slot(), Some(700));
+        assert!(!pool.is_user_slot_used(MAX_USERS_PER_POOL));
+        pool.free_user_slot(MAX_USERS_PER_POOL);
+        assert_eq!(pool.allocate_user_slot(), None);
+
+        let mut user_account = UserAccount::new([1; 32], [4; 32], 0);
+        for index in 0..MAX_STREAMS_PER_USER {
+            assert_eq!(user_account.allocate_stream_slot(), Some(index));
+        }
+        assert_eq!(user_account.allocate_stream_slot(), None);
+        user_account.free_stream_slot(MAX_STREAMS_PER_USER - 1);
+        assert_eq!(user_account.allocate_stream_slot(), Some(MAX_STREAMS_PER_USER - 1));
+        assert_eq!(user_account.stream_count, MAX_STREAMS_PER_USER);
+        assert!(!user_account.is_stream_slot_used(MAX_STREAMS_PER_USER));
+    }
+
+    #[test]
+    fn test_cursor_across_pages() {
+        let mut pool = Pool::new([2; 32], "pool");
+        for _ in 0..3 {
+            pool.allocate_user_slot();
+        }
+        let mut user_account = UserAccount::new([1; 32], [4; 32], 1);
+        for _ in 0..3 {
+            user_account.allocate_stream_slot();
+        }
+
+        pool.start_round([3; 32]).unwrap();
+        assert_eq!((pool.round_id, pool.cursor_user, pool.cursor_stream), (1, 0, 0));
+        assert!(!pool.is_cursor_done());
+
+        // The first page stops in the middle of the streams of user 1
+        pool.cursor_user = 1;
+        pool.cursor_stream = 2;
+
+        // Between pages, a stream behind the cursor is cancelled and its slot taken again, so the
+        // new stream waits for the next round while the streams ahead are still visited
+        user_account.free_stream_slot(1);
+        assert_eq!(user_account.allocate_stream_slot(), Some(1));
+        assert!(user_account.is_stream_slot_used(pool.cursor_stream));
+        user_account.free_stream_slot(2);
+        assert!(!user_account.is_stream_slot_used(pool.cursor_stream));
+        assert_eq!(user_account.stream_count, 3);
+
+        // A user taking a freed slot behind the cursor waits for the next round, one joining
+        // ahead of it is visited in the same round
+        pool.free_user_slot(0);
+        assert_eq!(pool.allocate_user_slot(), Some(0));
+        assert_eq!(pool.allocate_user_slot(), Some(3));
+        pool.cursor_user = 3;
+        assert!(!pool.is_cursor_done());
+        pool.cursor_user = 4;
+        assert!(pool.is_cursor_done());
+
+        // The next round starts over from the first slot
+        pool.round_input = 500;
+        pool.settle_round(400);
+        assert_eq!(pool.last_settled_round(), Some(1));
+        pool.start_round([3; 32]).unwrap();
+        assert_eq!((pool.round_id, pool.cursor_user, pool.cursor_stream, pool.round_input), (2, 0, 0, 0));
+        assert_eq!(pool.last_settled_round(), Some(1));
+    }
+
+    #[test]
+    fn test_pause_resume_and_cancel() {
+        let mut user_account = UserAccount::new([1; 32], [4; 32], 0);
+        user_account.balance = 1_000;
+        let stream_index = user_account.allocate_stream_slot().unwrap();
+        let mut stream = stream(stream_index, stream_params(300));
+        let commitment = stream.window_commitment();
+        user_account.recommit(&mut stream, commitment).unwrap();
+        assert_eq!(user_account.free_balance(), 700);
+
+        // Paused streams are skipped but keep their reservation
+        assert!(!stream.is_due(999));
+        assert!(stream.is_due(1_000));
+        stream.status = StreamStatus::Paused;
+        assert!(!stream.is_due(1_000));
+        assert_eq!(user_account.available_to(&stream), Ok(1_000));
+        assert_eq!(user_account.free_balance(), 700);
+        stream.status = StreamStatus::Active;
+        assert!(stream.is_due(1_000));
+        stream.status = StreamStatus::Underfunded;
+        assert!(stream.is_due(1_000));
+
+        // Cancelling waits for the output of a round in progress
+        let mut pool = Pool::new([2; 32], "pool");
+        pool.start_round([3; 32]).unwrap();
+        pool.round_input = 300;
+        stream.pending_round = pool.round_id;
+        stream.pending_input = 300;
+        user_account.balance -= 300;
+        stream.settle_pending(&pool, &mut user_account).unwrap();
+        assert!(stream.has_unsettled_input());
+        pool.settle_round(600);
+        stream.settle_pending(&pool, &mut user_account).unwrap();
+        assert!(!stream.has_unsettled_input());
+        assert_eq!(stream.output_balance, 600);
+
+        user_account.recommit(&mut stream, 0).unwrap();
+        user_account.free_stream_slot(stream.stream_index);
+        assert_eq!(user_account.committed, 0);
+        assert_eq!(user_account.free_balance(), 700);
+        assert!(!user_account.is_closable());
+        user_account.balance = 0;
+        assert!(user_account.is_closable());
+    }
+
+    #[test]
+    fn test_update_recommits_the_stream() {
+        let mut user_account = UserAccount::new([1; 32], [4; 32], 0);
+        user_account.balance = 1_000;
+        let mut other = stream(0, stream_params(200));
+        user_account.recommit(&mut other, 200).unwrap();
+        let mut stream = stream(1, StreamParams { carry_over: true, ..stream_params(300) });
+        user_account.recommit(&mut stream, 300).unwrap();
+        stream.executions = 2;
+        stream.total_input = 600;
+        stream.total_output = 1_200;
+        stream.carried_amount = 300;
+
+        // The new parameters keep the history of the stream
+        stream.set_params(stream_params(800));
+        assert_eq!((stream.executions, stream.total_input, stream.total_output), (2, 600, 1_200));
+        assert_eq!(stream.next_execution_ts, 1_000);
+        assert_eq!(stream.carried_amount, 0);
+
+        // The stream can take its own reservation and what the other stream leaves free
+        assert_eq!(user_account.available_to(&stream), Ok(800));
+        let commitment = stream.window_commitment();
+        user_account.recommit(&mut stream, commitment).unwrap();
+        assert_eq!(user_account.committed, 1_000);
+        assert_eq!(stream.committed_amount, 800);
+        assert_eq!(user_account.available_to(&other), Ok(200));
+
+        user_account.recommit(&mut stream, 100).unwrap();
+        assert_eq!(user_account.committed, 300);
+        assert_eq!(user_account.free_balance(), 700);
+
+        // A reservation the user account does not hold is an overflow
+        stream.committed_amount = 400;
+        assert_eq!(user_account.available_to(&stream), Err(StepError::AmountOverflow.into()));
+        assert_eq!(user_account.recommit(&mut stream, 0), Err(StepError::AmountOverflow.into()));
+        assert_eq!(user_account.committed, 300);
+    }
+
+    #[test]
+    fn test_end_conditions() {
+        let ends = |end_conditions: EndConditions| stream(0, StreamParams { end_conditions, ..stream_params(300) });
+
+        let mut stream = ends(EndConditions::default());
+        stream.executions = u64::MAX;
+        stream.total_input = u64::MAX;
+        assert!(!stream.is_end_reached(UnixTimestamp::MAX));
+
+        let stream = ends(EndConditions { end_ts: Some(1_200), ..EndConditions::default() });
+        assert!(!stream.is_end_reached(1_199));
+        assert!(stream.is_end_reached(1_200));
+
+        // Executions count windows, a catch-up execution can only take the windows left
+        let mut stream = ends(EndConditions { max_executions: Some(3), ..EndConditions::default() });
+        stream.catch_up = CatchUpPolicy::Combined { max_windows: 8 };
+        assert_eq!(stream.due_windows(1_500), Ok(3));
+        stream.executions = 2;
+        assert!(!stream.is_end_reached(1_000));
+        assert_eq!(stream.due_windows(1_500), Ok(1));
+        stream.executions = 3;
+        assert!(stream.is_end_reached(1_000));
+
+        // The last execution is reduced to what is left of the budget
+        let mut stream = ends(EndConditions { max_total_input: Some(1_000), ..EndConditions::default() });
+        stream.total_input = 900;
+        assert!(!stream.is_end_reached(1_000));
+        assert_eq!(stream.next_input(1, 1, 1), 100);
+        stream.total_input = 1_000;
+        assert!(stream.is_end_reached(1_000));
+
+        // Completed streams are never due and reserve nothing
+        stream.complete(1_100);
+        assert_eq!(stream.status, StreamStatus::Completed);
+        assert_eq!(stream.completed_ts, Some(1_100));
+        assert!(!stream.is_due(UnixTimestamp::MAX));
+        assert_eq!(stream.window_commitment(), 0);
+    }
+
+    #[test]
+    fn test_settle_with_the_gathered_limit() {
+        let mut user_account = UserAccount::new([1; 32], [4; 32], 0);
+        let limit = Price { quote_amount: 1, base_amount: 1 };
+        let mut limited = stream(0, StreamParams { limit_price: Some(limit), ..stream_params(300) });
+        let mut pool = Pool::new([2; 32], "pool");
+        pool.start_round([3; 32]).unwrap();
+
+        // Gathered with a limit the reserves are not within when the round is executed
+        limited.pending_round = pool.round_id;
+        limited.pending_input = 300;
+        limited.pending_windows = 1;
+        limited.pending_limit = limited.limit_price;
+        limited.total_input = 300;
+        limited.executions = 1;
+        pool.gather_limited_input(limit.input_per_output(StreamDirection::Buy), 300).unwrap();
+        pool.round_input += 200;
+        assert_eq!(pool.withhold_unmet_limits(3, 1), Ok(300));
+        pool.settle_round(100);
+
+        // Dropping the limit afterwards does not claim output of input that was never swapped
+        limited.set_params(stream_params(300));
+        limited.settle_pending(&pool, &mut user_account).unwrap();
+        assert_eq!((limited.output_balance, limited.total_input, limited.executions), (0, 0, 0));
+        assert_eq!(user_account.balance, 300);
+        assert!(!limited.has_unsettled_input());
+
+        // A round settled without input gives the input back instead of dividing by it
+        let mut unlimited = stream(1, stream_params(300));
+        pool.start_round([3; 32]).unwrap();
+        unlimited.pending_round = pool.round_id;
+        unlimited.pending_input = 300;
+        unlimited.total_input = 300;
+        pool.settle_round(0);
+        unlimited.settle_pending(&pool, &mut user_account).unwrap();
+        assert_eq!((unlimited.output_balance, unlimited.total_input), (0, 0));
+        assert_eq!(user_account.balance, 600);
+        assert!(!unlimited.has_unsettled_input());
+    }
+}
@@ -0,0 +1,62 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    common::*,
+    solana_program::instruction::InstructionError,
+    solana_sdk::{signature::{Keypair, Signer}, transaction::TransactionError},
+    step_finance::{
+        error::StepError,
+        instruction,
+        schedule::{CatchUpPolicy, Schedule, SECONDS_PER_HOUR},
+        state::{RoundState, StreamDirection},
+    },
+};
+
+const AMOUNT: u64 = 300;
+
+#[tokio::test]
+async fn test_aborted_round_gives_the_gathered_input_back() {
+    let mut env = setup(1, 10_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let stream = env.create_stream(0, true, 0, 0, StreamDirection::Buy, stream_params(schedule, CatchUpPolicy::SkipMissed, AMOUNT)).await;
+    let user_a = env.user_address(&env.pool_a.pool, 0);
+
+    // The swap runs dry after the round is gathered, so the round cannot be executed
+    let instructions = [env.gather_instruction(true, &[user_a, stream]), env.gather_instruction(false, &[])];
+    env.process(&instructions, &[]).await.unwrap();
+    assert_eq!(env.user_account(&user_a).await.balance, 1_000 - AMOUNT);
+    env.set_swap_reserves(0, 0);
+    let instruction = env.execute_instruction();
+    let error = env.process(&[instruction], &[]).await.unwrap_err().unwrap();
+    assert_eq!(error, step_error(StepError::NoLiquidity));
+
+    // Only the deployer can abort it
+    let user_keypair = Keypair::from_bytes(&env.users[0].keypair.to_bytes()).unwrap();
+    let instruction = instruction::abort_round(
+        &env.program_id,
+        &user_keypair.pubkey(),
+        &env.program_state,
+        &env.pool_a.pool,
+        &env.pool_b.pool,
+    );
+    let error = env.process(&[instruction], &[&user_keypair]).await.unwrap_err().unwrap();
+    assert_eq!(error, TransactionError::InstructionError(0, InstructionError::MissingRequiredSignature));
+
+    env.abort_round().await;
+    let pool_a = env.pool_a.pool;
+    let pool = env.pool_state(&pool_a).await;
+    assert_eq!((pool.round_state, pool.settled_input, pool.settled_output), (RoundState::Idle, 0, 0));
+
+    // Settling the stream gives its input back, after which it can be cancelled
+    env.claim(0, true, 0, &stream).await;
+    let stream_state = env.stream(&stream).await;
+    assert_eq!((stream_state.total_input, stream_state.executions, stream_state.output_balance), (0, 0, 0));
+    assert!(!stream_state.has_unsettled_input());
+    assert_eq!(env.user_account(&user_a).await.balance, 1_000);
+    env.cancel_stream(0, true, 0, &stream).await;
+    let vault_a = env.pool_a.vault;
+    assert_eq!(env.token_balance(&vault_a).await, 1_000);
+}
@@ -0,0 +1,91 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    common::*,
+    solana_sdk::signature::Signer,
+    step_finance::{
+        error::StepError,
+        instruction::StreamRelease,
+        schedule::{CatchUpPolicy, Schedule, SECONDS_PER_HOUR},
+        state::StreamDirection,
+    },
+};
+
+#[tokio::test]
+async fn test_prefunded_addresses_can_still_be_created() {
+    let mut env = setup(1, 10_000).await;
+    let user_address = env.user_address(&env.pool_a.pool, 0);
+    let stream_address = env.stream_address(&user_address, 0);
+
+    // The next user and stream slots are predictable, so anyone can send them lamports first
+    env.fund_account(&user_address, 1_000_000).await;
+    env.fund_account(&stream_address, 1_000_000).await;
+
+    env.deposit(0, true, 0, 1_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let params = stream_params(schedule, CatchUpPolicy::SkipMissed, 100);
+    env.create_stream(0, true, 0, 0, StreamDirection::Buy, params).await;
+
+    let user_account = env.user_account(&user_address).await;
+    assert_eq!(user_account.balance, 1_000);
+    assert_eq!(user_account.committed, 100);
+    assert_eq!(env.stream(&stream_address).await.user_pubkey, user_address.to_bytes());
+}
+
+#[tokio::test]
+async fn test_emptied_user_account_frees_its_slot() {
+    let mut env = setup(3, 10_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+    env.deposit(1, true, 1, 1_000).await;
+    let user_address = env.user_address(&env.pool_a.pool, 0);
+
+    // Streams keep the account open, even cancelled ones until their account is closed
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let stream_address = env.create_stream(0, true, 0, 0, StreamDirection::Buy, stream_params(schedule, CatchUpPolicy::SkipMissed, 100)).await;
+    env.withdraw(0, true, 0, 1_000, Some(StreamRelease::Cancel), &[stream_address]).await.unwrap();
+    assert_eq!(env.user_account(&user_address).await.balance, 0);
+    env.cancel_stream(0, true, 0, &stream_address).await;
+
+    let owner = env.users[0].keypair.pubkey();
+    let owner_lamports = env.lamports(&owner).await;
+    env.withdraw(0, true, 0, 0, None, &[]).await.unwrap();
+    assert!(env.context.banks_client.get_account(user_address).await.unwrap().is_none());
+    assert!(env.lamports(&owner).await > owner_lamports);
+
+    // The next new depositor takes the freed slot
+    env.deposit(2, true, 0, 500).await;
+    let user_account = env.user_account(&user_address).await;
+    assert_eq!(user_account.owner_pubkey, env.users[2].keypair.pubkey().to_bytes());
+    assert_eq!(user_account.balance, 500);
+    let token_a = env.users[0].token_a;
+    assert_eq!(env.token_balance(&token_a).await, 10_000);
+}
+
+#[tokio::test]
+async fn test_open_twap_order_keeps_user_account() {
+    let mut env = setup(1, 10_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+    let user_address = env.user_address(&env.pool_a.pool, 0);
+    let order = env.create_twap_order(0, 0, 0, 1_000, (START_TS, START_TS + 4 * SECONDS_PER_HOUR), 4, None).await;
+
+    // The order holds the whole deposit, which goes back to the account when it is closed
+    env.withdraw(0, true, 0, 0, None, &[]).await.unwrap();
+    assert_eq!(env.user_account(&user_address).await.order_count, 1);
+    env.close_twap_order(0, 0, &order).await;
+    let user_account = env.user_account(&user_address).await;
+    assert_eq!(user_account.balance, 1_000);
+    assert_eq!(user_account.order_count, 0);
+}
+
+#[tokio::test]
+async fn test_deposit_into_other_users_account_is_rejected() {
+    let mut env = setup(2, 10_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+
+    let error = env.try_deposit(1, true, 0, 500).await.unwrap_err().unwrap();
+    assert_eq!(error, step_error(StepError::InvalidOwner));
+    let user_account = env.user_account(&env.user_address(&env.pool_a.pool, 0)).await;
+    assert_eq!(user_account.balance, 1_000);
+}
@@ -0,0 +1,159 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    common::*,
+    step_finance::{
+        schedule::{CatchUpPolicy, Schedule, SECONDS_PER_HOUR},
+        state::{StreamDirection, StreamStatus},
+    },
+};
+
+const AMOUNT: u64 = 100;
+
+#[tokio::test]
+async fn test_auto_delivery_and_accrual_in_one_round() {
+    let mut env = setup(3, 10_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let mut streams = Vec::new();
+    for user in 0..3 {
+        env.deposit(user, true, user as u32, 1_000).await;
+        let params = stream_params(schedule.clone(), CatchUpPolicy::SkipMissed, AMOUNT);
+        streams.push(env.create_stream(user, true, user as u32, 0, StreamDirection::Buy, params).await);
+    }
+
+    // User 0 has an associated token account for pool B's mint, user 1 accrues and user 2
+    // asked for auto-delivery without having the account
+    let mint_b = env.pool_b.mint;
+    let ata_0 = env.create_associated_token_account(0, &mint_b);
+    let ata_2 = env.associated_token_address(2, &mint_b);
+    env.set_auto_delivery(0, true, 0, true).await;
+    env.set_auto_delivery(2, true, 2, true).await;
+    let accounts = vec![
+        env.user_address(&env.pool_a.pool, 0), streams[0], ata_0,
+        env.user_address(&env.pool_a.pool, 1), streams[1],
+        env.user_address(&env.pool_a.pool, 2), streams[2], ata_2,
+    ];
+
+    env.crank(&accounts, &[]).await;
+
+    // The output of the first round is settled while gathering the next one
+    env.warp_to_timestamp(START_TS + SECONDS_PER_HOUR).await;
+    env.crank(&accounts, &[]).await;
+
+    assert_eq!(env.token_balance(&ata_0).await, AMOUNT);
+    let stream = env.stream(&streams[0]).await;
+    assert_eq!(stream.total_output, AMOUNT);
+    assert_eq!(stream.output_balance, 0);
+
+    for stream_address in [streams[1], streams[2]].iter() {
+        let stream = env.stream(stream_address).await;
+        assert_eq!(stream.total_output, AMOUNT);
+        assert_eq!(stream.output_balance, AMOUNT);
+    }
+
+    // Accrued output is still claimed as before, along with the settled second round
+    env.claim(1, true, 1, &streams[1]).await;
+    let stream = env.stream(&streams[1]).await;
+    assert_eq!(stream.output_balance, 0);
+    let token_b = env.users[1].token_b;
+    assert_eq!(env.token_balance(&token_b).await, 10_000 + stream.total_output);
+}
+
+#[tokio::test]
+async fn test_deliver_sends_output_after_execution() {
+    let mut env = setup(1, 10_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let mut params = stream_params(schedule, CatchUpPolicy::SkipMissed, AMOUNT);
+    params.end_conditions.max_executions = Some(1);
+    let stream_address = env.create_stream(0, true, 0, 0, StreamDirection::Buy, params).await;
+    let mint_b = env.pool_b.mint;
+    let ata = env.create_associated_token_account(0, &mint_b);
+    env.set_auto_delivery(0, true, 0, true).await;
+    let user_address = env.user_address(&env.pool_a.pool, 0);
+
+    // The last window of the stream is delivered in the transaction executing it, no later round
+    // of the pair having to gather the completed stream
+    let instructions = [
+        env.gather_instruction(true, &[user_address, stream_address, ata]),
+        env.gather_instruction(false, &[]),
+        env.execute_instruction(),
+        env.deliver_instruction(false, &[(user_address, stream_address, ata)]),
+    ];
+    env.process(&instructions, &[]).await.unwrap();
+
+    assert_eq!(env.token_balance(&ata).await, AMOUNT);
+    let stream = env.stream(&stream_address).await;
+    assert_eq!(stream.status, StreamStatus::Completed);
+    assert_eq!(stream.total_output, AMOUNT);
+    assert_eq!(stream.output_balance, 0);
+}
+
+#[tokio::test]
+async fn test_deliver_sends_output_of_other_pairs() {
+    let mut env = setup(1, 10_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let params = stream_params(schedule, CatchUpPolicy::SkipMissed, 100);
+    let stream_address = env.create_basket_stream(0, 0, 0, (6_000, 4_000), params).await;
+    let (mint_b, mint_c) = (env.pool_b.mint, env.pool_c.mint);
+    let ata_b = env.create_associated_token_account(0, &mint_b);
+    let ata_c = env.create_associated_token_account(0, &mint_c);
+    env.set_auto_delivery(0, true, 0, true).await;
+    let user_address = env.user_address(&env.pool_a.pool, 0);
+
+    // Gathering only delivers the mint of the pair being gathered, which has no settled output of
+    // its own yet. The slice bought in the round of pools A and B is only settled after it.
+    env.crank(&[user_address, stream_address, ata_b], &[]).await;
+    env.crank_c(&[user_address, stream_address, ata_c], &[]).await;
+    assert_eq!(env.token_balance(&ata_b).await, 0);
+    assert_eq!(env.token_balance(&ata_c).await, 0);
+
+    let instructions = [
+        env.deliver_instruction(false, &[(user_address, stream_address, ata_b)]),
+        env.deliver_instruction(true, &[(user_address, stream_address, ata_c)]),
+    ];
+    env.process(&instructions, &[]).await.unwrap();
+    assert_eq!(env.token_balance(&ata_b).await, 60);
+    assert_eq!(env.token_balance(&ata_c).await, 40);
+    let stream = env.stream(&stream_address).await;
+    assert_eq!(stream.basket[1].total_output, 40);
+    assert_eq!(stream.basket[0].output_balance, 0);
+    assert_eq!(stream.basket[1].output_balance, 0);
+}
+
+#[tokio::test]
+async fn test_gather_without_the_delivery_account() {
+    let mut env = setup(2, 10_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let mut streams = Vec::new();
+    for user in 0..2 {
+        env.deposit(user, true, user as u32, 1_000).await;
+        let params = stream_params(schedule.clone(), CatchUpPolicy::SkipMissed, AMOUNT);
+        streams.push(env.create_stream(user, true, user as u32, 0, StreamDirection::Buy, params).await);
+    }
+    let mint_b = env.pool_b.mint;
+    let ata = env.create_associated_token_account(0, &mint_b);
+    env.set_auto_delivery(0, true, 0, true).await;
+    let user_0 = env.user_address(&env.pool_a.pool, 0);
+    let user_1 = env.user_address(&env.pool_a.pool, 1);
+
+    // A crank unaware of the auto-delivery leaves out the account, the next user is still gathered
+    env.crank(&[user_0, streams[0], user_1, streams[1]], &[]).await;
+    env.warp_to_timestamp(START_TS + SECONDS_PER_HOUR).await;
+    env.crank(&[user_0, streams[0], user_1, streams[1]], &[]).await;
+    let pool_a = env.pool_a.pool;
+    assert_eq!(env.pool_state(&pool_a).await.settled_input, 2 * AMOUNT);
+    assert_eq!(env.token_balance(&ata).await, 0);
+    let stream = env.stream(&streams[0]).await;
+    assert_eq!((stream.total_output, stream.output_balance), (AMOUNT, AMOUNT));
+
+    // Once given, the account receives everything settled so far
+    env.warp_to_timestamp(START_TS + 2 * SECONDS_PER_HOUR).await;
+    env.crank(&[user_0, streams[0], ata, user_1, streams[1]], &[]).await;
+    let stream = env.stream(&streams[0]).await;
+    assert_eq!(env.token_balance(&ata).await, stream.total_output);
+    assert_eq!(stream.output_balance, 0);
+}
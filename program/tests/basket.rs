@@ -0,0 +1,45 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    common::*,
+    step_finance::schedule::{CatchUpPolicy, Schedule, SECONDS_PER_HOUR},
+};
+
+#[tokio::test]
+async fn test_basket_outputs_are_routed_through_their_pairs() {
+    let mut env = setup(1, 10_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let params = stream_params(schedule, CatchUpPolicy::SkipMissed, 100);
+    let stream_address = env.create_basket_stream(0, 0, 0, (6_000, 4_000), params).await;
+    let accounts = vec![env.user_address(&env.pool_a.pool, 0), stream_address];
+
+    // The window is executed in the round of pools A and B, only the slice buying B's mint joins it
+    env.crank(&accounts, &[]).await;
+    let stream = env.stream(&stream_address).await;
+    assert_eq!(stream.executions, 1);
+    assert_eq!(stream.total_input, 100);
+    assert_eq!(stream.basket[0].pending_input, 60);
+    assert_eq!(stream.basket[1].queued_input, 40);
+    assert_eq!(env.user_account(&accounts[0]).await.balance, 900);
+
+    // The round of pools A and C picks up the other slice without executing a new window
+    env.crank_c(&accounts, &[]).await;
+    let stream = env.stream(&stream_address).await;
+    assert_eq!(stream.executions, 1);
+    assert_eq!(stream.basket[0].total_output, 60);
+    assert_eq!(stream.basket[1].queued_input, 0);
+    assert_eq!(stream.basket[1].pending_input, 40);
+
+    env.claim_basket(0, 0, &stream_address).await;
+    let stream = env.stream(&stream_address).await;
+    assert_eq!(stream.basket[1].total_output, 40);
+    assert_eq!(stream.basket[0].output_balance, 0);
+    assert_eq!(stream.basket[1].output_balance, 0);
+
+    let (token_b, token_c) = (env.users[0].token_b, env.users[0].token_c);
+    assert_eq!(env.token_balance(&token_b).await, 10_000 + 60);
+    assert_eq!(env.token_balance(&token_c).await, 10_000 + 40);
+}
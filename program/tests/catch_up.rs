@@ -0,0 +1,119 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    common::*,
+    solana_program::pubkey::Pubkey,
+    step_finance::{
+        schedule::{CatchUpPolicy, Schedule, SECONDS_PER_DAY, SECONDS_PER_HOUR},
+        state::StreamDirection,
+    },
+};
+
+const AMOUNT: u64 = 100;
+
+struct Streams {
+    pool_a_accounts: Vec<Pubkey>,
+    pool_b_accounts: Vec<Pubkey>,
+}
+
+/// Creates opposite daily streams out of both pools for one user, buying pool B's mint with pool A's
+/// and selling it back. Both sides are equal so every round is matched in full at the 1:1 reserve
+/// price without trading through the swap.
+async fn daily_streams(env: &mut TestEnv, catch_up: CatchUpPolicy) -> Streams {
+    env.deposit(0, true, 0, 10_000).await;
+    env.deposit(0, false, 0, 10_000).await;
+    let schedule = Schedule::Daily { hour: 0 };
+    let buy_params = stream_params(schedule.clone(), catch_up.clone(), AMOUNT);
+    let stream_a = env.create_stream(0, true, 0, 0, StreamDirection::Buy, buy_params).await;
+    let stream_b = env.create_stream(0, false, 0, 0, StreamDirection::Sell, stream_params(schedule, catch_up, AMOUNT)).await;
+
+    Streams {
+        pool_a_accounts: vec![env.user_address(&env.pool_a.pool, 0), stream_a],
+        pool_b_accounts: vec![env.user_address(&env.pool_b.pool, 0), stream_b],
+    }
+}
+
+async fn crank(env: &mut TestEnv, streams: &Streams) {
+    env.crank(&streams.pool_a_accounts, &streams.pool_b_accounts).await;
+}
+
+#[tokio::test]
+async fn test_skip_missed_windows() {
+    let mut env = setup(1, 10_000).await;
+    let streams = daily_streams(&mut env, CatchUpPolicy::SkipMissed).await;
+
+    // The crank is down for a week, so the windows of day 0 to 7 are due
+    env.warp_to_timestamp(START_TS + 7 * SECONDS_PER_DAY + SECONDS_PER_HOUR).await;
+    crank(&mut env, &streams).await;
+
+    let stream = env.stream(&streams.pool_a_accounts[1]).await;
+    assert_eq!(stream.executions, 1);
+    assert_eq!(stream.total_input, AMOUNT);
+    assert_eq!(stream.next_execution_ts, START_TS + 8 * SECONDS_PER_DAY);
+
+    // Nothing is left to catch up within the same day
+    env.warp_to_timestamp(START_TS + 7 * SECONDS_PER_DAY + 2 * SECONDS_PER_HOUR).await;
+    crank(&mut env, &streams).await;
+
+    let stream = env.stream(&streams.pool_a_accounts[1]).await;
+    assert_eq!(stream.executions, 1);
+    assert_eq!(stream.total_output, AMOUNT);
+    let user_account = env.user_account(&streams.pool_a_accounts[0]).await;
+    assert_eq!(user_account.balance, 10_000 - AMOUNT);
+}
+
+#[tokio::test]
+async fn test_combined_catch_up_is_capped() {
+    let mut env = setup(1, 10_000).await;
+    let streams = daily_streams(&mut env, CatchUpPolicy::Combined { max_windows: 5 }).await;
+
+    // Three windows fit under the cap
+    env.warp_to_timestamp(START_TS + 2 * SECONDS_PER_DAY + SECONDS_PER_HOUR).await;
+    crank(&mut env, &streams).await;
+
+    let stream = env.stream(&streams.pool_a_accounts[1]).await;
+    assert_eq!(stream.executions, 3);
+    assert_eq!(stream.total_input, 3 * AMOUNT);
+    assert_eq!(stream.next_execution_ts, START_TS + 3 * SECONDS_PER_DAY);
+
+    // Eight windows are missed next, five are executed and the rest skipped
+    env.warp_to_timestamp(START_TS + 10 * SECONDS_PER_DAY + SECONDS_PER_HOUR).await;
+    crank(&mut env, &streams).await;
+
+    let stream = env.stream(&streams.pool_a_accounts[1]).await;
+    assert_eq!(stream.executions, 8);
+    assert_eq!(stream.total_input, 8 * AMOUNT);
+    assert_eq!(stream.total_output, 3 * AMOUNT);
+    assert_eq!(stream.next_execution_ts, START_TS + 11 * SECONDS_PER_DAY);
+    let user_account = env.user_account(&streams.pool_a_accounts[0]).await;
+    assert_eq!(user_account.balance, 10_000 - 8 * AMOUNT);
+}
+
+#[tokio::test]
+async fn test_one_window_per_crank() {
+    let mut env = setup(1, 10_000).await;
+    let streams = daily_streams(&mut env, CatchUpPolicy::OneWindowPerCrank).await;
+    let now = START_TS + 7 * SECONDS_PER_DAY + SECONDS_PER_HOUR;
+    env.warp_to_timestamp(now).await;
+
+    for window in 1..=8 {
+        crank(&mut env, &streams).await;
+
+        let stream = env.stream(&streams.pool_a_accounts[1]).await;
+        assert_eq!(stream.executions, window);
+        assert_eq!(stream.next_execution_ts, START_TS + window as i64 * SECONDS_PER_DAY);
+
+        // Move to a new slot at the same time for the next crank
+        env.warp_to_timestamp(now).await;
+    }
+
+    // The stream is back on schedule
+    crank(&mut env, &streams).await;
+
+    let stream = env.stream(&streams.pool_a_accounts[1]).await;
+    assert_eq!(stream.executions, 8);
+    assert_eq!(stream.total_input, 8 * AMOUNT);
+    assert_eq!(stream.total_output, 8 * AMOUNT);
+}
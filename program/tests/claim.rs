@@ -0,0 +1,86 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    common::*,
+    solana_program::{instruction::InstructionError, pubkey::Pubkey},
+    solana_sdk::{signature::Keypair, transaction::TransactionError},
+    step_finance::{
+        schedule::{CatchUpPolicy, Schedule, SECONDS_PER_HOUR},
+        state::StreamDirection,
+    },
+};
+
+#[tokio::test]
+async fn test_claim_buy_and_sell_streams() {
+    // Pool A holds the quote asset and pool B the base asset
+    let mut env = setup(1, 10_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+    env.deposit(0, false, 0, 1_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let buy_stream = env
+        .create_stream(0, true, 0, 0, StreamDirection::Buy, stream_params(schedule.clone(), CatchUpPolicy::SkipMissed, 200))
+        .await;
+    let sell_stream = env
+        .create_stream(0, false, 0, 0, StreamDirection::Sell, stream_params(schedule, CatchUpPolicy::SkipMissed, 200))
+        .await;
+    let user_a = env.user_address(&env.pool_a.pool, 0);
+    let user_b = env.user_address(&env.pool_b.pool, 0);
+
+    env.crank(&[user_a, buy_stream], &[user_b, sell_stream]).await;
+    env.claim(0, true, 0, &buy_stream).await;
+    env.claim(0, false, 0, &sell_stream).await;
+
+    let token_a = env.users[0].token_a;
+    let token_b = env.users[0].token_b;
+    assert_eq!(env.token_balance(&token_a).await, 10_000 - 1_000 + 200);
+    assert_eq!(env.token_balance(&token_b).await, 10_000 - 1_000 + 200);
+
+    let buy = env.stream(&buy_stream).await;
+    assert_eq!(buy.output_balance, 0);
+    assert_eq!(buy.total_output, 200);
+    assert_eq!(buy.traded_amounts(), (200, 200));
+    let sell = env.stream(&sell_stream).await;
+    assert_eq!(sell.output_balance, 0);
+    assert_eq!(sell.traded_amounts(), (200, 200));
+
+    // The matched input of each side paid the claims of the other, leaving the unspent deposits
+    let vault_a = env.pool_a.vault;
+    let vault_b = env.pool_b.vault;
+    assert_eq!(env.token_balance(&vault_a).await, 800);
+    assert_eq!(env.token_balance(&vault_b).await, 800);
+    assert_eq!(env.user_account(&user_a).await.balance, 800);
+    assert_eq!(env.user_account(&user_b).await.balance, 800);
+
+    // Claiming again pays nothing
+    env.warp_to_timestamp(START_TS + 1).await;
+    env.claim(0, true, 0, &buy_stream).await;
+    assert_eq!(env.token_balance(&token_b).await, 10_000 - 1_000 + 200);
+}
+
+#[tokio::test]
+async fn test_claim_rejects_other_token_program() {
+    let mut env = setup(1, 10_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+    env.deposit(0, false, 0, 1_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let buy_stream = env
+        .create_stream(0, true, 0, 0, StreamDirection::Buy, stream_params(schedule, CatchUpPolicy::SkipMissed, 200))
+        .await;
+    let user_a = env.user_address(&env.pool_a.pool, 0);
+    let user_b = env.user_address(&env.pool_b.pool, 0);
+    env.crank(&[user_a, buy_stream], &[user_b]).await;
+
+    // The vault would sign the transfer, so it must only ever be handed to the token program
+    let mut instruction = env.claim_instruction(0, true, 0, &buy_stream);
+    instruction.accounts.last_mut().unwrap().pubkey = Pubkey::new_unique();
+    let user_keypair = Keypair::from_bytes(&env.users[0].keypair.to_bytes()).unwrap();
+    let error = env.process(&[instruction], &[&user_keypair]).await.unwrap_err().unwrap();
+    assert_eq!(error, TransactionError::InstructionError(0, InstructionError::IncorrectProgramId));
+
+    let token_b = env.users[0].token_b;
+    assert_eq!(env.token_balance(&token_b).await, 10_000 - 1_000);
+    env.claim(0, true, 0, &buy_stream).await;
+    assert_eq!(env.token_balance(&token_b).await, 10_000 - 1_000 + 200);
+}
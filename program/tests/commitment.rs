@@ -0,0 +1,89 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    common::*,
+    solana_sdk::signature::Keypair,
+    step_finance::{
+        error::StepError,
+        instruction::StreamRelease,
+        schedule::{CatchUpPolicy, Schedule, SECONDS_PER_HOUR},
+        state::{Strategy, StreamDirection, StreamStatus},
+    },
+};
+
+#[tokio::test]
+async fn test_streams_reserve_their_windows() {
+    let mut env = setup(1, 10_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let stream_address = env.create_stream(0, true, 0, 0, StreamDirection::Buy, stream_params(schedule.clone(), CatchUpPolicy::SkipMissed, 600)).await;
+    let user_address = env.user_address(&env.pool_a.pool, 0);
+    assert_eq!(env.user_account(&user_address).await.committed, 600);
+
+    // A second stream cannot take more than the 400 left free
+    let params = stream_params(schedule, CatchUpPolicy::SkipMissed, 500);
+    let (_, instruction) = env.create_stream_instruction(0, true, 0, 1, StreamDirection::Buy, params);
+    let user_keypair = Keypair::from_bytes(&env.users[0].keypair.to_bytes()).unwrap();
+    let error = env.process(&[instruction], &[&user_keypair]).await.unwrap_err().unwrap();
+    assert_eq!(error, step_error(StepError::InsufficientBalance));
+
+    // Neither can a withdrawal that does not release the stream
+    let error = env.withdraw(0, true, 0, 500, None, &[stream_address]).await.unwrap_err().unwrap();
+    assert_eq!(error, step_error(StepError::InsufficientBalance));
+    env.withdraw(0, true, 0, 400, None, &[]).await.unwrap();
+
+    // Shrinking frees what the withdrawal is short of
+    env.withdraw(0, true, 0, 100, Some(StreamRelease::Shrink), &[stream_address]).await.unwrap();
+    let stream = env.stream(&stream_address).await;
+    assert_eq!(stream.strategy, Strategy::FixedAmount { amount: 500 });
+    let user_account = env.user_account(&user_address).await;
+    assert_eq!(user_account.balance, 500);
+    assert_eq!(user_account.committed, 500);
+
+    // Cancelling releases the whole window
+    env.withdraw(0, true, 0, 500, Some(StreamRelease::Cancel), &[stream_address]).await.unwrap();
+    assert_eq!(env.stream(&stream_address).await.status, StreamStatus::Completed);
+    let user_account = env.user_account(&user_address).await;
+    assert_eq!(user_account.balance, 0);
+    assert_eq!(user_account.committed, 0);
+    let token_a = env.users[0].token_a;
+    assert_eq!(env.token_balance(&token_a).await, 10_000);
+}
+
+#[tokio::test]
+async fn test_underfunded_stream_is_skipped_without_failing_the_round() {
+    let mut env = setup(2, 10_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+    env.deposit(1, true, 1, 1_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let catch_up = CatchUpPolicy::Combined { max_windows: 5 };
+    let stream_0 = env.create_stream(0, true, 0, 0, StreamDirection::Buy, stream_params(schedule.clone(), catch_up.clone(), 400)).await;
+    let stream_1 = env.create_stream(1, true, 1, 0, StreamDirection::Buy, stream_params(schedule, catch_up, 100)).await;
+    let accounts = vec![
+        env.user_address(&env.pool_a.pool, 0), stream_0,
+        env.user_address(&env.pool_a.pool, 1), stream_1,
+    ];
+
+    // Three windows are due, 1_200 for the first stream against a balance of 1_000
+    env.warp_to_timestamp(START_TS + 2 * SECONDS_PER_HOUR).await;
+    env.crank(&accounts, &[]).await;
+
+    let stream = env.stream(&stream_0).await;
+    assert_eq!(stream.status, StreamStatus::Underfunded);
+    assert_eq!(stream.executions, 0);
+    assert_eq!(env.stream(&stream_1).await.executions, 3);
+
+    // Topped up, the stream executes its windows and is active again
+    env.deposit(0, true, 0, 1_000).await;
+    env.warp_to_timestamp(START_TS + 2 * SECONDS_PER_HOUR + 1).await;
+    env.crank(&accounts, &[]).await;
+
+    let stream = env.stream(&stream_0).await;
+    assert_eq!(stream.status, StreamStatus::Active);
+    assert_eq!(stream.executions, 3);
+    let user_account = env.user_account(&accounts[0]).await;
+    assert_eq!(user_account.balance, 2_000 - 1_200);
+    assert_eq!(user_account.committed, 400);
+}
@@ -0,0 +1,1011 @@
+#![allow(dead_code)]
+
+use {
+    solana_program::{
+        account_info::AccountInfo,
+        clock::{Clock, UnixTimestamp},
+        entrypoint::ProgramResult,
+        instruction::{Instruction, InstructionError},
+        program::{invoke, invoke_signed},
+        program_error::ProgramError,
+        program_option::COption,
+        program_pack::Pack,
+        pubkey::Pubkey,
+        system_instruction,
+        system_program,
+    },
+    std::convert::TryInto,
+    solana_program_test::*,
+    solana_sdk::{
+        account::Account,
+        signature::{Keypair, Signer},
+        transaction::{Transaction, TransactionError},
+    },
+    spl_token_swap::{
+        curve::{base::SwapCurve, fees::Fees},
+        state::{SwapV1, SwapVersion},
+    },
+    step_finance::{
+        error::StepError,
+        instruction::{self, StreamRelease, SwapAccountKeys},
+        pda,
+        processor::Processor,
+        schedule::{CatchUpPolicy, Schedule},
+        state::{
+            EndConditions, Pool, Price, RebalanceStream, Strategy, Stream, StreamDirection, StreamParams, TwapOrder, UserAccount,
+        },
+    },
+};
+
+/// Midnight UTC of 2024-01-01
+pub const START_TS: UnixTimestamp = 1_704_067_200;
+
+pub const SWAP_RESERVE: u64 = 1_000_000;
+
+pub struct TestPool {
+    pub mint: Pubkey,
+    pub pool: Pubkey,
+    pub vault: Pubkey,
+    pub name: String,
+    pub swap_token_account: Pubkey,
+}
+
+pub struct TestUser {
+    pub keypair: Keypair,
+    pub token_a: Pubkey,
+    pub token_b: Pubkey,
+    pub token_c: Pubkey,
+}
+
+/// A program with pools A and B trading through a registered route, and a pool C trading with
+/// pool A through a second route
+pub struct TestEnv {
+    pub context: ProgramTestContext,
+    pub program_id: Pubkey,
+    pub program_state: Pubkey,
+    pub swap_program: Pubkey,
+    pub swap_info: Pubkey,
+    pub swap_authority: Pubkey,
+    pub route: Pubkey,
+    pub pool_a: TestPool,
+    pub pool_b: TestPool,
+    pub pool_c: TestPool,
+    pub swap_c_info: Pubkey,
+    pub swap_c_authority: Pubkey,
+    /// Token account of the swap of pools A and C holding the mint of pool A
+    pub swap_c_token_a: Pubkey,
+    pub route_c: Pubkey,
+    pub users: Vec<TestUser>,
+}
+
+fn test_pool(program_id: &Pubkey, mint: Pubkey, name: &str) -> TestPool {
+    let (pool, _) = pda::find_pool_address(&mint, name, program_id);
+    let (vault, _) = pda::find_vault_address(&pool, program_id);
+
+    TestPool {
+        mint,
+        pool,
+        vault,
+        name: name.to_string(),
+        swap_token_account: Pubkey::new_unique(),
+    }
+}
+
+fn packed_account<T: Pack>(state: T, owner: &Pubkey) -> Account {
+    let mut data = vec![0; T::LEN];
+    T::pack(state, &mut data).unwrap();
+    Account {
+        lamports: 1_000_000_000,
+        data,
+        owner: *owner,
+        executable: false,
+        rent_epoch: 0,
+    }
+}
+
+fn token_account(mint: &Pubkey, owner: &Pubkey, amount: u64) -> Account {
+    let mut account = packed_account(
+        spl_token::state::Account {
+            mint: *mint,
+            owner: *owner,
+            amount,
+            state: spl_token::state::AccountState::Initialized,
+            ..spl_token::state::Account::default()
+        },
+        &spl_token::id(),
+    );
+
+    // Wrapped SOL is backed by the lamports of the account above its reserve
+    if *mint == spl_token::native_mint::id() {
+        let mut state = spl_token::state::Account::unpack(&account.data).unwrap();
+        state.is_native = COption::Some(account.lamports - amount);
+        spl_token::state::Account::pack(state, &mut account.data).unwrap();
+    }
+    account
+}
+
+fn swap_account(swap_program: &Pubkey, token_a: &TestPool, token_a_account: &Pubkey, token_b: &TestPool) -> Account {
+    let mut swap_data = vec![0; SwapVersion::LATEST_LEN];
+    SwapVersion::pack(
+        SwapVersion::SwapV1(SwapV1 {
+            is_initialized: true,
+            nonce: 0,
+            token_program_id: spl_token::id(),
+            token_a: *token_a_account,
+            token_b: token_b.swap_token_account,
+            pool_mint: Pubkey::new_unique(),
+            token_a_mint: token_a.mint,
+            token_b_mint: token_b.mint,
+            pool_fee_account: Pubkey::new_unique(),
+            fees: Fees::default(),
+            swap_curve: SwapCurve::default(),
+        }),
+        &mut swap_data,
+    )
+    .unwrap();
+
+    Account {
+        lamports: 1_000_000_000,
+        data: swap_data,
+        owner: *swap_program,
+        executable: false,
+        rent_epoch: 0,
+    }
+}
+
+/// Stand-in for the token swap program, trading at the reserve price without fees
+fn process_mock_swap(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let amount_in = u64::from_le_bytes(data[1..9].try_into().unwrap());
+    let minimum_amount_out = u64::from_le_bytes(data[9..17].try_into().unwrap());
+    let swap_info = &accounts[0];
+    let authority = &accounts[1];
+    let user_transfer_authority = &accounts[2];
+    let source = &accounts[3];
+    let swap_source = &accounts[4];
+    let swap_destination = &accounts[5];
+    let destination = &accounts[6];
+    let token_program = &accounts[9];
+
+    let source_reserve = spl_token::state::Account::unpack(&swap_source.data.borrow())?.amount;
+    let destination_reserve = spl_token::state::Account::unpack(&swap_destination.data.borrow())?.amount;
+    let amount_out = (amount_in as u128 * destination_reserve as u128 / source_reserve as u128) as u64;
+
+    if amount_out < minimum_amount_out {
+        return Err(ProgramError::Custom(0x10));
+    }
+
+    invoke(
+        &spl_token::instruction::transfer(token_program.key, source.key, swap_source.key, user_transfer_authority.key, &[], amount_in)?,
+        &[source.clone(), swap_source.clone(), user_transfer_authority.clone(), token_program.clone()],
+    )?;
+
+    let (_, nonce) = Pubkey::find_program_address(&[swap_info.key.as_ref()], program_id);
+    invoke_signed(
+        &spl_token::instruction::transfer(token_program.key, swap_destination.key, destination.key, authority.key, &[], amount_out)?,
+        &[swap_destination.clone(), destination.clone(), authority.clone(), token_program.clone()],
+        &[&[swap_info.key.as_ref(), &[nonce]]],
+    )
+}
+
+/// Starts the program with three initialized pools, routes from pool A to the others and `user_count`
+/// users holding `user_tokens` of every mint. The clock starts at [START_TS](constant.START_TS.html).
+pub async fn setup(user_count: usize, user_tokens: u64) -> TestEnv {
+    setup_pools(user_count, user_tokens, false).await
+}
+
+/// Same as `setup` with pool B holding wrapped SOL
+pub async fn setup_native_b(user_count: usize, user_tokens: u64) -> TestEnv {
+    setup_pools(user_count, user_tokens, true).await
+}
+
+async fn setup_pools(user_count: usize, user_tokens: u64, is_native_b: bool) -> TestEnv {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new("step_finance", program_id, processor!(Processor::process));
+
+    let mint_b = if is_native_b { spl_token::native_mint::id() } else { Pubkey::new_unique() };
+    let pool_a = test_pool(&program_id, Pubkey::new_unique(), "pool-a");
+    let pool_b = test_pool(&program_id, mint_b, "pool-b");
+    let pool_c = test_pool(&program_id, Pubkey::new_unique(), "pool-c");
+    let swap_program = Pubkey::new_unique();
+    let swap_info = Pubkey::new_unique();
+    let (swap_authority, _) = Pubkey::find_program_address(&[swap_info.as_ref()], &swap_program);
+    let swap_c_info = Pubkey::new_unique();
+    let (swap_c_authority, _) = Pubkey::find_program_address(&[swap_c_info.as_ref()], &swap_program);
+    let swap_c_token_a = Pubkey::new_unique();
+    program_test.add_program("mock_swap", swap_program, processor!(process_mock_swap));
+
+    for (test_pool, authority) in [(&pool_a, &swap_authority), (&pool_b, &swap_authority), (&pool_c, &swap_c_authority)].iter() {
+        program_test.add_account(
+            test_pool.mint,
+            packed_account(
+                spl_token::state::Mint {
+                    mint_authority: COption::None,
+                    supply: u64::MAX,
+                    decimals: if test_pool.mint == spl_token::native_mint::id() { 9 } else { 0 },
+                    is_initialized: true,
+                    freeze_authority: COption::None,
+                },
+                &spl_token::id(),
+            ),
+        );
+        program_test.add_account(
+            test_pool.swap_token_account,
+            token_account(&test_pool.mint, authority, SWAP_RESERVE),
+        );
+    }
+
+    program_test.add_account(swap_c_token_a, token_account(&pool_a.mint, &swap_c_authority, SWAP_RESERVE));
+    program_test.add_account(swap_info, swap_account(&swap_program, &pool_a, &pool_a.swap_token_account, &pool_b));
+    program_test.add_account(swap_c_info, swap_account(&swap_program, &pool_a, &swap_c_token_a, &pool_c));
+
+    let users: Vec<TestUser> = (0..user_count)
+        .map(|_| TestUser {
+            keypair: Keypair::new(),
+            token_a: Pubkey::new_unique(),
+            token_b: Pubkey::new_unique(),
+            token_c: Pubkey::new_unique(),
+        })
+        .collect();
+
+    for user in users.iter() {
+        program_test.add_account(
+            user.keypair.pubkey(),
+            Account::new(1_000_000_000, 0, &system_program::id()),
+        );
+        program_test.add_account(user.token_a, token_account(&pool_a.mint, &user.keypair.pubkey(), user_tokens));
+        program_test.add_account(user.token_b, token_account(&pool_b.mint, &user.keypair.pubkey(), user_tokens));
+        program_test.add_account(user.token_c, token_account(&pool_c.mint, &user.keypair.pubkey(), user_tokens));
+    }
+
+    let context = program_test.start_with_context().await;
+    let (program_state, _) = pda::find_program_state_address(&program_id);
+    let route = pda::find_route_address(&pool_a.pool, &pool_b.pool, &program_id).0;
+    let route_c = pda::find_route_address(&pool_a.pool, &pool_c.pool, &program_id).0;
+
+    let mut env = TestEnv {
+        context,
+        program_id,
+        program_state,
+        swap_program,
+        swap_info,
+        swap_authority,
+        route,
+        pool_a,
+        pool_b,
+        pool_c,
+        swap_c_info,
+        swap_c_authority,
+        swap_c_token_a,
+        route_c,
+        users,
+    };
+    env.warp_to_timestamp(START_TS).await;
+
+    let deployer = env.context.payer.pubkey();
+    let mut instructions = vec![instruction::init_program(&program_id, &deployer, &program_state)];
+
+    for test_pool in [&env.pool_a, &env.pool_b, &env.pool_c].iter() {
+        instructions.push(instruction::init_pool(
+            &program_id,
+            &deployer,
+            &program_state,
+            &test_pool.pool,
+            &test_pool.vault,
+            &test_pool.mint,
+            &test_pool.name,
+        ));
+    }
+
+    for (route, other_pool, swap_info) in [(route, &env.pool_b, swap_info), (route_c, &env.pool_c, swap_c_info)].iter() {
+        instructions.push(instruction::init_route(
+            &program_id,
+            &deployer,
+            &program_state,
+            route,
+            &env.pool_a.pool,
+            &other_pool.pool,
+            &swap_program,
+            swap_info,
+        ));
+    }
+
+    env.process(&instructions, &[]).await.unwrap();
+    env
+}
+
+/// Error of a transaction whose first instruction failed with `error`
+pub fn step_error(error: StepError) -> TransactionError {
+    TransactionError::InstructionError(0, InstructionError::Custom(error as u32))
+}
+
+/// Stream parameters spending a fixed amount without end conditions or limit price
+pub fn stream_params(schedule: Schedule, catch_up: CatchUpPolicy, amount: u64) -> StreamParams {
+    StreamParams {
+        schedule,
+        catch_up,
+        strategy: Strategy::FixedAmount { amount },
+        slippage_bps: 100,
+        end_conditions: EndConditions::default(),
+        limit_price: None,
+        carry_over: false,
+    }
+}
+
+impl TestEnv {
+    pub async fn process(&mut self, instructions: &[Instruction], signers: &[&Keypair]) -> Result<(), BanksClientError> {
+        let blockhash = self.context.banks_client.get_latest_blockhash().await.unwrap();
+        let mut all_signers = vec![&self.context.payer];
+        all_signers.extend_from_slice(signers);
+        let transaction = Transaction::new_signed_with_payer(
+            instructions,
+            Some(&self.context.payer.pubkey()),
+            &all_signers,
+            blockhash,
+        );
+        self.context.banks_client.process_transaction(transaction).await
+    }
+
+    /// Moves to a new slot whose clock reads `unix_timestamp`
+    pub async fn warp_to_timestamp(&mut self, unix_timestamp: UnixTimestamp) {
+        let clock: Clock = self.context.banks_client.get_sysvar().await.unwrap();
+        self.context.warp_to_slot(clock.slot + 1).unwrap();
+        self.context.set_sysvar(&Clock {
+            slot: clock.slot + 1,
+            unix_timestamp,
+            ..clock
+        });
+    }
+
+    pub fn pool(&self, is_pool_a: bool) -> &TestPool {
+        if is_pool_a { &self.pool_a } else { &self.pool_b }
+    }
+
+    pub fn user_address(&self, pool: &Pubkey, user_index: u32) -> Pubkey {
+        pda::find_user_address(pool, user_index, &self.program_id).0
+    }
+
+    pub fn stream_address(&self, user_account: &Pubkey, stream_index: u32) -> Pubkey {
+        pda::find_stream_address(user_account, stream_index, &self.program_id).0
+    }
+
+    /// Deposits into pool A or B for the user, who takes the given user slot on a first deposit
+    pub async fn deposit(&mut self, user: usize, is_pool_a: bool, user_index: u32, amount: u64) {
+        self.try_deposit(user, is_pool_a, user_index, amount).await.unwrap();
+    }
+
+    /// Deposits into pool A or B for the user, returning the result of the transaction
+    pub async fn try_deposit(&mut self, user: usize, is_pool_a: bool, user_index: u32, amount: u64) -> Result<(), BanksClientError> {
+        let user_token = if is_pool_a { self.users[user].token_a } else { self.users[user].token_b };
+        self.deposit_into(user, is_pool_a, false, user_token, user_index, amount).await
+    }
+
+    /// Deposits into pool C for the user
+    pub async fn deposit_c(&mut self, user: usize, user_index: u32, amount: u64) {
+        let user_token = self.users[user].token_c;
+        self.deposit_into(user, false, true, user_token, user_index, amount).await.unwrap();
+    }
+
+    async fn deposit_into(
+        &mut self,
+        user: usize,
+        is_pool_a: bool,
+        is_pool_c: bool,
+        user_token: Pubkey,
+        user_index: u32,
+        amount: u64
+    ) -> Result<(), BanksClientError> {
+        let test_pool = if is_pool_c { &self.pool_c } else { self.pool(is_pool_a) };
+        let instruction = instruction::deposit(
+            &self.program_id,
+            &self.users[user].keypair.pubkey(),
+            &user_token,
+            &self.user_address(&test_pool.pool, user_index),
+            &test_pool.pool,
+            &test_pool.vault,
+            amount,
+        );
+        let user_keypair = Keypair::from_bytes(&self.users[user].keypair.to_bytes()).unwrap();
+        self.process(&[instruction], &[&user_keypair]).await
+    }
+
+    /// Creates a stream out of pool A or B into the other pool
+    pub async fn create_stream(
+        &mut self,
+        user: usize,
+        is_pool_a: bool,
+        user_index: u32,
+        stream_index: u32,
+        direction: StreamDirection,
+        params: StreamParams,
+    ) -> Pubkey {
+        let (stream, instruction) = self.create_stream_instruction(user, is_pool_a, user_index, stream_index, direction, params);
+        let user_keypair = Keypair::from_bytes(&self.users[user].keypair.to_bytes()).unwrap();
+        self.process(&[instruction], &[&user_keypair]).await.unwrap();
+        stream
+    }
+
+    /// Instruction creating a stream out of pool A or B into the other pool, and the address of the stream
+    pub fn create_stream_instruction(
+        &self,
+        user: usize,
+        is_pool_a: bool,
+        user_index: u32,
+        stream_index: u32,
+        direction: StreamDirection,
+        params: StreamParams,
+    ) -> (Pubkey, Instruction) {
+        let input_pool = self.pool(is_pool_a);
+        let output_pool = self.pool(!is_pool_a);
+        let user_account = self.user_address(&input_pool.pool, user_index);
+        let stream = self.stream_address(&user_account, stream_index);
+        let instruction = instruction::create_stream(
+            &self.program_id,
+            &self.users[user].keypair.pubkey(),
+            &user_account,
+            &input_pool.pool,
+            &stream,
+            &input_pool.mint,
+            &output_pool.mint,
+            direction,
+            params,
+        );
+        (stream, instruction)
+    }
+
+    fn pair_gather_instruction(
+        &self,
+        pool: &TestPool,
+        other_pool: &TestPool,
+        route: &Pubkey,
+        swap_info: &Pubkey,
+        swap_tokens: (&Pubkey, &Pubkey),
+        users_and_streams: &[Pubkey],
+    ) -> Instruction {
+        instruction::gather(
+            &self.program_id,
+            &pool.pool,
+            &other_pool.pool,
+            route,
+            swap_info,
+            swap_tokens.0,
+            swap_tokens.1,
+            &other_pool.vault,
+            users_and_streams,
+        )
+    }
+
+    fn pair_execute_instruction(
+        &self,
+        other_pool: &TestPool,
+        route: &Pubkey,
+        swap_info: &Pubkey,
+        swap_authority: &Pubkey,
+        swap_token_a: &Pubkey,
+    ) -> Instruction {
+        let (reward_vault, _) = pda::find_reward_vault_address(&self.program_id);
+
+        instruction::execute(
+            &self.program_id,
+            &self.context.payer.pubkey(),
+            &self.program_state,
+            route,
+            &self.pool_a.pool,
+            &self.pool_a.vault,
+            &other_pool.pool,
+            &other_pool.vault,
+            &self.swap_accounts(swap_info, swap_authority, swap_token_a, &other_pool.swap_token_account),
+            &reward_vault,
+        )
+    }
+
+    /// Accounts of a swap trading out of `input_token`, the stub swap ignores its pool mint and fee account
+    fn swap_accounts(&self, swap_info: &Pubkey, swap_authority: &Pubkey, input_token: &Pubkey, output_token: &Pubkey) -> SwapAccountKeys {
+        SwapAccountKeys {
+            program_id: self.swap_program,
+            swap: *swap_info,
+            authority: *swap_authority,
+            input_token: *input_token,
+            output_token: *output_token,
+            pool_mint: Pubkey::new_unique(),
+            fee_account: Pubkey::new_unique(),
+        }
+    }
+
+    /// Gathers pool A or B in the pair of pools A and B
+    pub fn gather_instruction(&self, is_pool_a: bool, users_and_streams: &[Pubkey]) -> Instruction {
+        let swap_tokens = (&self.pool(is_pool_a).swap_token_account, &self.pool(!is_pool_a).swap_token_account);
+        self.pair_gather_instruction(
+            self.pool(is_pool_a),
+            self.pool(!is_pool_a),
+            &self.route,
+            &self.swap_info,
+            swap_tokens,
+            users_and_streams,
+        )
+    }
+
+    /// Gathers pool A or C in the pair of pools A and C
+    pub fn gather_c_instruction(&self, is_pool_a: bool, users_and_streams: &[Pubkey]) -> Instruction {
+        let (pool, other_pool) = if is_pool_a { (&self.pool_a, &self.pool_c) } else { (&self.pool_c, &self.pool_a) };
+        let swap_tokens = if is_pool_a {
+            (&self.swap_c_token_a, &self.pool_c.swap_token_account)
+        } else {
+            (&self.pool_c.swap_token_account, &self.swap_c_token_a)
+        };
+        self.pair_gather_instruction(pool, other_pool, &self.route_c, &self.swap_c_info, swap_tokens, users_and_streams)
+    }
+
+    pub fn execute_instruction(&self) -> Instruction {
+        self.pair_execute_instruction(
+            &self.pool_b,
+            &self.route,
+            &self.swap_info,
+            &self.swap_authority,
+            &self.pool_a.swap_token_account,
+        )
+    }
+
+    pub fn execute_c_instruction(&self) -> Instruction {
+        self.pair_execute_instruction(
+            &self.pool_c,
+            &self.route_c,
+            &self.swap_c_info,
+            &self.swap_c_authority,
+            &self.swap_c_token_a,
+        )
+    }
+
+    /// Gathers pools A and B in one page each and executes the round
+    pub async fn crank(&mut self, pool_a_accounts: &[Pubkey], pool_b_accounts: &[Pubkey]) {
+        let instructions = [
+            self.gather_instruction(true, pool_a_accounts),
+            self.gather_instruction(false, pool_b_accounts),
+            self.execute_instruction(),
+        ];
+        self.process(&instructions, &[]).await.unwrap();
+    }
+
+    /// Gathers pools A and C in one page each and executes the round
+    pub async fn crank_c(&mut self, pool_a_accounts: &[Pubkey], pool_c_accounts: &[Pubkey]) {
+        let instructions = [
+            self.gather_c_instruction(true, pool_a_accounts),
+            self.gather_c_instruction(false, pool_c_accounts),
+            self.execute_c_instruction(),
+        ];
+        self.process(&instructions, &[]).await.unwrap();
+    }
+
+    /// Creates a basket stream out of pool A buying the mints of pools B and C
+    pub async fn create_basket_stream(
+        &mut self,
+        user: usize,
+        user_index: u32,
+        stream_index: u32,
+        weights_bps: (u16, u16),
+        params: StreamParams,
+    ) -> Pubkey {
+        let user_account = self.user_address(&self.pool_a.pool, user_index);
+        let stream = self.stream_address(&user_account, stream_index);
+        let instruction = instruction::create_basket_stream(
+            &self.program_id,
+            &self.users[user].keypair.pubkey(),
+            &user_account,
+            &self.pool_a.pool,
+            &stream,
+            &self.pool_a.mint,
+            StreamDirection::Buy,
+            &[(self.pool_b.mint, weights_bps.0), (self.pool_c.mint, weights_bps.1)],
+            params,
+        );
+        let user_keypair = Keypair::from_bytes(&self.users[user].keypair.to_bytes()).unwrap();
+        self.process(&[instruction], &[&user_keypair]).await.unwrap();
+        stream
+    }
+
+    /// Claims both outputs of a basket stream created with `create_basket_stream`
+    pub async fn claim_basket(&mut self, user: usize, user_index: u32, stream: &Pubkey) {
+        let instruction = instruction::claim(
+            &self.program_id,
+            &self.users[user].keypair.pubkey(),
+            &self.user_address(&self.pool_a.pool, user_index),
+            &self.pool_a.pool,
+            stream,
+            &[
+                (self.pool_b.pool, self.pool_b.vault, self.users[user].token_b),
+                (self.pool_c.pool, self.pool_c.vault, self.users[user].token_c),
+            ],
+        );
+        let user_keypair = Keypair::from_bytes(&self.users[user].keypair.to_bytes()).unwrap();
+        self.process(&[instruction], &[&user_keypair]).await.unwrap();
+    }
+
+    /// Claims the output of a stream out of pool A or B into the users token account of the other pool
+    pub async fn claim(&mut self, user: usize, is_pool_a: bool, user_index: u32, stream: &Pubkey) {
+        let instruction = self.claim_instruction(user, is_pool_a, user_index, stream);
+        let user_keypair = Keypair::from_bytes(&self.users[user].keypair.to_bytes()).unwrap();
+        self.process(&[instruction], &[&user_keypair]).await.unwrap();
+    }
+
+    pub fn claim_instruction(&self, user: usize, is_pool_a: bool, user_index: u32, stream: &Pubkey) -> Instruction {
+        let input_pool = self.pool(is_pool_a);
+        let output_pool = self.pool(!is_pool_a);
+        let user_token = if is_pool_a { self.users[user].token_b } else { self.users[user].token_a };
+        instruction::claim(
+            &self.program_id,
+            &self.users[user].keypair.pubkey(),
+            &self.user_address(&input_pool.pool, user_index),
+            &input_pool.pool,
+            stream,
+            &[(output_pool.pool, output_pool.vault, user_token)],
+        )
+    }
+
+    /// Cancels a stream out of pool A or B, paying its output into the users token account of the other pool
+    pub async fn cancel_stream(&mut self, user: usize, is_pool_a: bool, user_index: u32, stream: &Pubkey) {
+        let input_pool = self.pool(is_pool_a);
+        let output_pool = self.pool(!is_pool_a);
+        let user_token = if is_pool_a { self.users[user].token_b } else { self.users[user].token_a };
+        let instruction = instruction::cancel_stream(
+            &self.program_id,
+            &self.users[user].keypair.pubkey(),
+            &self.user_address(&input_pool.pool, user_index),
+            &input_pool.pool,
+            stream,
+            &[(output_pool.pool, output_pool.vault, user_token)],
+        );
+        let user_keypair = Keypair::from_bytes(&self.users[user].keypair.to_bytes()).unwrap();
+        self.process(&[instruction], &[&user_keypair]).await.unwrap();
+    }
+
+    pub fn update_stream_instruction(
+        &self,
+        user: usize,
+        is_pool_a: bool,
+        user_index: u32,
+        stream: &Pubkey,
+        params: StreamParams,
+    ) -> Instruction {
+        let input_pool = self.pool(is_pool_a);
+        instruction::update_stream(
+            &self.program_id,
+            &self.users[user].keypair.pubkey(),
+            &self.user_address(&input_pool.pool, user_index),
+            &input_pool.pool,
+            stream,
+            params,
+        )
+    }
+
+    /// Creates a rebalancing stream over pools A, B and C from the users account in pool A
+    pub async fn create_rebalance_stream(
+        &mut self,
+        user: usize,
+        user_index: u32,
+        schedule: Schedule,
+        drift_bps: u16,
+        max_trade_value: u64,
+        weights_bps: [u16; 3],
+    ) -> Pubkey {
+        let user_account = self.user_address(&self.pool_a.pool, user_index);
+        let (stream, _) = pda::find_rebalance_stream_address(&user_account, &self.program_id);
+        let targets: Vec<(Pubkey, u16)> = [&self.pool_a, &self.pool_b, &self.pool_c]
+            .iter()
+            .zip(weights_bps.iter())
+            .map(|(test_pool, weight_bps)| (test_pool.mint, *weight_bps))
+            .collect();
+        let instruction = instruction::create_rebalance_stream(
+            &self.program_id,
+            &self.users[user].keypair.pubkey(),
+            &user_account,
+            &self.pool_a.pool,
+            &stream,
+            schedule,
+            drift_bps,
+            max_trade_value,
+            100,
+            &targets,
+        );
+        let user_keypair = Keypair::from_bytes(&self.users[user].keypair.to_bytes()).unwrap();
+        self.process(&[instruction], &[&user_keypair]).await.unwrap();
+        stream
+    }
+
+    /// Runs a rebalancing stream created with `create_rebalance_stream`, for the user slots in
+    /// pools A, B and C. A trade is expected out of pool A into pool C or B when `trade_into_c` is set.
+    pub async fn rebalance(&mut self, stream: &Pubkey, user_indices: [u32; 3], trade_into_c: Option<bool>) {
+        let holdings: Vec<(Pubkey, Pubkey)> = [&self.pool_a, &self.pool_b, &self.pool_c]
+            .iter()
+            .zip(user_indices.iter())
+            .map(|(test_pool, user_index)| (test_pool.pool, self.user_address(&test_pool.pool, *user_index)))
+            .collect();
+        let valuations = [
+            (self.route, self.swap_info, self.pool_a.swap_token_account, self.pool_b.swap_token_account),
+            (self.route_c, self.swap_c_info, self.swap_c_token_a, self.pool_c.swap_token_account),
+        ];
+        let trade = trade_into_c.map(|into_c| {
+            let (route, swap_info, swap_authority, swap_token_a, other_pool) = if into_c {
+                (&self.route_c, &self.swap_c_info, &self.swap_c_authority, &self.swap_c_token_a, &self.pool_c)
+            } else {
+                (&self.route, &self.swap_info, &self.swap_authority, &self.pool_a.swap_token_account, &self.pool_b)
+            };
+            let swap_accounts = self.swap_accounts(swap_info, swap_authority, swap_token_a, &other_pool.swap_token_account);
+            (*route, self.pool_a.vault, other_pool.vault, swap_accounts)
+        });
+
+        let instruction = instruction::rebalance(&self.program_id, stream, &holdings, &valuations, trade);
+        self.process(&[instruction], &[]).await.unwrap();
+    }
+
+    /// Creates a TWAP order buying pool B's mint with the users deposit in pool A
+    pub async fn create_twap_order(
+        &mut self,
+        user: usize,
+        user_index: u32,
+        order_id: u64,
+        total_amount: u64,
+        (start_ts, end_ts): (UnixTimestamp, UnixTimestamp),
+        slice_count: u32,
+        limit_price: Option<Price>,
+    ) -> Pubkey {
+        let user_account = self.user_address(&self.pool_a.pool, user_index);
+        let (order, _) = pda::find_twap_order_address(&user_account, order_id, &self.program_id);
+        let instruction = instruction::create_twap_order(
+            &self.program_id,
+            &self.users[user].keypair.pubkey(),
+            &user_account,
+            &self.pool_a.pool,
+            &order,
+            order_id,
+            &self.pool_b.mint,
+            StreamDirection::Buy,
+            total_amount,
+            start_ts,
+            end_ts,
+            slice_count,
+            limit_price,
+            100,
+        );
+        let user_keypair = Keypair::from_bytes(&self.users[user].keypair.to_bytes()).unwrap();
+        self.process(&[instruction], &[&user_keypair]).await.unwrap();
+        order
+    }
+
+    /// Executes the due slices of an order created with `create_twap_order`
+    pub async fn execute_twap_slice(&mut self, order: &Pubkey) {
+        let (reward_vault, _) = pda::find_reward_vault_address(&self.program_id);
+        let instruction = instruction::execute_twap_slice(
+            &self.program_id,
+            &self.context.payer.pubkey(),
+            &self.program_state,
+            &self.route,
+            &self.pool_a.pool,
+            &self.pool_a.vault,
+            &self.pool_b.pool,
+            &self.pool_b.vault,
+            &self.swap_accounts(&self.swap_info, &self.swap_authority, &self.pool_a.swap_token_account, &self.pool_b.swap_token_account),
+            &reward_vault,
+            order,
+        );
+        self.process(&[instruction], &[]).await.unwrap();
+    }
+
+    /// Closes an order created with `create_twap_order`, paying the output to the users token B account
+    pub async fn close_twap_order(&mut self, user: usize, user_index: u32, order: &Pubkey) {
+        let instruction = instruction::close_twap_order(
+            &self.program_id,
+            &self.users[user].keypair.pubkey(),
+            &self.user_address(&self.pool_a.pool, user_index),
+            order,
+            &self.pool_b.pool,
+            &self.pool_b.vault,
+            &self.users[user].token_b,
+        );
+        let user_keypair = Keypair::from_bytes(&self.users[user].keypair.to_bytes()).unwrap();
+        self.process(&[instruction], &[&user_keypair]).await.unwrap();
+    }
+
+    /// Withdraws from the users account in pool A or B to their token account, releasing the
+    /// given streams as `release` asks
+    pub async fn withdraw(
+        &mut self,
+        user: usize,
+        is_pool_a: bool,
+        user_index: u32,
+        amount: u64,
+        release: Option<StreamRelease>,
+        streams: &[Pubkey],
+    ) -> Result<(), BanksClientError> {
+        let test_pool = self.pool(is_pool_a);
+        let user_token = if is_pool_a { self.users[user].token_a } else { self.users[user].token_b };
+        let instruction = instruction::withdraw(
+            &self.program_id,
+            &self.users[user].keypair.pubkey(),
+            &self.user_address(&test_pool.pool, user_index),
+            &test_pool.pool,
+            &test_pool.vault,
+            &user_token,
+            None,
+            streams,
+            amount,
+            release,
+        );
+        let user_keypair = Keypair::from_bytes(&self.users[user].keypair.to_bytes()).unwrap();
+        self.process(&[instruction], &[&user_keypair]).await
+    }
+
+    /// Deposits lamports into pool B holding wrapped SOL for the user
+    pub async fn deposit_sol(&mut self, user: usize, user_index: u32, amount: u64) {
+        let instruction = instruction::deposit_sol(
+            &self.program_id,
+            &self.users[user].keypair.pubkey(),
+            &self.user_address(&self.pool_b.pool, user_index),
+            &self.pool_b.pool,
+            &self.pool_b.vault,
+            amount,
+        );
+        let user_keypair = Keypair::from_bytes(&self.users[user].keypair.to_bytes()).unwrap();
+        self.process(&[instruction], &[&user_keypair]).await.unwrap();
+    }
+
+    /// Claims the wrapped SOL bought by a stream out of pool A as lamports
+    pub async fn claim_sol(&mut self, user: usize, user_index: u32, stream: &Pubkey) {
+        let (unwrap_account, _) = pda::find_unwrap_address(&self.pool_b.pool, &self.program_id);
+        let instruction = instruction::claim_sol(
+            &self.program_id,
+            &self.users[user].keypair.pubkey(),
+            &self.user_address(&self.pool_a.pool, user_index),
+            &self.pool_a.pool,
+            stream,
+            &self.pool_b.pool,
+            &self.pool_b.vault,
+            &unwrap_account,
+        );
+        let user_keypair = Keypair::from_bytes(&self.users[user].keypair.to_bytes()).unwrap();
+        self.process(&[instruction], &[&user_keypair]).await.unwrap();
+    }
+
+    /// Withdraws wrapped SOL from the users account in pool B as lamports
+    pub async fn withdraw_sol(&mut self, user: usize, user_index: u32, amount: u64) {
+        let owner = self.users[user].keypair.pubkey();
+        self.withdraw_sol_to(user, user_index, amount, &owner).await;
+    }
+
+    /// Withdraws wrapped SOL from the users account in pool B as lamports paid to `destination`
+    pub async fn withdraw_sol_to(&mut self, user: usize, user_index: u32, amount: u64, destination: &Pubkey) {
+        let (unwrap_account, _) = pda::find_unwrap_address(&self.pool_b.pool, &self.program_id);
+        let instruction = instruction::withdraw(
+            &self.program_id,
+            &self.users[user].keypair.pubkey(),
+            &self.user_address(&self.pool_b.pool, user_index),
+            &self.pool_b.pool,
+            &self.pool_b.vault,
+            destination,
+            Some(&unwrap_account),
+            &[],
+            amount,
+            None,
+        );
+        let user_keypair = Keypair::from_bytes(&self.users[user].keypair.to_bytes()).unwrap();
+        self.process(&[instruction], &[&user_keypair]).await.unwrap();
+    }
+
+    /// Sends lamports from the payer to an address, as anyone can before a PDA is created
+    pub async fn fund_account(&mut self, account: &Pubkey, lamports: u64) {
+        let instruction = system_instruction::transfer(&self.context.payer.pubkey(), account, lamports);
+        self.process(&[instruction], &[]).await.unwrap();
+    }
+
+    /// Sets the keeper reward and the least round input of pool A and B paying it
+    pub async fn set_keeper_reward(&mut self, keeper_reward_lamports: u64, min_reward_input: u64) {
+        let deployer = self.context.payer.pubkey();
+        let mut instructions = vec![instruction::set_keeper_reward(&self.program_id, &deployer, &self.program_state, keeper_reward_lamports)];
+        for pool in [self.pool_a.pool, self.pool_b.pool].iter() {
+            instructions.push(instruction::set_min_reward_input(&self.program_id, &deployer, &self.program_state, pool, min_reward_input));
+        }
+        self.process(&instructions, &[]).await.unwrap();
+    }
+
+    /// Aborts the round of pools A and B as the deployer
+    pub async fn abort_round(&mut self) {
+        let deployer = self.context.payer.pubkey();
+        let instruction = instruction::abort_round(&self.program_id, &deployer, &self.program_state, &self.pool_a.pool, &self.pool_b.pool);
+        self.process(&[instruction], &[]).await.unwrap();
+    }
+
+    pub async fn lamports(&mut self, account: &Pubkey) -> u64 {
+        self.context.banks_client.get_balance(*account).await.unwrap()
+    }
+
+    /// Turns auto-delivery on or off for the users account in pool A or B
+    pub async fn set_auto_delivery(&mut self, user: usize, is_pool_a: bool, user_index: u32, enabled: bool) {
+        let instruction = instruction::set_auto_delivery(
+            &self.program_id,
+            &self.users[user].keypair.pubkey(),
+            &self.user_address(&self.pool(is_pool_a).pool, user_index),
+            enabled,
+        );
+        let user_keypair = Keypair::from_bytes(&self.users[user].keypair.to_bytes()).unwrap();
+        self.process(&[instruction], &[&user_keypair]).await.unwrap();
+    }
+
+    /// Instruction delivering the output of pool B, or pool C, to streams out of pool A. Every delivery
+    /// is a user account, its stream and the owners associated token account.
+    pub fn deliver_instruction(&self, to_pool_c: bool, deliveries: &[(Pubkey, Pubkey, Pubkey)]) -> Instruction {
+        let output_pool = if to_pool_c { &self.pool_c } else { &self.pool_b };
+        instruction::deliver(&self.program_id, &self.pool_a.pool, &output_pool.pool, &output_pool.vault, deliveries)
+    }
+
+    /// Associated token account of the user for the mint, which may not exist
+    pub fn associated_token_address(&self, user: usize, mint: &Pubkey) -> Pubkey {
+        pda::find_associated_token_address(&self.users[user].keypair.pubkey(), &mint.to_bytes())
+    }
+
+    /// Creates the users empty associated token account for the mint
+    pub fn create_associated_token_account(&mut self, user: usize, mint: &Pubkey) -> Pubkey {
+        let address = self.associated_token_address(user, mint);
+        let account = token_account(mint, &self.users[user].keypair.pubkey(), 0);
+        self.context.set_account(&address, &account.into());
+        address
+    }
+
+    /// Sets the reserves of the swap
+    pub fn set_swap_reserves(&mut self, reserve_a: u64, reserve_b: u64) {
+        for (test_pool, reserve) in [(&self.pool_a, reserve_a), (&self.pool_b, reserve_b)].iter() {
+            let account = token_account(&test_pool.mint, &self.swap_authority, *reserve);
+            self.context.set_account(&test_pool.swap_token_account, &account.into());
+        }
+    }
+
+    pub async fn token_balance(&mut self, token_account: &Pubkey) -> u64 {
+        let account = self.context.banks_client.get_account(*token_account).await.unwrap().unwrap();
+        spl_token::state::Account::unpack(&account.data).unwrap().amount
+    }
+
+    pub async fn pool_state(&mut self, pool: &Pubkey) -> Pool {
+        let account = self.context.banks_client.get_account(*pool).await.unwrap().unwrap();
+        Pool::unpack(&account.data).unwrap()
+    }
+
+    pub async fn stream(&mut self, stream: &Pubkey) -> Stream {
+        let account = self.context.banks_client.get_account(*stream).await.unwrap().unwrap();
+        Stream::unpack(&account.data).unwrap()
+    }
+
+    pub async fn rebalance_stream(&mut self, stream: &Pubkey) -> RebalanceStream {
+        let account = self.context.banks_client.get_account(*stream).await.unwrap().unwrap();
+        RebalanceStream::unpack(&account.data).unwrap()
+    }
+
+    pub async fn twap_order(&mut self, order: &Pubkey) -> TwapOrder {
+        let account = self.context.banks_client.get_account(*order).await.unwrap().unwrap();
+        TwapOrder::unpack(&account.data).unwrap()
+    }
+
+    pub async fn user_account(&mut self, user_account: &Pubkey) -> UserAccount {
+        let account = self.context.banks_client.get_account(*user_account).await.unwrap().unwrap();
+        UserAccount::unpack(&account.data).unwrap()
+    }
+
+    /// Overwrites the recorded balance of a user account
+    pub async fn set_user_balance(&mut self, user_account: &Pubkey, balance: u64) {
+        let mut account = self.context.banks_client.get_account(*user_account).await.unwrap().unwrap();
+        let mut state = UserAccount::unpack(&account.data).unwrap();
+        state.balance = balance;
+        UserAccount::pack(state, &mut account.data).unwrap();
+        self.context.set_account(user_account, &account.into());
+    }
+
+    /// Overwrites the recorded total output of a stream
+    pub async fn set_stream_total_output(&mut self, stream: &Pubkey, total_output: u64) {
+        let mut account = self.context.banks_client.get_account(*stream).await.unwrap().unwrap();
+        let mut state = Stream::unpack(&account.data).unwrap();
+        state.total_output = total_output;
+        Stream::pack(state, &mut account.data).unwrap();
+        self.context.set_account(stream, &account.into());
+    }
+
+    /// Creates a system account holding `lamports`, more than the payer could fund
+    pub fn set_lamports(&mut self, account: &Pubkey, lamports: u64) {
+        self.context.set_account(account, &Account::new(lamports, 0, &system_program::id()).into());
+    }
+}
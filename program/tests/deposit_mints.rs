@@ -0,0 +1,65 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    common::*,
+    solana_sdk::signature::Keypair,
+    step_finance::{
+        error::StepError,
+        schedule::{CatchUpPolicy, Schedule, SECONDS_PER_HOUR},
+        state::StreamDirection,
+    },
+};
+
+const AMOUNT: u64 = 100;
+
+#[tokio::test]
+async fn test_each_mint_funds_its_own_streams() {
+    let mut env = setup(1, 10_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+    env.deposit(0, false, 0, 500).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let params = stream_params(schedule, CatchUpPolicy::SkipMissed, AMOUNT);
+    let stream_a = env.create_stream(0, true, 0, 0, StreamDirection::Buy, params.clone()).await;
+    let stream_b = env.create_stream(0, false, 0, 0, StreamDirection::Sell, params.clone()).await;
+    let user_a = env.user_address(&env.pool_a.pool, 0);
+    let user_b = env.user_address(&env.pool_b.pool, 0);
+
+    env.crank(&[user_a, stream_a], &[user_b, stream_b]).await;
+
+    let user_account = env.user_account(&user_a).await;
+    assert_eq!(user_account.pool_pubkey, env.pool_a.pool.to_bytes());
+    assert_eq!(user_account.balance, 1_000 - AMOUNT);
+    let user_account = env.user_account(&user_b).await;
+    assert_eq!(user_account.pool_pubkey, env.pool_b.pool.to_bytes());
+    assert_eq!(user_account.balance, 500 - AMOUNT);
+
+    // A stream spending pool B's mint cannot be funded by the deposit in pool A
+    let (_, mut instruction) = env.create_stream_instruction(0, true, 0, 1, StreamDirection::Buy, params);
+    instruction.data[1..33].copy_from_slice(env.pool_b.mint.as_ref());
+    let user_keypair = Keypair::from_bytes(&env.users[0].keypair.to_bytes()).unwrap();
+    let error = env.process(&[instruction], &[&user_keypair]).await.unwrap_err().unwrap();
+    assert_eq!(error, step_error(StepError::MintMismatch));
+}
+
+#[tokio::test]
+async fn test_stream_needs_funded_balance() {
+    let mut env = setup(1, 10_000).await;
+    env.deposit(0, true, 0, 0).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let params = stream_params(schedule, CatchUpPolicy::SkipMissed, AMOUNT);
+
+    let (_, instruction) = env.create_stream_instruction(0, true, 0, 0, StreamDirection::Buy, params.clone());
+    let user_keypair = Keypair::from_bytes(&env.users[0].keypair.to_bytes()).unwrap();
+    let error = env.process(&[instruction], &[&user_keypair]).await.unwrap_err().unwrap();
+    assert_eq!(error, step_error(StepError::InsufficientBalance));
+
+    env.deposit(0, true, 0, AMOUNT).await;
+    env.create_stream(0, true, 0, 0, StreamDirection::Buy, params.clone()).await;
+
+    // The balance is funded but already reserved for the first stream
+    let (_, instruction) = env.create_stream_instruction(0, true, 0, 1, StreamDirection::Buy, params);
+    let error = env.process(&[instruction], &[&user_keypair]).await.unwrap_err().unwrap();
+    assert_eq!(error, step_error(StepError::InsufficientBalance));
+}
@@ -0,0 +1,74 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    common::*,
+    solana_program::pubkey::Pubkey,
+    step_finance::{
+        pda,
+        schedule::{CatchUpPolicy, Schedule, SECONDS_PER_HOUR},
+        state::StreamDirection,
+    },
+};
+
+const REWARD: u64 = 5_000;
+const MIN_REWARD_INPUT: u64 = 500;
+
+async fn setup_rewarded_stream(amount: u64) -> (TestEnv, Pubkey, Pubkey) {
+    let mut env = setup(1, 10_000).await;
+    env.set_keeper_reward(REWARD, MIN_REWARD_INPUT).await;
+    let (reward_vault, _) = pda::find_reward_vault_address(&env.program_id);
+    env.fund_account(&reward_vault, 10_000_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let stream = env.create_stream(0, true, 0, 0, StreamDirection::Buy, stream_params(schedule, CatchUpPolicy::SkipMissed, amount)).await;
+    (env, reward_vault, stream)
+}
+
+#[tokio::test]
+async fn test_keeper_is_paid_for_rounds_reaching_the_minimum() {
+    let (mut env, reward_vault, stream) = setup_rewarded_stream(MIN_REWARD_INPUT).await;
+    let user_a = env.user_address(&env.pool_a.pool, 0);
+    let vault_lamports = env.lamports(&reward_vault).await;
+
+    env.crank(&[user_a, stream], &[]).await;
+
+    assert_eq!(env.lamports(&reward_vault).await, vault_lamports - REWARD);
+}
+
+#[tokio::test]
+async fn test_keeper_is_not_paid_below_the_minimum() {
+    let (mut env, reward_vault, stream) = setup_rewarded_stream(MIN_REWARD_INPUT - 1).await;
+    let user_a = env.user_address(&env.pool_a.pool, 0);
+    let vault_lamports = env.lamports(&reward_vault).await;
+
+    env.crank(&[user_a, stream], &[]).await;
+
+    // The round is still executed
+    assert_eq!(env.lamports(&reward_vault).await, vault_lamports);
+    assert_eq!(env.user_account(&user_a).await.balance, 1_000 - (MIN_REWARD_INPUT - 1));
+    env.claim(0, true, 0, &stream).await;
+    assert_eq!(env.stream(&stream).await.total_output, MIN_REWARD_INPUT - 1);
+}
+
+#[tokio::test]
+async fn test_keeper_is_paid_for_twap_slices_reaching_the_minimum() {
+    let mut env = setup(1, 10_000).await;
+    env.set_keeper_reward(REWARD, MIN_REWARD_INPUT).await;
+    let (reward_vault, _) = pda::find_reward_vault_address(&env.program_id);
+    env.fund_account(&reward_vault, 10_000_000).await;
+    env.deposit(0, true, 0, 3_000).await;
+    let range = (START_TS, START_TS + 4 * SECONDS_PER_HOUR);
+    let vault_lamports = env.lamports(&reward_vault).await;
+
+    // Dust slices are still filled
+    let dust_order = env.create_twap_order(0, 0, 1, 4 * (MIN_REWARD_INPUT - 1), range, 4, None).await;
+    env.execute_twap_slice(&dust_order).await;
+    assert_eq!(env.twap_order(&dust_order).await.filled_input, MIN_REWARD_INPUT - 1);
+    assert_eq!(env.lamports(&reward_vault).await, vault_lamports);
+
+    let order = env.create_twap_order(0, 0, 2, MIN_REWARD_INPUT, range, 1, None).await;
+    env.execute_twap_slice(&order).await;
+    assert_eq!(env.lamports(&reward_vault).await, vault_lamports - REWARD);
+}
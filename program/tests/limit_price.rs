@@ -0,0 +1,151 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    common::*,
+    solana_sdk::signature::Keypair,
+    step_finance::{
+        error::StepError,
+        schedule::{CatchUpPolicy, Schedule, SECONDS_PER_HOUR},
+        state::{Price, StreamDirection},
+    },
+};
+
+const AMOUNT: u64 = 100;
+
+#[tokio::test]
+async fn test_buy_ceiling_carries_amount_over() {
+    // Pool A holds the quote asset and pool B the base asset
+    let mut env = setup(1, 10_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let mut params = stream_params(schedule, CatchUpPolicy::SkipMissed, AMOUNT);
+    params.limit_price = Some(Price { quote_amount: 1, base_amount: 1 });
+    params.carry_over = true;
+    let stream_address = env.create_stream(0, true, 0, 0, StreamDirection::Buy, params).await;
+    let accounts = vec![env.user_address(&env.pool_a.pool, 0), stream_address];
+
+    // The base asset costs two quote tokens, above the ceiling
+    env.set_swap_reserves(2 * SWAP_RESERVE, SWAP_RESERVE);
+    env.crank(&accounts, &[]).await;
+
+    let stream = env.stream(&stream_address).await;
+    assert_eq!(stream.executions, 0);
+    assert_eq!(stream.carried_amount, AMOUNT);
+    assert_eq!(stream.next_execution_ts, START_TS + SECONDS_PER_HOUR);
+    assert_eq!(env.user_account(&accounts[0]).await.balance, 1_000);
+
+    // Back at the ceiling, the skipped amount is bought with the next window
+    env.set_swap_reserves(SWAP_RESERVE, SWAP_RESERVE);
+    env.warp_to_timestamp(START_TS + SECONDS_PER_HOUR).await;
+    env.crank(&accounts, &[]).await;
+
+    let stream = env.stream(&stream_address).await;
+    assert_eq!(stream.executions, 1);
+    assert_eq!(stream.carried_amount, 0);
+    assert_eq!(stream.total_input, 2 * AMOUNT);
+    assert_eq!(stream.next_execution_ts, START_TS + 2 * SECONDS_PER_HOUR);
+    assert_eq!(env.user_account(&accounts[0]).await.balance, 1_000 - 2 * AMOUNT);
+
+    env.claim(0, true, 0, &stream_address).await;
+    let token_b = env.users[0].token_b;
+    assert_eq!(env.token_balance(&token_b).await, 10_000 + 2 * AMOUNT);
+}
+
+#[tokio::test]
+async fn test_sell_floor_skips_window() {
+    let mut env = setup(1, 10_000).await;
+    env.deposit(0, false, 0, 1_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let mut params = stream_params(schedule, CatchUpPolicy::SkipMissed, AMOUNT);
+    params.limit_price = Some(Price { quote_amount: 1, base_amount: 1 });
+    let stream_address = env.create_stream(0, false, 0, 0, StreamDirection::Sell, params).await;
+    let accounts = vec![env.user_address(&env.pool_b.pool, 0), stream_address];
+
+    // The base asset sells for half a quote token, below the floor
+    env.set_swap_reserves(SWAP_RESERVE, 2 * SWAP_RESERVE);
+    env.crank(&[], &accounts).await;
+
+    let stream = env.stream(&stream_address).await;
+    assert_eq!(stream.executions, 0);
+    assert_eq!(stream.carried_amount, 0);
+    assert_eq!(stream.next_execution_ts, START_TS + SECONDS_PER_HOUR);
+
+    // Without carry-over only the window's own amount is sold
+    env.set_swap_reserves(2 * SWAP_RESERVE, SWAP_RESERVE);
+    env.warp_to_timestamp(START_TS + SECONDS_PER_HOUR).await;
+    env.crank(&[], &accounts).await;
+
+    let stream = env.stream(&stream_address).await;
+    assert_eq!(stream.executions, 1);
+    assert_eq!(stream.total_input, AMOUNT);
+    assert_eq!(env.user_account(&accounts[0]).await.balance, 1_000 - AMOUNT);
+
+    env.claim(0, false, 0, &stream_address).await;
+    let token_a = env.users[0].token_a;
+    assert_eq!(env.token_balance(&token_a).await, 10_000 + 2 * AMOUNT);
+}
+
+#[tokio::test]
+async fn test_limit_is_checked_again_when_the_round_executes() {
+    let mut env = setup(2, 10_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+    env.deposit(1, true, 1, 1_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let mut params = stream_params(schedule.clone(), CatchUpPolicy::SkipMissed, AMOUNT);
+    params.limit_price = Some(Price { quote_amount: 1, base_amount: 1 });
+    params.carry_over = true;
+    let limit_stream = env.create_stream(0, true, 0, 0, StreamDirection::Buy, params.clone()).await;
+    let plain_stream = env.create_stream(1, true, 1, 0, StreamDirection::Buy, stream_params(schedule, CatchUpPolicy::SkipMissed, AMOUNT)).await;
+    let limit_user = env.user_address(&env.pool_a.pool, 0);
+    let plain_user = env.user_address(&env.pool_a.pool, 1);
+    let accounts = vec![limit_user, limit_stream, plain_user, plain_stream];
+
+    // Both streams are gathered at the ceiling, then the price moves above it before the round executes
+    let instructions = [env.gather_instruction(true, &accounts), env.gather_instruction(false, &[])];
+    env.process(&instructions, &[]).await.unwrap();
+    assert_eq!(env.user_account(&limit_user).await.balance, 1_000 - AMOUNT);
+
+    // The limit cannot be dropped while the gathered input waits for the round
+    let user_keypair = Keypair::from_bytes(&env.users[0].keypair.to_bytes()).unwrap();
+    let mut unlimited = params.clone();
+    unlimited.limit_price = None;
+    let update = env.update_stream_instruction(0, true, 0, &limit_stream, unlimited);
+    let error = env.process(&[update], &[&user_keypair]).await.unwrap_err().unwrap();
+    assert_eq!(error, step_error(StepError::RoundPending));
+    env.set_swap_reserves(2 * SWAP_RESERVE, SWAP_RESERVE);
+    let instruction = env.execute_instruction();
+    env.process(&[instruction], &[]).await.unwrap();
+
+    // Only the stream without a limit was swapped
+    let vault_a = env.pool_a.vault;
+    assert_eq!(env.token_balance(&vault_a).await, 2_000 - AMOUNT);
+
+    // Settling gives the withheld input back and carries it over, before an update once executed
+    let update = env.update_stream_instruction(0, true, 0, &limit_stream, params);
+    env.process(&[update], &[&user_keypair]).await.unwrap();
+    env.claim(0, true, 0, &limit_stream).await;
+    let stream = env.stream(&limit_stream).await;
+    assert_eq!(stream.executions, 0);
+    assert_eq!(stream.total_input, 0);
+    assert_eq!(stream.total_output, 0);
+    assert_eq!(stream.carried_amount, AMOUNT);
+    assert_eq!(stream.pending_input, 0);
+    assert_eq!(env.user_account(&limit_user).await.balance, 1_000);
+    let token_b = env.users[0].token_b;
+    assert_eq!(env.token_balance(&token_b).await, 10_000);
+
+    // Back at the ceiling, the carried amount is bought with the next window
+    env.set_swap_reserves(SWAP_RESERVE, SWAP_RESERVE);
+    env.warp_to_timestamp(START_TS + SECONDS_PER_HOUR).await;
+    env.crank(&accounts, &[]).await;
+    env.claim(0, true, 0, &limit_stream).await;
+
+    let stream = env.stream(&limit_stream).await;
+    assert_eq!(stream.executions, 1);
+    assert_eq!(stream.total_input, 2 * AMOUNT);
+    assert_eq!(env.user_account(&limit_user).await.balance, 1_000 - 2 * AMOUNT);
+    assert_eq!(env.token_balance(&token_b).await, 10_000 + 2 * AMOUNT);
+    assert!(env.stream(&plain_stream).await.total_output > 0);
+}
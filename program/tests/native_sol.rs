@@ -0,0 +1,86 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    common::*,
+    solana_sdk::signature::Signer,
+    step_finance::{
+        pda,
+        schedule::{CatchUpPolicy, Schedule, SECONDS_PER_HOUR},
+        state::StreamDirection,
+    },
+};
+
+const AMOUNT: u64 = 100;
+
+#[tokio::test]
+async fn test_sol_is_wrapped_on_deposit_and_unwrapped_on_claim() {
+    // Pool B holds wrapped SOL
+    let mut env = setup_native_b(2, 10_000).await;
+    env.deposit_sol(0, 0, 1_000).await;
+    let sol_user_account = env.user_address(&env.pool_b.pool, 0);
+    assert_eq!(env.user_account(&sol_user_account).await.balance, 1_000);
+    let vault_b = env.pool_b.vault;
+    assert_eq!(env.token_balance(&vault_b).await, 1_000);
+
+    env.deposit(1, true, 0, 1_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let params = stream_params(schedule, CatchUpPolicy::SkipMissed, AMOUNT);
+    let stream_address = env.create_stream(1, true, 0, 0, StreamDirection::Buy, params).await;
+    let accounts = vec![env.user_address(&env.pool_a.pool, 0), stream_address];
+    env.crank(&accounts, &[sol_user_account]).await;
+    assert_eq!(env.token_balance(&vault_b).await, 1_000 + AMOUNT);
+
+    let owner = env.users[1].keypair.pubkey();
+    let lamports = env.lamports(&owner).await;
+    env.claim_sol(1, 0, &stream_address).await;
+
+    assert_eq!(env.lamports(&owner).await, lamports + AMOUNT);
+    assert_eq!(env.token_balance(&vault_b).await, 1_000);
+    let stream = env.stream(&stream_address).await;
+    assert_eq!(stream.total_output, AMOUNT);
+    assert_eq!(stream.output_balance, 0);
+}
+
+#[tokio::test]
+async fn test_sol_is_unwrapped_on_withdrawal() {
+    let mut env = setup_native_b(1, 10_000).await;
+    env.deposit_sol(0, 0, 1_000).await;
+    let owner = env.users[0].keypair.pubkey();
+    let lamports = env.lamports(&owner).await;
+
+    env.withdraw_sol(0, 0, 300).await;
+
+    assert_eq!(env.lamports(&owner).await, lamports + 300);
+    let user_address = env.user_address(&env.pool_b.pool, 0);
+    assert_eq!(env.user_account(&user_address).await.balance, 700);
+    let vault_b = env.pool_b.vault;
+    assert_eq!(env.token_balance(&vault_b).await, 700);
+}
+
+#[tokio::test]
+async fn test_prefunded_unwrap_account_still_unwraps() {
+    let mut env = setup_native_b(2, 10_000).await;
+    env.deposit_sol(0, 0, 1_000).await;
+
+    // The unwrap account sits at a fixed address anyone can send lamports to
+    let (unwrap_account, _) = pda::find_unwrap_address(&env.pool_b.pool, &env.program_id);
+    env.fund_account(&unwrap_account, 1_000_000).await;
+
+    // Lamports can be withdrawn to any account, the owner gets back the rent of the unwrap account
+    let owner = env.users[0].keypair.pubkey();
+    let lamports = env.lamports(&owner).await;
+    let destination = env.users[1].keypair.pubkey();
+    let destination_lamports = env.lamports(&destination).await;
+    env.withdraw_sol_to(0, 0, 300, &destination).await;
+    assert_eq!(env.lamports(&destination).await, destination_lamports + 300);
+    assert_eq!(env.lamports(&unwrap_account).await, 0);
+    assert_eq!(env.lamports(&owner).await, lamports + 1_000_000);
+
+    let lamports = env.lamports(&owner).await;
+    env.withdraw_sol(0, 0, 200).await;
+    assert_eq!(env.lamports(&owner).await, lamports + 200);
+    let user_address = env.user_address(&env.pool_b.pool, 0);
+    assert_eq!(env.user_account(&user_address).await.balance, 500);
+}
@@ -0,0 +1,58 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    common::*,
+    step_finance::{
+        schedule::{CatchUpPolicy, Schedule, SECONDS_PER_HOUR},
+        state::StreamDirection,
+    },
+};
+
+#[tokio::test]
+async fn test_only_the_imbalance_is_swapped() {
+    // One A trades for two B
+    let mut env = setup(2, 10_000).await;
+    env.set_swap_reserves(SWAP_RESERVE, 2 * SWAP_RESERVE);
+    env.deposit(0, true, 0, 1_000).await;
+    env.deposit(1, false, 0, 1_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let buy_stream = env
+        .create_stream(0, true, 0, 0, StreamDirection::Buy, stream_params(schedule.clone(), CatchUpPolicy::SkipMissed, 300))
+        .await;
+    let sell_stream = env
+        .create_stream(1, false, 0, 0, StreamDirection::Sell, stream_params(schedule, CatchUpPolicy::SkipMissed, 200))
+        .await;
+    let user_a = env.user_address(&env.pool_a.pool, 0);
+    let user_b = env.user_address(&env.pool_b.pool, 0);
+
+    env.crank(&[user_a, buy_stream], &[user_b, sell_stream]).await;
+
+    // The 200 B of the sell stream match 100 A of the buy stream, only the other 200 A are swapped
+    let swap_a = env.pool_a.swap_token_account;
+    let swap_b = env.pool_b.swap_token_account;
+    assert_eq!(env.token_balance(&swap_a).await, SWAP_RESERVE + 200);
+    assert_eq!(env.token_balance(&swap_b).await, 2 * SWAP_RESERVE - 400);
+
+    env.claim(0, true, 0, &buy_stream).await;
+    env.claim(1, false, 0, &sell_stream).await;
+
+    let buy = env.stream(&buy_stream).await;
+    assert_eq!(buy.total_input, 300);
+    assert_eq!(buy.total_output, 200 + 400);
+    let sell = env.stream(&sell_stream).await;
+    assert_eq!(sell.total_input, 200);
+    assert_eq!(sell.total_output, 100);
+
+    let token_b = env.users[0].token_b;
+    let token_a = env.users[1].token_a;
+    assert_eq!(env.token_balance(&token_b).await, 10_000 + 600);
+    assert_eq!(env.token_balance(&token_a).await, 10_000 + 100);
+
+    // Each vault keeps the deposits that were not spent
+    let vault_a = env.pool_a.vault;
+    let vault_b = env.pool_b.vault;
+    assert_eq!(env.token_balance(&vault_a).await, 1_000 - 300);
+    assert_eq!(env.token_balance(&vault_b).await, 1_000 - 200);
+}
@@ -0,0 +1,111 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    common::*,
+    solana_program::pubkey::Pubkey,
+    solana_sdk::signature::Keypair,
+    step_finance::{
+        error::StepError,
+        pda,
+        schedule::{CatchUpPolicy, Schedule, SECONDS_PER_HOUR},
+        state::StreamDirection,
+    },
+};
+
+const REWARD: u64 = 5_000;
+
+#[tokio::test]
+async fn test_deposit_up_to_u64_max() {
+    let mut env = setup(1, 10_000).await;
+    env.deposit(0, true, 0, 100).await;
+    let user_account = env.user_address(&env.pool_a.pool, 0);
+    env.set_user_balance(&user_account, u64::MAX - 100).await;
+
+    let error = env.try_deposit(0, true, 0, 101).await.unwrap_err().unwrap();
+    assert_eq!(error, step_error(StepError::AmountOverflow));
+    assert_eq!(env.user_account(&user_account).await.balance, u64::MAX - 100);
+
+    env.try_deposit(0, true, 0, 100).await.unwrap();
+    assert_eq!(env.user_account(&user_account).await.balance, u64::MAX);
+}
+
+#[tokio::test]
+async fn test_gather_up_to_u64_max() {
+    let mut env = setup(3, 10_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let amounts = [u64::MAX / 2 + 1, u64::MAX / 2, 1];
+    let mut accounts = vec![];
+    for (user, amount) in amounts.iter().enumerate() {
+        env.deposit(user, true, user as u32, 100).await;
+        let user_account = env.user_address(&env.pool_a.pool, user as u32);
+        env.set_user_balance(&user_account, *amount).await;
+        let params = stream_params(schedule.clone(), CatchUpPolicy::SkipMissed, *amount);
+        let stream = env.create_stream(user, true, user as u32, 0, StreamDirection::Buy, params).await;
+        accounts.push(user_account);
+        accounts.push(stream);
+    }
+
+    // The third stream takes the round input past u64::MAX
+    let instruction = env.gather_instruction(true, &accounts);
+    let error = env.process(&[instruction], &[]).await.unwrap_err().unwrap();
+    assert_eq!(error, step_error(StepError::AmountOverflow));
+
+    let instruction = env.gather_instruction(true, &accounts[..4]);
+    env.process(&[instruction], &[]).await.unwrap();
+    let pool_a = env.pool_a.pool;
+    assert_eq!(env.pool_state(&pool_a).await.round_input, u64::MAX);
+}
+
+#[tokio::test]
+async fn test_settle_up_to_u64_max() {
+    let mut env = setup(1, 10_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let stream = env.create_stream(0, true, 0, 0, StreamDirection::Buy, stream_params(schedule, CatchUpPolicy::SkipMissed, 100)).await;
+    let user_a = env.user_address(&env.pool_a.pool, 0);
+    env.crank(&[user_a, stream], &[]).await;
+    let pool_a = env.pool_a.pool;
+    let output = env.pool_state(&pool_a).await.settled_output;
+
+    env.set_stream_total_output(&stream, u64::MAX - output + 1).await;
+    let instruction = env.claim_instruction(0, true, 0, &stream);
+    let user_keypair = Keypair::from_bytes(&env.users[0].keypair.to_bytes()).unwrap();
+    let error = env.process(&[instruction], &[&user_keypair]).await.unwrap_err().unwrap();
+    assert_eq!(error, step_error(StepError::AmountOverflow));
+
+    env.set_stream_total_output(&stream, u64::MAX - output).await;
+    env.warp_to_timestamp(START_TS + 1).await;
+    env.claim(0, true, 0, &stream).await;
+    assert_eq!(env.stream(&stream).await.total_output, u64::MAX);
+}
+
+#[tokio::test]
+async fn test_keeper_reward_up_to_u64_max() {
+    let mut env = setup(1, 10_000).await;
+    env.set_keeper_reward(REWARD, 0).await;
+    let (reward_vault, _) = pda::find_reward_vault_address(&env.program_id);
+    env.fund_account(&reward_vault, 10_000_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let stream = env.create_stream(0, true, 0, 0, StreamDirection::Buy, stream_params(schedule, CatchUpPolicy::SkipMissed, 100)).await;
+    let user_a = env.user_address(&env.pool_a.pool, 0);
+    let instructions = [env.gather_instruction(true, &[user_a, stream]), env.gather_instruction(false, &[])];
+    env.process(&instructions, &[]).await.unwrap();
+
+    // A keeper whose balance the reward would overflow cannot execute the round
+    let full_keeper = Pubkey::new_unique();
+    env.set_lamports(&full_keeper, u64::MAX - REWARD + 1);
+    let mut instruction = env.execute_instruction();
+    instruction.accounts[0].pubkey = full_keeper;
+    let error = env.process(&[instruction], &[]).await.unwrap_err().unwrap();
+    assert_eq!(error, step_error(StepError::AmountOverflow));
+
+    let keeper = Pubkey::new_unique();
+    env.set_lamports(&keeper, u64::MAX - REWARD);
+    let mut instruction = env.execute_instruction();
+    instruction.accounts[0].pubkey = keeper;
+    env.process(&[instruction], &[]).await.unwrap();
+    assert_eq!(env.lamports(&keeper).await, u64::MAX);
+}
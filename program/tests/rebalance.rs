@@ -0,0 +1,41 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    common::*,
+    step_finance::schedule::{Schedule, SECONDS_PER_HOUR},
+};
+
+#[tokio::test]
+async fn test_rebalance_moves_overweight_deposit_in_capped_steps() {
+    let mut env = setup(1, 10_000).await;
+    env.deposit(0, true, 0, 600).await;
+    env.deposit(0, false, 0, 200).await;
+    env.deposit_c(0, 0, 200).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let stream = env.create_rebalance_stream(0, 0, schedule, 500, 80, [4_000, 3_000, 3_000]).await;
+    let user_a = env.user_address(&env.pool_a.pool, 0);
+    let user_b = env.user_address(&env.pool_b.pool, 0);
+    let user_c = env.user_address(&env.pool_c.pool, 0);
+
+    // Pool A is 200 over its target and B and C are 100 under, the step is capped at 80
+    env.rebalance(&stream, [0, 0, 0], Some(false)).await;
+    assert_eq!(env.user_account(&user_a).await.balance, 520);
+    assert_eq!(env.user_account(&user_b).await.balance, 280);
+
+    // C is now the most underweight
+    env.warp_to_timestamp(START_TS + SECONDS_PER_HOUR).await;
+    env.rebalance(&stream, [0, 0, 0], Some(true)).await;
+    assert_eq!(env.user_account(&user_a).await.balance, 440);
+    assert_eq!(env.user_account(&user_c).await.balance, 280);
+
+    // A drifts 400 bps from its target, within the threshold
+    env.warp_to_timestamp(START_TS + 2 * SECONDS_PER_HOUR).await;
+    env.rebalance(&stream, [0, 0, 0], None).await;
+    assert_eq!(env.user_account(&user_a).await.balance, 440);
+
+    let stream = env.rebalance_stream(&stream).await;
+    assert_eq!(stream.rebalances, 2);
+    assert_eq!(stream.next_execution_ts, START_TS + 3 * SECONDS_PER_HOUR);
+}
@@ -0,0 +1,49 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    common::*,
+    step_finance::{schedule::SECONDS_PER_HOUR, state::Price},
+};
+
+#[tokio::test]
+async fn test_twap_slices_roll_forward_past_limit() {
+    // Pool A holds the quote asset and pool B the base asset
+    let mut env = setup(1, 10_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+    let range = (START_TS, START_TS + 4 * SECONDS_PER_HOUR);
+    let limit_price = Some(Price { quote_amount: 1, base_amount: 1 });
+    let order_address = env.create_twap_order(0, 0, 7, 400, range, 4, limit_price).await;
+    let user_address = env.user_address(&env.pool_a.pool, 0);
+    assert_eq!(env.user_account(&user_address).await.balance, 600);
+
+    env.execute_twap_slice(&order_address).await;
+    let order = env.twap_order(&order_address).await;
+    assert_eq!(order.next_slice, 1);
+    assert_eq!(order.filled_input, 100);
+    assert_eq!(order.output_balance, 100);
+
+    // The base asset costs two quote tokens, above the ceiling, so the second slice is not filled
+    env.set_swap_reserves(2 * SWAP_RESERVE, SWAP_RESERVE);
+    env.warp_to_timestamp(START_TS + SECONDS_PER_HOUR).await;
+    env.execute_twap_slice(&order_address).await;
+    let order = env.twap_order(&order_address).await;
+    assert_eq!(order.next_slice, 2);
+    assert_eq!(order.filled_input, 100);
+
+    // Back at the ceiling, the third slice takes the input of the second with it
+    env.set_swap_reserves(SWAP_RESERVE, SWAP_RESERVE);
+    env.warp_to_timestamp(START_TS + 2 * SECONDS_PER_HOUR).await;
+    env.execute_twap_slice(&order_address).await;
+    let order = env.twap_order(&order_address).await;
+    assert_eq!(order.next_slice, 3);
+    assert_eq!(order.filled_input, 300);
+    assert_eq!(order.total_output, 300);
+
+    // Closing before the last slice returns its input to the deposit
+    env.close_twap_order(0, 0, &order_address).await;
+    assert_eq!(env.user_account(&user_address).await.balance, 700);
+    let token_b = env.users[0].token_b;
+    assert_eq!(env.token_balance(&token_b).await, 10_000 + 300);
+}
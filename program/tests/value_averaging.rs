@@ -0,0 +1,48 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    common::*,
+    step_finance::{
+        schedule::{CatchUpPolicy, Schedule, SECONDS_PER_HOUR},
+        state::{Strategy, StreamDirection},
+    },
+};
+
+#[tokio::test]
+async fn test_value_averaging_follows_price() {
+    // Pool A holds the quote asset and pool B the base asset
+    let mut env = setup(1, 10_000).await;
+    env.deposit(0, true, 0, 1_000).await;
+    let schedule = Schedule::Interval { interval_seconds: SECONDS_PER_HOUR };
+    let mut params = stream_params(schedule, CatchUpPolicy::SkipMissed, 0);
+    params.strategy = Strategy::ValueAveraging { target_growth: 100, min_amount: 10, max_amount: 120 };
+    let stream_address = env.create_stream(0, true, 0, 0, StreamDirection::Buy, params).await;
+    let accounts = vec![env.user_address(&env.pool_a.pool, 0), stream_address];
+
+    // Nothing is accumulated yet, the first window buys the target growth
+    env.crank(&accounts, &[]).await;
+    let stream = env.stream(&stream_address).await;
+    assert_eq!(stream.total_input, 100);
+
+    // The base asset halves in price, the 100 bought are worth 50 against a target of 200
+    // so 150 are needed, bounded by the maximum of 120
+    env.set_swap_reserves(SWAP_RESERVE, 2 * SWAP_RESERVE);
+    env.warp_to_timestamp(START_TS + SECONDS_PER_HOUR).await;
+    env.crank(&accounts, &[]).await;
+    let stream = env.stream(&stream_address).await;
+    assert_eq!(stream.total_output, 100);
+    assert_eq!(stream.total_input, 220);
+
+    // The base asset is back above its starting price, the 340 bought are worth 680 against
+    // a target of 300 so only the minimum is spent
+    env.set_swap_reserves(2 * SWAP_RESERVE, SWAP_RESERVE);
+    env.warp_to_timestamp(START_TS + 2 * SECONDS_PER_HOUR).await;
+    env.crank(&accounts, &[]).await;
+    let stream = env.stream(&stream_address).await;
+    assert_eq!(stream.total_output, 340);
+    assert_eq!(stream.total_input, 230);
+    assert_eq!(stream.executions, 3);
+    assert_eq!(env.user_account(&accounts[0]).await.balance, 1_000 - 230);
+}
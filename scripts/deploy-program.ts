@@ -16,9 +16,17 @@ import {
   TOKEN_PROGRAM_ID,
 } from "@solana/spl-token";
 import { WRAPPED_SOL_MINT } from "../src/utils/ids";
-
-import BN from "bn.js";
-import * as BufferLayout from "buffer-layout";
+import {
+  INIT_PROGRAM_TAG,
+  PROGRAM_STATE_LAYOUT,
+  STEP_POOL_NAME,
+  findPoolAddress,
+  findProgramStateAddress,
+  findVaultAddress,
+  getPool,
+  initPoolInstruction,
+  poolName,
+} from "../src/actions/step";
 
 import {getOurAccount} from './lib/account';
 import {getNodeConnection} from './lib/connection';
@@ -59,17 +67,17 @@ async function main() {
   console.log("Program loaded to:",programId.toBase58()," cost was:", costLoad, " lamports (", costLoad/LAMPORTS_PER_SOL, ") Sol")
 
 
-  const PROGRAM_STATE_PDA = await PublicKey.findProgramAddress([Buffer.from("step_program_state-123456789abcb")], programId);
+  const programStateAddress = await findProgramStateAddress(programId);
   // Init Program
   const initTx = new TransactionInstruction({
       programId: programId,
       keys: [
-        { pubkey: ourAccount.publicKey, isSigner: true, isWritable: false },
-        { pubkey: PROGRAM_STATE_PDA[0], isSigner: false, isWritable: true },
+        { pubkey: ourAccount.publicKey, isSigner: true, isWritable: true },
+        { pubkey: programStateAddress, isSigner: false, isWritable: true },
         { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
         { pubkey: SYSVAR_RENT_PUBKEY, isSigner: false, isWritable: false},
       ],
-      data: Buffer.from(Uint8Array.of(0))
+      data: Buffer.from(Uint8Array.of(INIT_PROGRAM_TAG))
   })
 
   let tx = new Transaction()
@@ -83,54 +91,29 @@ async function main() {
   // NEED THIS OTHERWISE PROGRAM STATE ACCOUNT IS NOT ON CHAIN YET
   await new Promise((resolve) => setTimeout(resolve, 2000));
 
-  const publicKey = (property = "publicKey") => {
-    return BufferLayout.blob(32, property);
-  };
-
-  const uint64 = (property: string = 'uint64'): Object => {
-    return BufferLayout.blob(8, property);
-  };
-
-  const PROGRAM_STATE_DATA_LAYOUT = BufferLayout.struct([
-    BufferLayout.u8("isInitialized"),
-    publicKey("deployerPubkey")
-  ]);
-
-  interface ProgramStateLayout {
-    isInitialized: number,
-    deployerPubkey: Uint8Array,
-  }
-
-  const encodedEscrowState = (await connection.getAccountInfo(PROGRAM_STATE_PDA[0], 'singleGossip'))!.data;
-  const decodedEscrowState = PROGRAM_STATE_DATA_LAYOUT.decode(encodedEscrowState) as ProgramStateLayout;
+  const encodedProgramState = (await connection.getAccountInfo(programStateAddress, 'singleGossip'))!.data;
+  const decodedProgramState = PROGRAM_STATE_LAYOUT.decode(encodedProgramState);
   console.log( {
-      escrowAccountPubkey: new PublicKey(decodedEscrowState.deployerPubkey).toBase58(),
-      isInitialized: !!decodedEscrowState.isInitialized,
+      deployerPubkey: decodedProgramState.deployerPubkey.toBase58(),
+      isInitialized: !!decodedProgramState.isInitialized,
+      keeperRewardLamports: decodedProgramState.keeperRewardLamports.toString(),
   });
 
-  // Init Pool
+  // Init Pool, derived from its mint and name
   const mintPublicKey = WRAPPED_SOL_MINT;//new PublicKey('Ha5VtSz9aH6Qx55fJhfimMFeksB4pLgFQoU6F9CQzSgh');
-  const TOKEN_ACCOUNT_PDA = await PublicKey.findProgramAddress([Buffer.from("seedy")], programId);
-  const POOL_INFO_SEED = Buffer.from("sol-pool-123456789abcdefghijklmn");
-  const POOL_INFO_PDA = await PublicKey.findProgramAddress([POOL_INFO_SEED], programId);
-
-  const initPoolTx = new TransactionInstruction({
-      programId: programId,
-      keys: [
-          { pubkey: ourAccount.publicKey, isSigner: true, isWritable: false },
-          { pubkey: PROGRAM_STATE_PDA[0], isSigner: false, isWritable: false },
-          { pubkey: POOL_INFO_PDA[0], isSigner: false, isWritable: true },
-          { pubkey: TOKEN_ACCOUNT_PDA[0], isSigner: false, isWritable: true },
-          { pubkey: mintPublicKey, isSigner: false, isWritable: false},
-          { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
-          { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
-          { pubkey: SYSVAR_RENT_PUBKEY, isSigner: false, isWritable: false},
-      ],
-      data: Buffer.from(Uint8Array.of(1, ...POOL_INFO_SEED))
-  })
+  const poolAddress = await findPoolAddress(mintPublicKey, STEP_POOL_NAME, programId);
+  const vaultAddress = await findVaultAddress(poolAddress, programId);
 
   tx = new Transaction()
-    .add(initPoolTx);
+    .add(initPoolInstruction(
+      ourAccount.publicKey,
+      programStateAddress,
+      poolAddress,
+      vaultAddress,
+      mintPublicKey,
+      STEP_POOL_NAME,
+      programId
+    ));
   await connection.sendTransaction(
       tx,
       [ourAccount],
@@ -140,40 +123,14 @@ async function main() {
   // NEED THIS OTHERWISE PROGRAM STATE ACCOUNT IS NOT ON CHAIN YET
   await new Promise((resolve) => setTimeout(resolve, 2000));
 
-  interface UserAccountLayout {
-    balance: number
-  }
-
-  interface PoolLayout {
-    isInitialized: number,
-    mintPubkey: Uint8Array,
-    pdaSeed: Uint8Array,
-    userAccount: UserAccountLayout,
-  }
-
-  const USER_ACCOUNT_DATA_LAYOUT = BufferLayout.struct([
-    BufferLayout.u32("accountIndex"),
-    uint64("balance"),
-  ], 'userAccount');
-
-  const POOL_DATA_LAYOUT = BufferLayout.struct([
-    BufferLayout.u8("isInitialized"),
-    publicKey("mintPubkey"),
-    BufferLayout.blob(32, "pdaSeed"),
-    USER_ACCOUNT_DATA_LAYOUT,
-  ]);
-
-
-
-  const encodedPoolState = (await connection.getAccountInfo(POOL_INFO_PDA[0], 'singleGossip'))!.data;
-  const decodedPoolState = POOL_DATA_LAYOUT.decode(encodedPoolState) as PoolLayout;
+  const decodedPoolState = await getPool(connection, poolAddress);
   console.log( {
-    mintPubkey: new PublicKey(decodedPoolState.mintPubkey).toBase58(),
+    pool: poolAddress.toBase58(),
+    vault: vaultAddress.toBase58(),
+    mintPubkey: decodedPoolState.mintPubkey.toBase58(),
     isInitialized: !!decodedPoolState.isInitialized,
-    pdaSeed: decodedPoolState.pdaSeed.toString(),
-    account: {
-      balance: decodedPoolState.userAccount.balance
-    },
+    name: poolName(decodedPoolState),
+    userCount: decodedPoolState.userCount,
   });
 
   console.log("-----")
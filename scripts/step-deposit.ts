@@ -15,10 +15,15 @@ import {
   TOKEN_PROGRAM_ID,
 } from "@solana/spl-token";
 
-import {
-  findAssociatedTokenAddress
-} from "../src/contexts/spl-token";
 import { WRAPPED_SOL_MINT } from "../src/utils/ids";
+import {
+  STEP_POOL_NAME,
+  depositInstruction,
+  findPoolAddress,
+  findUserAccount,
+  findVaultAddress,
+  getUserAccount,
+} from "../src/actions/step";
 
 import {getOurAccount} from './lib/account';
 import {getNodeConnection} from './lib/connection';
@@ -45,7 +50,7 @@ async function main() {
     SystemProgram.createAccount({
       fromPubkey: ourAccount.publicKey,
       newAccountPubkey: wrappedSOLAccount.publicKey,
-      lamports: depositAmount,
+      lamports: await connection.getMinimumBalanceForRentExemption(AccountLayout.span) + depositAmount,
       space: AccountLayout.span,
       programId: TOKEN_PROGRAM_ID,
     })
@@ -72,30 +77,32 @@ async function main() {
     )
   );
 
-  const ourTokenAccount = await findAssociatedTokenAddress(ourAccount.publicKey, mintPublicKey);
-  console.log("Our token account:", ourTokenAccount.toString());
-  const PDA = await PublicKey.findProgramAddress([Buffer.from("seedy")], stepProgram);
-  console.log("PDA: ", PDA.toString());
-
-  instructions.push(new TransactionInstruction({
-      programId: stepProgram,
-      keys: [
-          { pubkey: ourAccount.publicKey, isSigner: true, isWritable: false },
-          { pubkey: ourTokenAccount, isSigner: false, isWritable: true },
-          { pubkey: PDA[0], isSigner: false, isWritable: true },
-          { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
-      ],
-      data: Buffer.from(Uint8Array.of(1, ...new BN(depositAmount).toArray("le", 8)))
-  })
-
-  const tx = new Transaction()
-      .add(depositTx);
+  const pool = await findPoolAddress(mintPublicKey, STEP_POOL_NAME, stepProgram);
+  const vault = await findVaultAddress(pool, stepProgram);
+  const userAccount = await findUserAccount(connection, pool, ourAccount.publicKey, stepProgram);
+  console.log("Pool:", pool.toString());
+  console.log("User account:", userAccount.address.toString(), userAccount.isNew ? "created in slot" : "in slot", userAccount.userIndex);
+
+  instructions.push(depositInstruction(
+    ourAccount.publicKey,
+    wrappedSOLAccount.publicKey,
+    userAccount.address,
+    pool,
+    vault,
+    new BN(depositAmount),
+    stepProgram
+  ));
+
+  const tx = new Transaction();
+  instructions.concat(cleanupInstructions).forEach((instruction) => tx.add(instruction));
   await connection.sendTransaction(
       tx,
-      [ourAccount],
+      [ourAccount, ...signers],
       {skipPreflight: false, preflightCommitment: 'singleGossip'}
   );
 
+  const depositedUserAccount = await getUserAccount(connection, userAccount.address);
+  console.log("Balance:", depositedUserAccount?.balance.toString());
 
   console.log("-----")
 }
@@ -1,20 +1,11 @@
-import React, {
-  useCallback,
-  useContext,
-  useEffect,
-  useMemo,
-  useState,
-} from "react";
-
 import {
   Account,
   Connection,
   PublicKey,
   SystemProgram,
-  Transaction,
   LAMPORTS_PER_SOL,
+  SYSVAR_CLOCK_PUBKEY,
   SYSVAR_RENT_PUBKEY,
-  sendAndConfirmTransaction,
   TransactionInstruction
 } from "@solana/web3.js";
 import {
@@ -29,16 +20,312 @@ import {
 import { createUninitializedAccount } from "../actions/account";
 import { sendTransaction } from "../contexts/connection";
 import { WRAPPED_SOL_MINT } from "../utils/ids";
+import { publicKey, uint64 } from "../utils/layout";
 
 import BN from "bn.js";
-import { DexInstructions } from "@project-serum/serum";
+import * as BufferLayout from "buffer-layout";
 
 export const STEP_PROGRAM_ID: PublicKey = new PublicKey(
   'J8Nug8arcy4c85Cur7sU8Q551r9N8xLZhbU7e8XDGT9L',
 );
 
-export const STEP_TOKEN_PDA_SEED = [Buffer.from("seedy")];
-export const STEP_POOL_PDA_SEED = [Buffer.from("sol-pool-123456789abcdefghijklmn")];
+// Seeds of the program derived accounts, see program/src/pda.rs
+const PROGRAM_STATE_SEED = Buffer.from("step_program_state-123456789abcb");
+const POOL_SEED = Buffer.from("pool");
+const VAULT_SEED = Buffer.from("vault");
+const USER_SEED = Buffer.from("user");
+const STREAM_SEED = Buffer.from("stream");
+const ROUTE_SEED = Buffer.from("route");
+const REWARD_VAULT_SEED = Buffer.from("keeper_reward");
+
+// Name the pools of the app are created with, pools are derived from their mint and name
+export const STEP_POOL_NAME = "step";
+export const MAX_POOL_NAME_LEN = 32;
+
+// Instruction tags, in the order of StepInstruction in program/src/instruction.rs
+export const INIT_PROGRAM_TAG = 0;
+export const INIT_POOL_TAG = 1;
+export const DEPOSIT_TAG = 2;
+export const EXECUTE_TAG = 4;
+export const GATHER_TAG = 7;
+
+const u32le = (value: number) => Buffer.from(new BN(value).toArray("le", 4));
+
+const findAddress = async (seeds: Buffer[], programId: PublicKey) =>
+  (await PublicKey.findProgramAddress(seeds, programId))[0];
+
+export const findProgramStateAddress = (programId = STEP_PROGRAM_ID) =>
+  findAddress([PROGRAM_STATE_SEED], programId);
+
+export const findPoolAddress = (mint: PublicKey, name = STEP_POOL_NAME, programId = STEP_PROGRAM_ID) =>
+  findAddress([POOL_SEED, mint.toBuffer(), Buffer.from(name, "utf8")], programId);
+
+export const findVaultAddress = (pool: PublicKey, programId = STEP_PROGRAM_ID) =>
+  findAddress([pool.toBuffer(), VAULT_SEED], programId);
+
+export const findUserAddress = (pool: PublicKey, userIndex: number, programId = STEP_PROGRAM_ID) =>
+  findAddress([pool.toBuffer(), USER_SEED, u32le(userIndex)], programId);
+
+export const findStreamAddress = (userAccount: PublicKey, streamIndex: number, programId = STEP_PROGRAM_ID) =>
+  findAddress([userAccount.toBuffer(), STREAM_SEED, u32le(streamIndex)], programId);
+
+// A pair derives the same route whichever pool is the input
+export const findRouteAddress = (poolA: PublicKey, poolB: PublicKey, programId = STEP_PROGRAM_ID) => {
+  const [first, second] = Buffer.compare(poolA.toBuffer(), poolB.toBuffer()) <= 0 ? [poolA, poolB] : [poolB, poolA];
+  return findAddress([ROUTE_SEED, first.toBuffer(), second.toBuffer()], programId);
+};
+
+export const findRewardVaultAddress = (programId = STEP_PROGRAM_ID) =>
+  findAddress([REWARD_VAULT_SEED], programId);
+
+// Borsh layouts of the program accounts, see program/src/state.rs
+export const PROGRAM_STATE_LAYOUT = BufferLayout.struct([
+  BufferLayout.u8("isInitialized"),
+  publicKey("deployerPubkey"),
+  uint64("keeperRewardLamports"),
+]);
+
+// Leading fields of Pool, the round in progress and its limit prices follow
+export const POOL_LAYOUT = BufferLayout.struct([
+  BufferLayout.u8("isInitialized"),
+  publicKey("mintPubkey"),
+  BufferLayout.blob(MAX_POOL_NAME_LEN, "name"),
+  BufferLayout.u32("userCount"),
+  BufferLayout.seq(uint64(), 16, "userSlots"),
+  uint64("roundId"),
+  BufferLayout.u8("roundState"),
+]);
+
+export const USER_ACCOUNT_LAYOUT = BufferLayout.struct([
+  BufferLayout.u8("isInitialized"),
+  publicKey("ownerPubkey"),
+  publicKey("poolPubkey"),
+  BufferLayout.u32("userIndex"),
+  uint64("balance"),
+  uint64("committed"),
+  BufferLayout.u32("streamCount"),
+  uint64("streamSlots"),
+  BufferLayout.u32("orderCount"),
+  BufferLayout.u8("autoDeliver"),
+]);
+
+export interface PoolState {
+  isInitialized: number,
+  mintPubkey: PublicKey,
+  name: Buffer,
+  userCount: number,
+  userSlots: BN[],
+  roundId: BN,
+  roundState: number,
+}
+
+export interface UserAccountState {
+  isInitialized: number,
+  ownerPubkey: PublicKey,
+  poolPubkey: PublicKey,
+  userIndex: number,
+  balance: BN,
+  committed: BN,
+  streamCount: number,
+  streamSlots: BN,
+  orderCount: number,
+  autoDeliver: number,
+}
+
+export const poolName = (pool: PoolState) => {
+  const end = pool.name.indexOf(0);
+  return pool.name.slice(0, end < 0 ? MAX_POOL_NAME_LEN : end).toString("utf8");
+};
+
+// Slots below `count` taken in a bitmap of 64 bit words
+export const usedSlots = (words: BN[], count: number) =>
+  Array.from(Array(count).keys()).filter(index => words[Math.floor(index / 64)].testn(index % 64));
+
+const lowestFreeSlot = (words: BN[]) => {
+  for (let index = 0; index < words.length * 64; index++) {
+    if (!words[Math.floor(index / 64)].testn(index % 64)) {
+      return index;
+    }
+  }
+  throw new Error("Pool has no free user slot");
+};
+
+export const getPool = async (connection: Connection, pool: PublicKey) => {
+  const info = await connection.getAccountInfo(pool);
+  if (!info) {
+    throw new Error(`Pool ${pool.toBase58()} does not exist`);
+  }
+  return POOL_LAYOUT.decode(info.data) as PoolState;
+};
+
+export const getUserAccount = async (connection: Connection, userAccount: PublicKey) => {
+  const info = await connection.getAccountInfo(userAccount);
+  return info ? USER_ACCOUNT_LAYOUT.decode(info.data) as UserAccountState : null;
+};
+
+// The user account of `owner` in a pool, or the slot the first deposit creates it in
+export const findUserAccount = async (connection: Connection, pool: PublicKey, owner: PublicKey, programId = STEP_PROGRAM_ID) => {
+  const poolState = await getPool(connection, pool);
+
+  for (const userIndex of usedSlots(poolState.userSlots, poolState.userCount)) {
+    const address = await findUserAddress(pool, userIndex, programId);
+    const userAccount = await getUserAccount(connection, address);
+    if (userAccount && userAccount.ownerPubkey.equals(owner)) {
+      return { address, userIndex, isNew: false };
+    }
+  }
+
+  const userIndex = lowestFreeSlot(poolState.userSlots);
+  return { address: await findUserAddress(pool, userIndex, programId), userIndex, isNew: true };
+};
+
+export const initPoolInstruction = (
+  deployer: PublicKey,
+  programState: PublicKey,
+  pool: PublicKey,
+  vault: PublicKey,
+  mint: PublicKey,
+  name: string,
+  programId = STEP_PROGRAM_ID
+) => {
+  const nameBytes = Buffer.from(name, "utf8");
+  return new TransactionInstruction({
+    programId,
+    keys: [
+      { pubkey: deployer, isSigner: true, isWritable: true },
+      { pubkey: programState, isSigner: false, isWritable: false },
+      { pubkey: pool, isSigner: false, isWritable: true },
+      { pubkey: vault, isSigner: false, isWritable: true },
+      { pubkey: mint, isSigner: false, isWritable: false },
+      { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      { pubkey: SYSVAR_RENT_PUBKEY, isSigner: false, isWritable: false },
+    ],
+    data: Buffer.concat([Buffer.from([INIT_POOL_TAG]), u32le(nameBytes.length), nameBytes])
+  });
+};
+
+export const depositInstruction = (
+  depositor: PublicKey,
+  depositorTokenAccount: PublicKey,
+  userAccount: PublicKey,
+  pool: PublicKey,
+  vault: PublicKey,
+  amount: BN,
+  programId = STEP_PROGRAM_ID
+) => new TransactionInstruction({
+  programId,
+  keys: [
+    { pubkey: depositor, isSigner: true, isWritable: true },
+    { pubkey: depositorTokenAccount, isSigner: false, isWritable: true },
+    { pubkey: userAccount, isSigner: false, isWritable: true },
+    { pubkey: pool, isSigner: false, isWritable: true },
+    { pubkey: vault, isSigner: false, isWritable: true },
+    { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+    { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+    { pubkey: SYSVAR_RENT_PUBKEY, isSigner: false, isWritable: false },
+  ],
+  data: Buffer.from(Uint8Array.of(DEPOSIT_TAG, ...amount.toArray("le", 8)))
+});
+
+// Accounts of the token swap a pair of pools trades through, named from the side of the trades input
+export interface SwapAccounts {
+  programId: PublicKey,
+  swap: PublicKey,
+  authority: PublicKey,
+  inputToken: PublicKey,
+  outputToken: PublicKey,
+  poolMint: PublicKey,
+  feeAccount: PublicKey,
+}
+
+// Users of a pool in slot order for a round not started yet, each with its streams. Streams of
+// users with auto-delivery are each followed by the owners associated token account of `otherMint`.
+export const gatherUsers = async (connection: Connection, pool: PublicKey, otherMint: PublicKey, programId = STEP_PROGRAM_ID) => {
+  const poolState = await getPool(connection, pool);
+  const users: PublicKey[][] = [];
+
+  for (const userIndex of usedSlots(poolState.userSlots, poolState.userCount)) {
+    const address = await findUserAddress(pool, userIndex, programId);
+    const userAccount = (await getUserAccount(connection, address))!;
+    const deliveryAccount = userAccount.autoDeliver
+      ? await findAssociatedTokenAddress(userAccount.ownerPubkey, otherMint)
+      : null;
+    const accounts = [address];
+
+    for (const streamIndex of usedSlots([userAccount.streamSlots], userAccount.streamCount)) {
+      accounts.push(await findStreamAddress(address, streamIndex, programId));
+      if (deliveryAccount) {
+        accounts.push(deliveryAccount);
+      }
+    }
+    users.push(accounts);
+  }
+  return users;
+};
+
+export const gatherInstruction = (
+  pool: PublicKey,
+  otherPool: PublicKey,
+  route: PublicKey,
+  swap: PublicKey,
+  swapPoolToken: PublicKey,
+  swapOtherToken: PublicKey,
+  otherVault: PublicKey,
+  page: PublicKey[],
+  programId = STEP_PROGRAM_ID
+) => new TransactionInstruction({
+  programId,
+  keys: [
+    { pubkey: pool, isSigner: false, isWritable: true },
+    { pubkey: otherPool, isSigner: false, isWritable: true },
+    { pubkey: route, isSigner: false, isWritable: false },
+    { pubkey: SYSVAR_CLOCK_PUBKEY, isSigner: false, isWritable: false },
+    { pubkey: swap, isSigner: false, isWritable: false },
+    { pubkey: swapPoolToken, isSigner: false, isWritable: false },
+    { pubkey: swapOtherToken, isSigner: false, isWritable: false },
+    { pubkey: otherVault, isSigner: false, isWritable: true },
+    { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+    ...page.map(pubkey => ({ pubkey, isSigner: false, isWritable: true })),
+  ],
+  data: Buffer.from([GATHER_TAG])
+});
+
+export const executeInstruction = (
+  keeper: PublicKey,
+  programState: PublicKey,
+  route: PublicKey,
+  inputPool: PublicKey,
+  inputVault: PublicKey,
+  outputPool: PublicKey,
+  outputVault: PublicKey,
+  swapAccounts: SwapAccounts,
+  rewardVault: PublicKey,
+  programId = STEP_PROGRAM_ID
+) => new TransactionInstruction({
+  programId,
+  keys: [
+    { pubkey: keeper, isSigner: false, isWritable: true },
+    { pubkey: programState, isSigner: false, isWritable: false },
+    { pubkey: route, isSigner: false, isWritable: false },
+    { pubkey: inputPool, isSigner: false, isWritable: true },
+    { pubkey: inputVault, isSigner: false, isWritable: true },
+    { pubkey: outputPool, isSigner: false, isWritable: true },
+    { pubkey: outputVault, isSigner: false, isWritable: true },
+    { pubkey: swapAccounts.programId, isSigner: false, isWritable: false },
+    { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+    { pubkey: swapAccounts.swap, isSigner: false, isWritable: false },
+    { pubkey: swapAccounts.authority, isSigner: false, isWritable: false },
+    { pubkey: swapAccounts.inputToken, isSigner: false, isWritable: true },
+    { pubkey: swapAccounts.outputToken, isSigner: false, isWritable: true },
+    { pubkey: swapAccounts.poolMint, isSigner: false, isWritable: true },
+    { pubkey: swapAccounts.feeAccount, isSigner: false, isWritable: true },
+    { pubkey: rewardVault, isSigner: false, isWritable: true },
+    { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+    { pubkey: SYSVAR_RENT_PUBKEY, isSigner: false, isWritable: false },
+  ],
+  data: Buffer.from([EXECUTE_TAG])
+});
 
 export const depositTokens = async(
   connection: Connection,
@@ -105,22 +392,20 @@ export const depositTokens = async(
   }
 
   console.log("Depositor token account:", depositorTokenAccount.toString());
-  const PDA_TOKEN_ACCOUNT = await PublicKey.findProgramAddress(STEP_TOKEN_PDA_SEED, STEP_PROGRAM_ID);
-  console.log("PDA: ", PDA_TOKEN_ACCOUNT.toString());
+  const pool = await findPoolAddress(tokenMintAddress);
+  const vault = await findVaultAddress(pool);
+  const userAccount = await findUserAccount(connection, pool, depositorAccount);
+  console.log("Pool: ", pool.toString(), "user account: ", userAccount.address.toString(), "in slot", userAccount.userIndex);
   console.log("Deposit amount: ", depositAmount)
-  const PDA_POOL_STATE = await PublicKey.findProgramAddress(STEP_POOL_PDA_SEED, STEP_PROGRAM_ID);
 
-  instructions.push(new TransactionInstruction({
-    programId: STEP_PROGRAM_ID,
-    keys: [
-        { pubkey: depositorAccount, isSigner: true, isWritable: false },
-        { pubkey: depositorTokenAccount, isSigner: false, isWritable: true },
-        { pubkey: PDA_POOL_STATE[0], isSigner: false, isWritable: true },
-        { pubkey: PDA_TOKEN_ACCOUNT[0], isSigner: false, isWritable: true },
-        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
-    ],
-    data: Buffer.from(Uint8Array.of(2, ...new BN(depositAmount * LAMPORTS_PER_SOL).toArray("le", 8)))
-  }));
+  instructions.push(depositInstruction(
+    depositorAccount,
+    depositorTokenAccount,
+    userAccount.address,
+    pool,
+    vault,
+    new BN(depositAmount * LAMPORTS_PER_SOL)
+  ));
 
   await sendTransaction(
     connection,